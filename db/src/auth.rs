@@ -0,0 +1,261 @@
+//! Argon2id password hashing, used everywhere a `User`'s `password` field is set or checked so
+//! only a PHC-format hash (`$argon2id$...`) ever reaches storage, never the cleartext; and the
+//! signed JWT access tokens handed out at login (see `issue_token`/`verify_token`).
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::models::{User, UserKind};
+
+/// Hashes `password` into a PHC-format string suitable for storing on `User::password`.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("hashing a non-empty, in-memory password should not fail")
+        .to_string()
+}
+
+/// Outcome of [`verify_password`]: besides whether `password` matched, whether the caller needs
+/// to write a new value back to `User::password` to finish migrating it to Argon2.
+pub enum PasswordCheck {
+    /// `password` did not match.
+    Invalid,
+    /// `password` matched an existing Argon2 hash; nothing to do.
+    Valid,
+    /// `password` matched a value that predates this module (plain cleartext, from before
+    /// hashing was introduced). The caller should store the enclosed PHC string on `User::password`
+    /// so the record upgrades in place instead of staying cleartext forever.
+    ValidNeedsRehash(String),
+}
+
+/// Checks `password` against `stored`, which is normally a PHC-format hash previously produced by
+/// [`hash_password`]. For backward compatibility with cleartext values seeded or persisted before
+/// this module existed, a `stored` that doesn't parse as a PHC string (no `$argon2` prefix) is
+/// instead compared to `password` in constant time; on a match, [`PasswordCheck::ValidNeedsRehash`]
+/// carries the freshly-hashed PHC form for the caller to persist, so the record is upgraded the
+/// next time its password is successfully checked rather than needing a separate migration pass.
+pub fn verify_password(password: &str, stored: &str) -> PasswordCheck {
+    match PasswordHash::new(stored) {
+        Ok(parsed_hash) => {
+            if Argon2::default()
+                .verify_password(password.as_bytes(), &parsed_hash)
+                .is_ok()
+            {
+                PasswordCheck::Valid
+            } else {
+                PasswordCheck::Invalid
+            }
+        }
+        Err(_) => {
+            if constant_time_eq(password.as_bytes(), stored.as_bytes()) {
+                PasswordCheck::ValidNeedsRehash(hash_password(password))
+            } else {
+                PasswordCheck::Invalid
+            }
+        }
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first differing byte, so the legacy
+/// cleartext fallback in [`verify_password`] doesn't leak how many leading bytes matched through
+/// response timing the way `==` would.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+/// Symmetric signing key for access tokens. Reads `JWT_SECRET` so it can be overridden per
+/// deployment (same `from_env`-with-fallback pattern as `storage::StorageBackend::from_env`);
+/// falling back to a fixed value keeps `cargo run` working out of the box for local development.
+fn jwt_secret() -> String {
+    std::env::var("JWT_SECRET").unwrap_or_else(|_| "dev-insecure-jwt-secret".to_string())
+}
+
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// A user's role, as carried in a `Claims` token. Mirrors `UserKind` without the per-kind payload
+/// (email, rank, ...) that can change without the token needing to be reissued.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Administrator,
+    Teacher,
+    Student,
+}
+
+impl From<&UserKind> for Role {
+    fn from(kind: &UserKind) -> Self {
+        match kind {
+            UserKind::Administrator => Role::Administrator,
+            UserKind::Teacher(_) => Role::Teacher,
+            UserKind::Student(_) => Role::Student,
+        }
+    }
+}
+
+/// Claims carried by a signed access token: who (`sub`, the user id), what they're allowed to do
+/// (`role`), when to stop trusting the token (`exp`), and a unique id (`jti`) so a single token can
+/// be individually revoked (see `Database::auth_logout`) without the server having to remember
+/// every token it ever issued — a filter authorizes a request from the token alone, only
+/// consulting the database to check the (much smaller) revocation set.
+#[derive(Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: u32,
+    pub role: Role,
+    jti: String,
+    iat: usize,
+    exp: usize,
+}
+
+impl Claims {
+    /// This token's unique id, as tracked by the revocation set `auth_logout` inserts into.
+    pub fn jti(&self) -> &str {
+        &self.jti
+    }
+
+    /// Unix timestamp this token stops being valid at, so a revocation entry for it can be evicted
+    /// once the token would have expired on its own anyway.
+    pub fn expires_at(&self) -> usize {
+        self.exp
+    }
+}
+
+/// Issues a signed access token for `user`, carrying their id, role, and a fresh `jti`, valid for
+/// [`TOKEN_LIFETIME`].
+pub fn issue_token(user: &User) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock should be after the epoch")
+        .as_secs() as usize;
+    let exp = now + TOKEN_LIFETIME.as_secs() as usize;
+
+    let claims = Claims {
+        sub: user.id,
+        role: Role::from(&user.kind),
+        jti: generate_jti(),
+        iat: now,
+        exp,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret().as_bytes()),
+    )
+    .expect("encoding a well-formed Claims should not fail")
+}
+
+/// A random 128-bit id, hex-encoded, unique enough to tell two tokens for the same user apart in
+/// the revocation set without needing a database-wide counter.
+fn generate_jti() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Validates `token`'s signature and expiry, returning its claims if both hold.
+pub fn verify_token(token: &str) -> Option<Claims> {
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(jwt_secret().as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|data| data.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Rank, TeacherInformations, UserKind};
+
+    fn teacher_user(id: u32) -> User {
+        User {
+            id,
+            username: format!("teacher{}", id),
+            password: hash_password("hunter2"),
+            first_name: "Jane".to_string(),
+            last_name: "Doe".to_string(),
+            kind: UserKind::Teacher(TeacherInformations {
+                phone_number: None,
+                email: None,
+                rank: Rank::Professor,
+            }),
+        }
+    }
+
+    #[test]
+    fn verify_password_accepts_a_matching_hash() {
+        let hash = hash_password("hunter2");
+        assert!(matches!(
+            verify_password("hunter2", &hash),
+            PasswordCheck::Valid
+        ));
+    }
+
+    #[test]
+    fn verify_password_rejects_a_non_matching_hash() {
+        let hash = hash_password("hunter2");
+        assert!(matches!(
+            verify_password("wrong", &hash),
+            PasswordCheck::Invalid
+        ));
+    }
+
+    #[test]
+    fn verify_password_upgrades_a_legacy_cleartext_value() {
+        match verify_password("hunter2", "hunter2") {
+            PasswordCheck::ValidNeedsRehash(rehashed) => {
+                assert!(matches!(
+                    verify_password("hunter2", &rehashed),
+                    PasswordCheck::Valid
+                ));
+            }
+            _ => panic!("expected a rehash of the matching cleartext password"),
+        }
+    }
+
+    #[test]
+    fn verify_password_rejects_a_non_matching_legacy_cleartext_value() {
+        assert!(matches!(
+            verify_password("wrong", "hunter2"),
+            PasswordCheck::Invalid
+        ));
+    }
+
+    #[test]
+    fn issued_token_round_trips_through_verify_token() {
+        let user = teacher_user(1);
+        let token = issue_token(&user);
+
+        let claims = verify_token(&token).expect("a freshly issued token should verify");
+        assert_eq!(claims.sub, user.id);
+        assert!(claims.role == Role::Teacher);
+    }
+
+    #[test]
+    fn verify_token_rejects_garbage() {
+        assert!(verify_token("not.a.token").is_none());
+    }
+
+    #[test]
+    fn two_tokens_for_the_same_user_have_distinct_jtis() {
+        let user = teacher_user(2);
+        let first = verify_token(&issue_token(&user)).unwrap();
+        let second = verify_token(&issue_token(&user)).unwrap();
+
+        assert_ne!(first.jti(), second.jti());
+    }
+}
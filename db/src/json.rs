@@ -1,25 +1,31 @@
 use bimap::BiMap;
 use log::{error, info};
-use rand::{self, Rng};
 use serde::{Deserialize, Serialize};
 use std::io::Read;
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::PathBuf,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::broadcast;
 
 use super::{
-    models::Class, seed::seed_db, username_from_name, ClassUpdate, ClassroomUpdate, Database,
-    NewClass, NewClassroom, NewOccupancySeed, NewSubject, SubjectUpdate, UpdateStatus, PAGE_SIZE,
+    models::Class, seed::seed_db, username_from_name, BatchItemResult, BatchItemStatus,
+    BatchReport, ClassUpdate, ClassroomUpdate, Conflict, Database, ImportReport,
+    ModificationEvent, NewClass, NewClassroom, NewOccupancySeed, NewSubject, RecurrenceEnd,
+    RecurrenceReport, RecurrenceSpec, SubjectUpdate, UpdateStatus, Weekday, PAGE_SIZE,
 };
 use crate::{
     groups,
     models::{
-        Classroom, Modification, ModificationOccupancy, ModificationType, Occupancy,
-        StudentSubject, Subject, SubjectTeacher, User, UserKind,
+        Avatar, ClassLevel, Classroom, EffectiveOccupancy, Modification, ModificationOccupancy,
+        ModificationType, Occupancy, OccupancyOccurrence, OccupancyType, Rank, StudentSubject,
+        Subject, SubjectTeacher, Substitution, TeacherInformations, User, UserKind, WeekType,
     },
-    NewOccupancy,
+    storage::{FlushMode, StorageBackend},
+    untis_import::{UntisDump, UntisFach},
+    webuntis::{UntisLessonType, UntisPeriod},
+    NewOccupancy, NewUser, OccupancyUpdate,
 };
 
 #[derive(Debug)]
@@ -39,11 +45,202 @@ impl std::error::Error for BincodeError {
     }
 }
 
+/// On-disk bincode format version. Bump this and add a branch to `JSONDatabase::_migrate` whenever
+/// a change to `JSONDatabase`'s shape needs translating old saves instead of just loading them.
+const DB_FORMAT_VERSION: u32 = 4;
+
+/// Per-resource free-busy index: occupancy intervals keyed by start datetime, each start bucket
+/// holding the `(end_bound, occupancy_id)` pairs starting there. `end_bound` is the occupancy's
+/// own end for a non-recurring occupancy, or its last possible occurrence's end for a recurring
+/// one, so a single entry still covers the whole recurrence.
+type IntervalIndex = HashMap<u32, BTreeMap<u64, Vec<(u64, u32)>>>;
+
+const DAY_SECONDS: u64 = 24 * 3600;
+const WEEK_SECONDS: u64 = 7 * DAY_SECONDS;
+
+/// `StudentSubject.group_number` a freshly-enrolled student is given, never a valid group index
+/// (`group_count` never reaches `u32::MAX`), so `_distribute_subject_groups` can tell new/orphaned
+/// students apart from ones already placed in a real group.
+const UNGROUPED: u32 = u32::MAX;
+
+/// How many `ModificationEvent`s `occupancies_modifications_since` can hand back to a client
+/// catching up after a missed poll; older ones are dropped, same as `_add_modification`'s
+/// per-user history cap.
+const MODIFICATION_FEED_CAPACITY: usize = 256;
+
+fn new_modification_feed() -> broadcast::Sender<ModificationEvent> {
+    broadcast::channel(MODIFICATION_FEED_CAPACITY).0
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct JSONDatabase {
+    dirty: bool,
+    filename: String,
+    /// Where `dirty_to_bincode`'s bytes actually get read from / written to. Not serialized: it's
+    /// runtime configuration (`DB_BACKEND`/`DB_URL`), re-supplied by `new`/`from_backend` on every
+    /// startup rather than carried over inside the save itself.
+    #[serde(skip)]
+    backend: StorageBackend,
+    /// Same story as `backend`: runtime configuration from `DB_FLUSH_MODE`, re-supplied on every
+    /// startup rather than carried over inside the save itself.
+    #[serde(skip)]
+    flush_mode: FlushMode,
+    delay: Duration,
+    schoolyear_anchor: u64,
+    users: HashMap<String, User>,
+    /// Revoked-but-not-yet-expired access tokens, keyed by `jti` rather than the token itself: since
+    /// tokens are stateless JWTs (see `auth::Claims`), a request is authorized straight from its
+    /// signature and expiry, and this set only needs consulting for the (normally empty) case of a
+    /// token an `auth_logout` call explicitly invalidated before it would have expired on its own.
+    /// The value is the token's own `exp`, so `_prune_revoked_tokens` can drop an entry once the
+    /// token it refers to couldn't be replayed anyway, instead of this set growing forever.
+    revoked_tokens: HashMap<String, u64>,
+    classrooms: HashMap<u32, Classroom>,
+    classes: HashMap<u32, Class>,
+    subjects: HashMap<u32, Subject>,
+    subjects_teachers: HashMap<u32, SubjectTeacher>,
+    subjects_students: HashMap<u32, StudentSubject>,
+    occupancies: HashMap<u32, Occupancy>,
+    modifications: HashMap<u32, Vec<Modification>>,
+    /// Profile pictures, keyed by user id. Absent for a user who never uploaded one.
+    avatars: HashMap<u32, Avatar>,
+    #[serde(skip)]
+    classroom_index: IntervalIndex,
+    #[serde(skip)]
+    teacher_index: IntervalIndex,
+    #[serde(skip)]
+    class_index: IntervalIndex,
+    /// Recent `ModificationEvent`s, for `occupancies_modifications_since` to replay to a client
+    /// catching up. Runtime-only: a fresh process starts with an empty feed.
+    #[serde(skip)]
+    modification_feed_log: VecDeque<ModificationEvent>,
+    /// Broadcasts every new `ModificationEvent` to whatever `/api/occupancies/stream` clients are
+    /// currently subscribed.
+    #[serde(skip, default = "new_modification_feed")]
+    modification_feed: broadcast::Sender<ModificationEvent>,
+    #[serde(skip)]
+    next_modification_event_id: u64,
+    next_user_id: u32,
+    next_classroom_id: u32,
+    next_class_id: u32,
+    next_subject_id: u32,
+    next_subject_teacher_id: u32,
+    next_subject_students_id: u32,
+    next_occupancy_id: u32,
+    next_recurrence_group_id: u32,
+}
+
+/// `JSONDatabase`'s shape as of format version 1, before `Occupancy` gained
+/// `recurrence_group_id` and the database gained `next_recurrence_group_id`. Only used by
+/// `JSONDatabase::_migrate` to read old saves; never written.
+#[derive(Deserialize)]
+struct JSONDatabaseV1 {
+    dirty: bool,
+    filename: String,
+    delay: Duration,
+    schoolyear_anchor: u64,
+    users: HashMap<String, User>,
+    tokens: BiMap<String, String>,
+    classrooms: HashMap<u32, Classroom>,
+    classes: HashMap<u32, Class>,
+    subjects: HashMap<u32, Subject>,
+    subjects_teachers: HashMap<u32, SubjectTeacher>,
+    subjects_students: HashMap<u32, StudentSubject>,
+    occupancies: HashMap<u32, OccupancyV1>,
+    modifications: HashMap<u32, Vec<Modification>>,
+    next_user_id: u32,
+    next_classroom_id: u32,
+    next_class_id: u32,
+    next_subject_id: u32,
+    next_subject_teacher_id: u32,
+    next_subject_students_id: u32,
+    next_occupancy_id: u32,
+}
+
+#[derive(Deserialize)]
+struct OccupancyV1 {
+    id: u32,
+    classroom_id: Option<u32>,
+    group_number: Option<u32>,
+    subject_id: Option<u32>,
+    teacher_id: u32,
+    start_datetime: u64,
+    end_datetime: u64,
+    occupancy_type: OccupancyType,
+    name: String,
+    recurrence: Option<crate::models::Recurrence>,
+}
+
+impl JSONDatabaseV1 {
+    fn upgrade(self) -> JSONDatabase {
+        let occupancies = self
+            .occupancies
+            .into_iter()
+            .map(|(id, o)| {
+                (
+                    id,
+                    Occupancy {
+                        id: o.id,
+                        classroom_id: o.classroom_id,
+                        group_number: o.group_number,
+                        subject_id: o.subject_id,
+                        teacher_id: o.teacher_id,
+                        start_datetime: o.start_datetime,
+                        end_datetime: o.end_datetime,
+                        occupancy_type: o.occupancy_type,
+                        name: o.name,
+                        recurrence: o.recurrence,
+                        recurrence_group_id: None,
+                    },
+                )
+            })
+            .collect();
+
+        JSONDatabase {
+            dirty: self.dirty,
+            filename: self.filename,
+            backend: StorageBackend::default(),
+            flush_mode: FlushMode::default(),
+            delay: self.delay,
+            schoolyear_anchor: self.schoolyear_anchor,
+            users: self.users,
+            // Sessions (`tokens`) predate stateless JWTs entirely; there's nothing to carry over.
+            revoked_tokens: HashMap::new(),
+            classrooms: self.classrooms,
+            classes: self.classes,
+            subjects: self.subjects,
+            subjects_teachers: self.subjects_teachers,
+            subjects_students: self.subjects_students,
+            occupancies,
+            modifications: self.modifications,
+            avatars: HashMap::new(),
+            classroom_index: HashMap::new(),
+            teacher_index: HashMap::new(),
+            class_index: HashMap::new(),
+            modification_feed_log: VecDeque::new(),
+            modification_feed: new_modification_feed(),
+            next_modification_event_id: 0,
+            next_user_id: self.next_user_id,
+            next_classroom_id: self.next_classroom_id,
+            next_class_id: self.next_class_id,
+            next_subject_id: self.next_subject_id,
+            next_subject_teacher_id: self.next_subject_teacher_id,
+            next_subject_students_id: self.next_subject_students_id,
+            next_occupancy_id: self.next_occupancy_id,
+            next_recurrence_group_id: 0,
+        }
+    }
+}
+
+/// `JSONDatabase`'s shape as of format version 2, before `auth_login` moved from session tokens
+/// stored in `tokens` to stateless JWTs backed only by a `revoked_tokens` revocation set. Only used
+/// by `JSONDatabase::_migrate` to read old saves; never written.
+#[derive(Deserialize)]
+struct JSONDatabaseV2 {
     dirty: bool,
     filename: String,
     delay: Duration,
+    schoolyear_anchor: u64,
     users: HashMap<String, User>,
     tokens: BiMap<String, String>,
     classrooms: HashMap<u32, Classroom>,
@@ -60,13 +257,122 @@ pub struct JSONDatabase {
     next_subject_teacher_id: u32,
     next_subject_students_id: u32,
     next_occupancy_id: u32,
+    next_recurrence_group_id: u32,
+}
+
+impl JSONDatabaseV2 {
+    fn upgrade(self) -> JSONDatabase {
+        // Every still-logged-in user under the old session model just gets signed out: the next
+        // request with their old token hits `auth_get_user`'s stateless decode, finds no matching
+        // session state to have carried over, and 401s like any other expired token would.
+        drop(self.tokens);
+
+        JSONDatabase {
+            dirty: self.dirty,
+            filename: self.filename,
+            backend: StorageBackend::default(),
+            flush_mode: FlushMode::default(),
+            delay: self.delay,
+            schoolyear_anchor: self.schoolyear_anchor,
+            users: self.users,
+            revoked_tokens: HashMap::new(),
+            classrooms: self.classrooms,
+            classes: self.classes,
+            subjects: self.subjects,
+            subjects_teachers: self.subjects_teachers,
+            subjects_students: self.subjects_students,
+            occupancies: self.occupancies,
+            modifications: self.modifications,
+            avatars: HashMap::new(),
+            classroom_index: HashMap::new(),
+            teacher_index: HashMap::new(),
+            class_index: HashMap::new(),
+            modification_feed_log: VecDeque::new(),
+            modification_feed: new_modification_feed(),
+            next_modification_event_id: 0,
+            next_user_id: self.next_user_id,
+            next_classroom_id: self.next_classroom_id,
+            next_class_id: self.next_class_id,
+            next_subject_id: self.next_subject_id,
+            next_subject_teacher_id: self.next_subject_teacher_id,
+            next_subject_students_id: self.next_subject_students_id,
+            next_occupancy_id: self.next_occupancy_id,
+            next_recurrence_group_id: self.next_recurrence_group_id,
+        }
+    }
+}
+
+/// `JSONDatabase`'s shape as of format version 3, before profile picture uploads added `avatars`.
+/// Only used by `JSONDatabase::_migrate` to read old saves; never written.
+#[derive(Deserialize)]
+struct JSONDatabaseV3 {
+    dirty: bool,
+    filename: String,
+    delay: Duration,
+    schoolyear_anchor: u64,
+    users: HashMap<String, User>,
+    revoked_tokens: HashMap<String, u64>,
+    classrooms: HashMap<u32, Classroom>,
+    classes: HashMap<u32, Class>,
+    subjects: HashMap<u32, Subject>,
+    subjects_teachers: HashMap<u32, SubjectTeacher>,
+    subjects_students: HashMap<u32, StudentSubject>,
+    occupancies: HashMap<u32, Occupancy>,
+    modifications: HashMap<u32, Vec<Modification>>,
+    next_user_id: u32,
+    next_classroom_id: u32,
+    next_class_id: u32,
+    next_subject_id: u32,
+    next_subject_teacher_id: u32,
+    next_subject_students_id: u32,
+    next_occupancy_id: u32,
+    next_recurrence_group_id: u32,
+}
+
+impl JSONDatabaseV3 {
+    fn upgrade(self) -> JSONDatabase {
+        JSONDatabase {
+            dirty: self.dirty,
+            filename: self.filename,
+            backend: StorageBackend::default(),
+            flush_mode: FlushMode::default(),
+            delay: self.delay,
+            schoolyear_anchor: self.schoolyear_anchor,
+            users: self.users,
+            revoked_tokens: self.revoked_tokens,
+            classrooms: self.classrooms,
+            classes: self.classes,
+            subjects: self.subjects,
+            subjects_teachers: self.subjects_teachers,
+            subjects_students: self.subjects_students,
+            occupancies: self.occupancies,
+            modifications: self.modifications,
+            avatars: HashMap::new(),
+            classroom_index: HashMap::new(),
+            teacher_index: HashMap::new(),
+            class_index: HashMap::new(),
+            modification_feed_log: VecDeque::new(),
+            modification_feed: new_modification_feed(),
+            next_modification_event_id: 0,
+            next_user_id: self.next_user_id,
+            next_classroom_id: self.next_classroom_id,
+            next_class_id: self.next_class_id,
+            next_subject_id: self.next_subject_id,
+            next_subject_teacher_id: self.next_subject_teacher_id,
+            next_subject_students_id: self.next_subject_students_id,
+            next_occupancy_id: self.next_occupancy_id,
+            next_recurrence_group_id: self.next_recurrence_group_id,
+        }
+    }
 }
 
 impl JSONDatabase {
-    pub fn new(filename: String) -> Self {
+    pub fn new(backend: StorageBackend) -> Self {
         // Try to read from disk
-        match Self::from_file(&filename) {
-            Ok(db) => {
+        match Self::from_backend(&backend) {
+            Ok(mut db) => {
+                db.backend = backend;
+                db.flush_mode = FlushMode::from_env();
                 info!("Database loaded");
                 return db;
             }
@@ -78,10 +384,13 @@ impl JSONDatabase {
 
         let mut db = Self {
             dirty: true,
-            filename,
+            filename: format!("{:?}", backend),
+            backend,
+            flush_mode: FlushMode::from_env(),
             delay: Duration::from_millis(0),
+            schoolyear_anchor: 0,
             users: HashMap::new(),
-            tokens: BiMap::new(),
+            revoked_tokens: HashMap::new(),
             classrooms: HashMap::new(),
             classes: HashMap::new(),
             subjects: HashMap::new(),
@@ -89,6 +398,13 @@ impl JSONDatabase {
             subjects_students: HashMap::new(),
             occupancies: HashMap::new(),
             modifications: HashMap::new(),
+            avatars: HashMap::new(),
+            classroom_index: HashMap::new(),
+            teacher_index: HashMap::new(),
+            class_index: HashMap::new(),
+            modification_feed_log: VecDeque::new(),
+            modification_feed: new_modification_feed(),
+            next_modification_event_id: 0,
             next_user_id: 0,
             next_classroom_id: 0,
             next_class_id: 0,
@@ -96,22 +412,33 @@ impl JSONDatabase {
             next_subject_teacher_id: 0,
             next_subject_students_id: 0,
             next_occupancy_id: 0,
+            next_recurrence_group_id: 0,
         };
 
         db.reset();
 
         db
     }
-    fn from_file(filename: &str) -> Result<Self, std::io::Error> {
-        let contents = {
-            let mut file = File::open(filename)?;
-            let mut contents = Vec::new();
-            file.read_to_end(&mut contents)?;
-            contents
-        };
 
-        match bincode::deserialize(&contents[..]) {
-            Ok(deserialized) => Ok(deserialized),
+    /// Reads and deserializes a database from `backend`, without making it `backend`'s own
+    /// storage location yet (the caller, `new` or the trait's `from_file`, decides that).
+    fn from_backend(backend: &StorageBackend) -> Result<Self, std::io::Error> {
+        let contents = backend.read()?;
+
+        match Self::_deserialize_versioned(&contents) {
+            Ok(mut db) => {
+                db.backend = backend.clone();
+                db.flush_mode = FlushMode::from_env();
+                // For `Sled`, the five core collections' own trees are written synchronously on
+                // every mutation (see `_sled_sync_*`), so they can be ahead of the blob just
+                // decoded above, which only gets refreshed on the periodic/immediate whole-arena
+                // flush. Overlay them before rebuilding the indexes, which read from `occupancies`.
+                db._load_sled_overlay();
+                // The interval indexes are `#[serde(skip)]`, so they come back empty from bincode
+                // and need to be rebuilt from the deserialized occupancies.
+                db._rebuild_indexes();
+                Ok(db)
+            }
             Err(e) => Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 BincodeError { kind: e },
@@ -119,10 +446,184 @@ impl JSONDatabase {
         }
     }
 
+    /// For `Sled`, replaces each of the five core collections with whatever's in their own
+    /// per-entity tree, when that tree is non-empty — see `storage.rs`'s module doc for why those
+    /// trees can be more up to date than the whole-arena blob `from_backend` just decoded. A
+    /// collection whose tree is still empty (a save from before this existed, or a backend that
+    /// isn't `Sled`) is left exactly as the blob decoded it.
+    fn _load_sled_overlay(&mut self) {
+        if !self.backend.is_per_entity() {
+            return;
+        }
+
+        if let Ok(entries) = self.backend.entities("classrooms") {
+            if !entries.is_empty() {
+                self.classrooms = entries
+                    .into_iter()
+                    .filter_map(|(_, bytes)| bincode::deserialize::<Classroom>(&bytes).ok())
+                    .map(|classroom| (classroom.id, classroom))
+                    .collect();
+            }
+        }
+
+        if let Ok(entries) = self.backend.entities("classes") {
+            if !entries.is_empty() {
+                self.classes = entries
+                    .into_iter()
+                    .filter_map(|(_, bytes)| bincode::deserialize::<Class>(&bytes).ok())
+                    .map(|class| (class.id, class))
+                    .collect();
+            }
+        }
+
+        if let Ok(entries) = self.backend.entities("subjects") {
+            if !entries.is_empty() {
+                self.subjects = entries
+                    .into_iter()
+                    .filter_map(|(_, bytes)| bincode::deserialize::<Subject>(&bytes).ok())
+                    .map(|subject| (subject.id, subject))
+                    .collect();
+            }
+        }
+
+        if let Ok(entries) = self.backend.entities("occupancies") {
+            if !entries.is_empty() {
+                self.occupancies = entries
+                    .into_iter()
+                    .filter_map(|(_, bytes)| bincode::deserialize::<Occupancy>(&bytes).ok())
+                    .map(|occupancy| (occupancy.id, occupancy))
+                    .collect();
+            }
+        }
+
+        if let Ok(entries) = self.backend.entities("users") {
+            if !entries.is_empty() {
+                self.users = entries
+                    .into_iter()
+                    .filter_map(|(key, bytes)| {
+                        let username = String::from_utf8(key).ok()?;
+                        let user: User = bincode::deserialize(&bytes).ok()?;
+                        Some((username, user))
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    /// Upserts `id`'s current row into `collection`'s sled tree (or deletes it, if `id` is no
+    /// longer in `self.<collection>`), a no-op unless `self.backend` is `Sled`. Called from the one
+    /// choke point each of the five core collections actually mutates through, so every public
+    /// add/update/remove path gets per-entity durability automatically.
+    fn _sled_sync_classroom(&self, id: u32) {
+        self._sled_sync_by_id("classrooms", id, self.classrooms.get(&id));
+    }
+
+    fn _sled_sync_class(&self, id: u32) {
+        self._sled_sync_by_id("classes", id, self.classes.get(&id));
+    }
+
+    fn _sled_sync_subject(&self, id: u32) {
+        self._sled_sync_by_id("subjects", id, self.subjects.get(&id));
+    }
+
+    fn _sled_sync_occupancy(&self, id: u32) {
+        self._sled_sync_by_id("occupancies", id, self.occupancies.get(&id));
+    }
+
+    fn _sled_sync_by_id<T: Serialize>(&self, collection: &str, id: u32, value: Option<&T>) {
+        if !self.backend.is_per_entity() {
+            return;
+        }
+
+        let result = match value {
+            Some(value) => bincode::serialize(value)
+                .map_err(|e| BincodeError { kind: e }.to_string())
+                .and_then(|bytes| {
+                    self.backend
+                        .put_entity(collection, &id.to_be_bytes(), &bytes)
+                        .map_err(|e| e.to_string())
+                }),
+            None => self
+                .backend
+                .remove_entity(collection, &id.to_be_bytes())
+                .map_err(|e| e.to_string()),
+        };
+
+        if let Err(e) = result {
+            error!("sled per-entity sync failed for {} {}: {}", collection, id, e);
+        }
+    }
+
+    fn _sled_sync_user(&self, username: &str) {
+        if !self.backend.is_per_entity() {
+            return;
+        }
+
+        let result = match self.users.get(username) {
+            Some(user) => bincode::serialize(user)
+                .map_err(|e| BincodeError { kind: e }.to_string())
+                .and_then(|bytes| {
+                    self.backend
+                        .put_entity("users", username.as_bytes(), &bytes)
+                        .map_err(|e| e.to_string())
+                }),
+            None => self
+                .backend
+                .remove_entity("users", username.as_bytes())
+                .map_err(|e| e.to_string()),
+        };
+
+        if let Err(e) = result {
+            error!("sled per-entity sync failed for user {}: {}", username, e);
+        }
+    }
+
+    /// Reads the `{version: u32}` envelope this file should have been saved with, and migrates
+    /// the payload that follows it. Saves written before versioning existed have no such envelope,
+    /// so if the first 4 bytes don't yield a version we can migrate from, falls back to parsing
+    /// the whole file as that original, unversioned shape.
+    fn _deserialize_versioned(contents: &[u8]) -> Result<Self, bincode::Error> {
+        if contents.len() >= 4 {
+            if let Ok(version) = bincode::deserialize::<u32>(&contents[..4]) {
+                info!("database file declares format version {}", version);
+
+                match Self::_migrate(version, &contents[4..]) {
+                    Some(db) => return db,
+                    None => error!(
+                        "could not migrate database from version {}, falling back to the legacy unversioned format",
+                        version
+                    ),
+                }
+            }
+        }
+
+        info!("no recognizable version envelope, trying the legacy unversioned format");
+        bincode::deserialize(contents)
+    }
+
+    /// Deserializes `data` according to `version`, or returns `None` for a version this binary
+    /// doesn't know how to read.
+    fn _migrate(version: u32, data: &[u8]) -> Option<Result<Self, bincode::Error>> {
+        match version {
+            DB_FORMAT_VERSION => Some(bincode::deserialize(data)),
+            3 => Some(bincode::deserialize::<JSONDatabaseV3>(data).map(JSONDatabaseV3::upgrade)),
+            2 => Some(bincode::deserialize::<JSONDatabaseV2>(data).map(JSONDatabaseV2::upgrade)),
+            1 => Some(bincode::deserialize::<JSONDatabaseV1>(data).map(JSONDatabaseV1::upgrade)),
+            _ => None,
+        }
+    }
+
     pub fn set_dirty(&mut self) {
         self.dirty = true;
     }
 
+    /// Drops revocation entries for tokens that would have expired on their own by now, so
+    /// `revoked_tokens` doesn't grow forever across the lifetime of the server.
+    fn _prune_revoked_tokens(&mut self) {
+        let now = _now_secs();
+        self.revoked_tokens.retain(|_, &mut exp| exp > now);
+    }
+
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
@@ -133,11 +634,40 @@ impl JSONDatabase {
 
         self.dirty = false;
 
-        bincode::serialize(self).expect("could not serialize")
+        let mut bytes =
+            bincode::serialize(&DB_FORMAT_VERSION).expect("could not serialize format version");
+        bytes.extend(bincode::serialize(self).expect("could not serialize"));
+        bytes
+    }
+
+    /// Under `FlushMode::Immediate`, serializes and writes the whole arena to `self.backend`
+    /// right away instead of waiting for `main.rs`'s periodic save loop to pick up the dirty
+    /// flag. Called by the handful of mutations (`subject_add`, `teacher_set_teaches`,
+    /// `subject_add_group`, `distribute_subject_groups`, `occupancies_add`) that need
+    /// crash-durability; everything else still only sets the dirty flag and relies on the
+    /// interval flush, same as before.
+    fn _flush_if_immediate(&mut self) {
+        if self.flush_mode != FlushMode::Immediate {
+            return;
+        }
+
+        let bytes = self.dirty_to_bincode();
+
+        if let Err(e) = self.backend.write(&bytes) {
+            error!("immediate flush failed: {}", e);
+        }
     }
 }
 
 impl Database for JSONDatabase {
+    fn from_file(filename: &str) -> Result<Self, std::io::Error> {
+        Self::from_backend(&StorageBackend::File(PathBuf::from(filename)))
+    }
+
+    fn storage_backend(&self) -> StorageBackend {
+        self.backend.clone()
+    }
+
     fn delay_set(&mut self, delay: Duration) {
         self.delay = delay;
         self.set_dirty();
@@ -147,9 +677,19 @@ impl Database for JSONDatabase {
         self.delay
     }
 
+    fn schoolyear_anchor_set(&mut self, anchor: u64) {
+        self.schoolyear_anchor = anchor;
+        self.set_dirty();
+    }
+
+    fn schoolyear_anchor_get(&self) -> u64 {
+        self.schoolyear_anchor
+    }
+
     fn reset(&mut self) {
         self.dirty = true;
         self.delay = Duration::from_millis(0);
+        self.schoolyear_anchor = 0;
         self.users.clear();
         self.tokens.clear();
         self.classrooms.clear();
@@ -159,6 +699,11 @@ impl Database for JSONDatabase {
         self.subjects_students.clear();
         self.occupancies.clear();
         self.modifications.clear();
+        self.classroom_index.clear();
+        self.teacher_index.clear();
+        self.class_index.clear();
+        self.modification_feed_log.clear();
+        self.next_modification_event_id = 0;
         self.next_user_id = 0;
         self.next_classroom_id = 0;
         self.next_class_id = 0;
@@ -166,6 +711,7 @@ impl Database for JSONDatabase {
         self.next_subject_teacher_id = 0;
         self.next_subject_students_id = 0;
         self.next_occupancy_id = 0;
+        self.next_recurrence_group_id = 0;
 
         // Will call self.seed(), which calls persist
         seed_db(self);
@@ -174,16 +720,12 @@ impl Database for JSONDatabase {
     fn seed(
         &mut self,
         users: impl Iterator<Item = super::NewUser>,
-        classrooms: impl Iterator<Item = NewClassroom>,
-        classes: impl Iterator<Item = NewClass>,
         subjects: impl Iterator<Item = NewSubject>,
         occupancies: impl Iterator<Item = NewOccupancySeed>,
     ) {
-        classrooms.for_each(|c| self._classroom_add(c));
         users.for_each(|u| {
             self._user_add(u);
         });
-        classes.for_each(|c| self._class_add(c));
         subjects.for_each(|s| self._subject_add(s));
 
         // Link students to each subjects
@@ -246,6 +788,7 @@ impl Database for JSONDatabase {
                 end_datetime: new_occupancy.end_datetime,
                 occupancy_type: new_occupancy.occupancy_type,
                 name: new_occupancy.name,
+                recurrence: None,
             };
 
             self._add_occupancy(occupancy);
@@ -258,49 +801,298 @@ impl Database for JSONDatabase {
         serde_json::to_string(&self)
     }
 
-    fn auth_login(&mut self, username: &str, password: &str) -> Option<(&User, String)> {
-        let user_password = self.users.get(username).map(|u| u.password.to_string())?;
+    fn load_from_json(&mut self, json: &str) -> Result<(), serde_json::Error> {
+        let mut db: Self = serde_json::from_str(json)?;
+        db._rebuild_indexes();
+        *self = db;
+        Ok(())
+    }
 
-        if password != user_password {
-            return None;
+    fn stats(&self) -> super::Stats {
+        super::Stats {
+            occupancies: self.occupancies.len(),
+            users: self.users.len(),
+            classes: self.classes.len(),
+            subjects: self.subjects.len(),
+            classrooms: self.classrooms.len(),
+            most_recent_occupancy_end: self.occupancies.values().map(|o| o.end_datetime).max(),
+        }
+    }
+
+    /// Resolves or creates the teacher/class/classroom/subject named by each period (this is a
+    /// trusted upstream sync, not user input, so an unknown name is reconciled rather than
+    /// rejected) and inserts it the same way a manual `occupancies_create` would: through
+    /// `occupancies_add_checked`, so a period clashing with an already-scheduled occupancy (room
+    /// or teacher double-booked, ...) is reported in `ImportReport::unresolved` instead of being
+    /// silently inserted on top of it.
+    fn import_webuntis_periods(&mut self, periods: impl Iterator<Item = UntisPeriod>) -> ImportReport {
+        let mut report = ImportReport::default();
+
+        for period in periods {
+            let (teacher_id, _) = self._resolve_or_create_teacher(
+                &period.teacher_first_name,
+                &period.teacher_last_name,
+            );
+
+            let (class_id, _) = self._resolve_or_create_class(&period.class_name);
+            let (classroom_id, _) = self._resolve_or_create_classroom(&period.classroom_name);
+            let (subject_id, _) =
+                self._resolve_or_create_subject(&period.subject_name, class_id, teacher_id);
+
+            let occupancy_type = match (period.lesson_type, period.group_number) {
+                (UntisLessonType::Lesson, Some(_)) => OccupancyType::TD,
+                (UntisLessonType::Lesson, None) => OccupancyType::CM,
+                (UntisLessonType::OfficeHour, _) => OccupancyType::Administration,
+                (UntisLessonType::Exam, _) => OccupancyType::External,
+            };
+
+            let already_imported = self.occupancies.values().any(|o| {
+                o.teacher_id == teacher_id
+                    && o.classroom_id == Some(classroom_id)
+                    && o.start_datetime == period.start_datetime
+                    && o.end_datetime == period.end_datetime
+            });
+
+            if already_imported {
+                report.skipped += 1;
+                continue;
+            }
+
+            let name = period.subject_name.clone();
+
+            match self.occupancies_add_checked(NewOccupancy {
+                classroom_id: Some(classroom_id),
+                group_number: period.group_number,
+                subject_id: Some(subject_id),
+                teacher_id,
+                start_datetime: period.start_datetime,
+                end_datetime: period.end_datetime,
+                occupancy_type,
+                name: period.subject_name,
+                recurrence: None,
+            }) {
+                Ok(_id) => report.created += 1,
+                Err(conflicts) => {
+                    report.skipped += 1;
+                    report.unresolved.push(format!(
+                        "{}: clashes with existing occupancies ({:?})",
+                        name, conflicts
+                    ));
+                }
+            }
         }
 
-        let mut rng = rand::thread_rng();
+        self.set_dirty();
+        report
+    }
+
+    fn import_untis(&mut self, reader: impl Read) -> ImportReport {
+        let mut report = ImportReport::default();
+
+        let dump: UntisDump = match serde_json::from_reader(reader) {
+            Ok(dump) => dump,
+            Err(err) => {
+                report.unresolved.push(format!("could not parse Untis dump: {}", err));
+                return report;
+            }
+        };
+
+        let class_ids: HashMap<u32, u32> = dump
+            .klassen
+            .iter()
+            .map(|klasse| {
+                let (id, created) = self._resolve_or_create_class(&klasse.name);
+                if created {
+                    report.created += 1;
+                } else {
+                    report.skipped += 1;
+                }
+                (klasse.id, id)
+            })
+            .collect();
 
-        let token: String = std::iter::repeat(())
-            .map(|()| rng.sample(rand::distributions::Alphanumeric))
-            .take(25)
+        let teacher_ids: HashMap<u32, u32> = dump
+            .lehrer
+            .iter()
+            .map(|lehrer| {
+                let (id, created) = self._resolve_or_create_teacher(&lehrer.vorname, &lehrer.nachname);
+                if created {
+                    report.created += 1;
+                } else {
+                    report.skipped += 1;
+                }
+                (lehrer.id, id)
+            })
             .collect();
 
-        self.tokens.insert(token.clone(), username.to_string());
+        let faecher: HashMap<u32, &UntisFach> =
+            dump.faecher.iter().map(|fach| (fach.id, fach)).collect();
+
+        for period in &dump.periods {
+            let fach = match faecher.get(&period.fach_id) {
+                Some(fach) => fach,
+                None => {
+                    report
+                        .unresolved
+                        .push(format!("period {}: unknown fach {}", period.id, period.fach_id));
+                    continue;
+                }
+            };
+
+            let class_id = match class_ids.get(&fach.klasse_id) {
+                Some(id) => *id,
+                None => {
+                    report.unresolved.push(format!(
+                        "period {}: unknown klasse {}",
+                        period.id, fach.klasse_id
+                    ));
+                    continue;
+                }
+            };
+
+            let teacher_id = match teacher_ids.get(&period.lehrer_id) {
+                Some(id) => *id,
+                None => {
+                    report.unresolved.push(format!(
+                        "period {}: unknown lehrer {}",
+                        period.id, period.lehrer_id
+                    ));
+                    continue;
+                }
+            };
+
+            let (subject_id, subject_created) =
+                self._resolve_or_create_subject(&fach.name, class_id, teacher_id);
+            if subject_created {
+                report.created += 1;
+            }
+
+            let (classroom_id, classroom_created) =
+                self._resolve_or_create_classroom(&period.room_name);
+            if classroom_created {
+                report.created += 1;
+            }
+
+            let already_imported = self.occupancies.values().any(|o| {
+                o.teacher_id == teacher_id
+                    && o.classroom_id == Some(classroom_id)
+                    && o.start_datetime == period.start_datetime
+                    && o.end_datetime == period.end_datetime
+            });
+
+            if already_imported {
+                report.skipped += 1;
+                continue;
+            }
+
+            let occupancy_type = match (period.lesson_type, period.group_number) {
+                (UntisLessonType::Lesson, Some(_)) => OccupancyType::TD,
+                (UntisLessonType::Lesson, None) => OccupancyType::CM,
+                (UntisLessonType::OfficeHour, _) => OccupancyType::Administration,
+                (UntisLessonType::Exam, _) => OccupancyType::External,
+            };
+
+            match self.occupancies_add_checked(NewOccupancy {
+                classroom_id: Some(classroom_id),
+                group_number: period.group_number,
+                subject_id: Some(subject_id),
+                teacher_id,
+                start_datetime: period.start_datetime,
+                end_datetime: period.end_datetime,
+                occupancy_type,
+                name: fach.name.clone(),
+                recurrence: None,
+            }) {
+                Ok(_id) => report.created += 1,
+                Err(conflicts) => {
+                    report.skipped += 1;
+                    report.unresolved.push(format!(
+                        "period {}: clashes with existing occupancies ({:?})",
+                        period.id, conflicts
+                    ));
+                }
+            }
+        }
+
         self.set_dirty();
+        report
+    }
+
+    fn auth_login(&mut self, username: &str, password: &str) -> Option<(&User, String)> {
+        let user_password = self.users.get(username).map(|u| u.password.to_string())?;
+
+        match super::auth::verify_password(password, &user_password) {
+            super::auth::PasswordCheck::Invalid => return None,
+            super::auth::PasswordCheck::Valid => {}
+            super::auth::PasswordCheck::ValidNeedsRehash(rehashed) => {
+                self.users
+                    .get_mut(username)
+                    .expect("just looked up by this username above")
+                    .password = rehashed;
+                self.set_dirty();
+            }
+        }
 
         let user = self
             .users
             .get(username)
-            .expect("should be a valid reference");
+            .expect("just looked up by this username above");
+        let token = super::auth::issue_token(user);
 
         Some((user, token))
     }
 
     fn auth_logout(&mut self, token: &str) -> bool {
-        let removed = self.tokens.remove_by_left(&token.to_string()).is_some();
+        let claims = match super::auth::verify_token(token) {
+            Some(claims) => claims,
+            None => return false,
+        };
+
+        self._prune_revoked_tokens();
+        self.revoked_tokens
+            .insert(claims.jti().to_string(), claims.expires_at() as u64);
         self.set_dirty();
-        removed
+
+        true
     }
 
     fn auth_get_user<'a, 'b>(&'a self, token: &str) -> Option<&'a User> {
-        let username = self.tokens.get_by_left(&token.to_string())?; // TODO
-        self.users.get(username)
+        let claims = super::auth::verify_token(token)?;
+
+        if self.auth_is_revoked(claims.jti()) {
+            return None;
+        }
+
+        self.user_get_by_id(claims.sub)
+    }
+
+    fn auth_is_revoked(&self, jti: &str) -> bool {
+        self.revoked_tokens.contains_key(jti)
+    }
+
+    fn avatar_set(&mut self, user_id: u32, avatar: Avatar) {
+        self.avatars.insert(user_id, avatar);
+        self.set_dirty();
+    }
+
+    fn avatar_get(&self, user_id: u32) -> Option<&Avatar> {
+        self.avatars.get(&user_id)
     }
 
-    fn classroom_list(&self, page: usize, query: Option<&str>) -> (usize, Vec<&Classroom>) {
+    fn classroom_list(
+        &self,
+        page: usize,
+        per_page: Option<usize>,
+        query: Option<&str>,
+    ) -> (usize, Vec<&Classroom>) {
         _search(
             self.classrooms.values(),
             |c: &Classroom| c.name.to_string(),
             Some(page),
+            per_page,
             query,
             |_| true,
+            &[],
         )
     }
 
@@ -321,6 +1113,7 @@ impl Database for JSONDatabase {
 
         classrooms.iter().for_each(|id| {
             self.classrooms.remove(id);
+            self._sled_sync_classroom(*id);
         });
 
         self.set_dirty();
@@ -340,6 +1133,10 @@ impl Database for JSONDatabase {
                 self.set_dirty();
             }
 
+            if updated {
+                self._sled_sync_classroom(id);
+            }
+
             UpdateStatus {
                 found: true,
                 updated,
@@ -367,13 +1164,16 @@ impl Database for JSONDatabase {
     }
 
     fn user_update(&mut self, user: User) {
-        self.users.insert(user.username.clone(), user);
+        let username = user.username.clone();
+        self.users.insert(username.clone(), user);
+        self._sled_sync_user(&username);
         self.set_dirty();
     }
 
     fn user_list(
         &self,
         page: Option<usize>,
+        per_page: Option<usize>,
         query: Option<&str>,
         filter: impl Fn(&User) -> bool,
     ) -> (usize, Vec<&User>) {
@@ -381,8 +1181,10 @@ impl Database for JSONDatabase {
             self.users.values(),
             |u: &User| u.full_name(),
             page,
+            per_page,
             query,
             filter,
+            &[],
         )
     }
 
@@ -401,12 +1203,12 @@ impl Database for JSONDatabase {
             .map(|u| u.username.clone())
             .collect();
 
-        for username in removed_usernames {
-            self.tokens.remove_by_right(&username);
+        self.users.retain(|_, u| !users.contains(&u.id));
+
+        for username in &removed_usernames {
+            self._sled_sync_user(username);
         }
 
-        self.users.retain(|_, u| !users.contains(&u.id));
-        // TODO: persist
         self.set_dirty();
         true
     }
@@ -416,13 +1218,20 @@ impl Database for JSONDatabase {
         self.set_dirty();
     }
 
-    fn class_list(&self, page: usize, query: Option<&str>) -> (usize, Vec<&Class>) {
+    fn class_list(
+        &self,
+        page: usize,
+        per_page: Option<usize>,
+        query: Option<&str>,
+    ) -> (usize, Vec<&Class>) {
         _search(
             self.classes.values(),
             |c: &Class| c.name.to_string(),
             Some(page),
+            per_page,
             query,
             |_| true,
+            &[],
         )
     }
 
@@ -434,6 +1243,7 @@ impl Database for JSONDatabase {
 
         classes.iter().for_each(|id| {
             self.classes.remove(id);
+            self._sled_sync_class(*id);
         });
 
         self.set_dirty();
@@ -463,6 +1273,7 @@ impl Database for JSONDatabase {
 
             if updated {
                 self.set_dirty();
+                self._sled_sync_class(id);
             }
 
             UpdateStatus {
@@ -480,6 +1291,7 @@ impl Database for JSONDatabase {
     fn subject_list(
         &self,
         page: usize,
+        per_page: Option<usize>,
         query: Option<&str>,
         filter: impl Fn(&Subject) -> bool,
     ) -> (usize, Vec<&Subject>) {
@@ -487,14 +1299,17 @@ impl Database for JSONDatabase {
             self.subjects.values(),
             |s: &Subject| s.name.to_string(),
             Some(page),
+            per_page,
             query,
             filter,
+            &[],
         )
     }
 
     fn subject_add(&mut self, subject: NewSubject) {
         self._subject_add(subject);
         self.set_dirty();
+        self._flush_if_immediate();
     }
 
     fn subject_remove(&mut self, subjects: &[u32]) -> bool {
@@ -507,6 +1322,7 @@ impl Database for JSONDatabase {
 
         subjects.iter().for_each(|id| {
             self.subjects.remove(id);
+            self._sled_sync_subject(*id);
         });
 
         self.set_dirty();
@@ -572,6 +1388,27 @@ impl Database for JSONDatabase {
         subjects
     }
 
+    fn subjects_teachers(&self, subject_ids: &[u32]) -> HashMap<u32, Vec<(&User, bool)>> {
+        let mut by_subject: HashMap<u32, Vec<(&User, bool)>> = HashMap::new();
+
+        for subject_teacher in self.subjects_teachers.values() {
+            if !subject_ids.contains(&subject_teacher.subject_id) {
+                continue;
+            }
+
+            let teacher = self
+                .user_get_by_id(subject_teacher.teacher_id)
+                .expect("teacher_id in subjects_teachers should reference a real user");
+
+            by_subject
+                .entry(subject_teacher.subject_id)
+                .or_insert_with(Vec::new)
+                .push((teacher, subject_teacher.in_charge));
+        }
+
+        by_subject
+    }
+
     fn student_subjects(&self, student_id: u32) -> Vec<&Subject> {
         let subject_ids: Vec<u32> = self
             .subjects_students
@@ -625,6 +1462,7 @@ impl Database for JSONDatabase {
 
             if updated {
                 self.set_dirty();
+                self._sled_sync_subject(id);
             }
 
             UpdateStatus {
@@ -645,7 +1483,9 @@ impl Database for JSONDatabase {
             .get_mut(&subject_id)
             .expect("subject shoulld exist");
         subject.group_count += 1;
+        self._sled_sync_subject(subject_id);
         self.set_dirty();
+        self._flush_if_immediate();
     }
 
     fn subject_remove_group(&mut self, subject_id: u32) -> bool {
@@ -667,6 +1507,7 @@ impl Database for JSONDatabase {
     fn teacher_set_teaches(&mut self, teacher_id: u32, subject_id: u32) {
         self._set_teaches(subject_id, teacher_id, None);
         self.set_dirty();
+        self._flush_if_immediate();
     }
 
     fn teacher_unset_teaches(&mut self, teacher_id: u32, subject_id: u32) {
@@ -674,9 +1515,12 @@ impl Database for JSONDatabase {
         self.set_dirty();
     }
 
-    fn distribute_subject_groups(&mut self, subject_id: u32) {
-        self._distribute_subject_groups(subject_id);
+    fn distribute_subject_groups(&mut self, subject_id: u32, rebalance: bool) -> Vec<u32> {
+        let changed = self._distribute_subject_groups(subject_id, rebalance);
         self.set_dirty();
+        self._flush_if_immediate();
+
+        changed
     }
 
     fn student_group(&self, student_id: u32, subject_id: u32) -> u32 {
@@ -687,23 +1531,32 @@ impl Database for JSONDatabase {
             .group_number
     }
 
-    fn occupancies_list(&self, from: Option<u64>, to: Option<u64>) -> Vec<&Occupancy> {
+    fn occupancies_list(&self, from: Option<u64>, to: Option<u64>) -> Vec<OccupancyOccurrence> {
+        let from = from.unwrap_or(0);
+        let to = to.unwrap_or(u64::MAX);
+
         self.occupancies
             .values()
-            .filter(|o| {
-                if let Some(from) = from {
-                    if o.start_datetime < from {
-                        return false;
-                    }
-                }
+            .flat_map(|o| self._occurrences(o, from, to))
+            .collect()
+    }
 
-                if let Some(to) = to {
-                    if o.end_datetime > to {
-                        return false;
-                    }
+    fn occupancies_list_effective(&self, from: Option<u64>, to: Option<u64>) -> Vec<EffectiveOccupancy> {
+        self.occupancies_list(from, to)
+            .into_iter()
+            .map(|occurrence| {
+                let substitution = self
+                    .modifications
+                    .values()
+                    .flatten()
+                    .filter(|m| m.occupancy.occupancy_id == occurrence.id)
+                    .max_by_key(|m| m.modification_timestamp)
+                    .and_then(|m| m.occupancy.substitution.clone());
+
+                EffectiveOccupancy {
+                    occurrence,
+                    substitution,
                 }
-
-                true
             })
             .collect()
     }
@@ -717,9 +1570,28 @@ impl Database for JSONDatabase {
             return false;
         }
 
-        occupancies.iter().for_each(|id| {
-            self.occupancies.remove(id);
-        });
+        for id in occupancies {
+            if let Some(occupancy) = self.occupancies.remove(id) {
+                let (start, _) = _occupancy_index_bounds(&occupancy);
+
+                let class_id = occupancy
+                    .subject_id
+                    .and_then(|subject_id| self.subjects.get(&subject_id))
+                    .map(|subject| subject.class_id);
+
+                if let Some(classroom_id) = occupancy.classroom_id {
+                    _index_remove(&mut self.classroom_index, classroom_id, start, occupancy.id);
+                }
+
+                _index_remove(&mut self.teacher_index, occupancy.teacher_id, start, occupancy.id);
+
+                if let Some(class_id) = class_id {
+                    _index_remove(&mut self.class_index, class_id, start, occupancy.id);
+                }
+
+                self._sled_sync_occupancy(occupancy.id);
+            }
+        }
 
         self.set_dirty();
 
@@ -729,34 +1601,237 @@ impl Database for JSONDatabase {
     fn occupancies_add(&mut self, occupancy: NewOccupancy) {
         self._add_occupancy(occupancy);
         self.set_dirty();
+        self._flush_if_immediate();
     }
 
-    fn classroom_free(&self, classroom_id: u32, from: u64, to: u64) -> bool {
-        // Find an occupancy that is in this classroom, and between from and to
-        !self.occupancies.values().any(|o| {
-            o.classroom_id == Some(classroom_id) && o.start_datetime >= from && o.end_datetime <= to
-        })
+    fn occupancies_add_checked(&mut self, occupancy: NewOccupancy) -> Result<u32, Vec<Conflict>> {
+        let conflicts = self._check_occupancy_conflicts(&occupancy, None);
+
+        if !conflicts.is_empty() {
+            return Err(conflicts);
+        }
+
+        let id = self.next_occupancy_id;
+        self._add_occupancy(occupancy);
+        self.set_dirty();
+
+        Ok(id)
     }
 
-    fn teacher_free(&self, teacher_id: u32, from: u64, to: u64) -> bool {
-        !self
-            .occupancies
-            .values()
-            .any(|o| o.teacher_id == teacher_id && o.start_datetime >= from && o.end_datetime <= to)
+    fn occupancies_add_recurring(
+        &mut self,
+        template: NewOccupancy,
+        recurrence: RecurrenceSpec,
+    ) -> RecurrenceReport {
+        let report = self._add_recurring_occupancy(template, recurrence);
+        self.set_dirty();
+
+        report
     }
 
-    fn class_free(&self, class_id: u32, from: u64, to: u64) -> bool {
-        !self.occupancies.values().any(|o| {
-            // Find an occupancy with a subject
-            let subject = match o.subject_id.and_then(|sid| self.subject_get(sid)) {
-                Some(subject) => subject,
-                None => return false,
+    fn occupancies_update(&mut self, id: u32, update: OccupancyUpdate) -> UpdateStatus {
+        if !self.occupancies.contains_key(&id) {
+            return UpdateStatus {
+                found: false,
+                updated: false,
             };
+        }
+
+        let updated = self._apply_occupancy_update(id, update);
+
+        if updated {
+            self.set_dirty();
+        }
 
-            subject.class_id == class_id && o.start_datetime >= from && o.end_datetime <= to
+        UpdateStatus {
+            found: true,
+            updated,
+        }
+    }
+
+    fn occupancies_update_checked(
+        &mut self,
+        id: u32,
+        update: OccupancyUpdate,
+    ) -> Result<UpdateStatus, Vec<Conflict>> {
+        if !self.occupancies.contains_key(&id) {
+            return Ok(UpdateStatus {
+                found: false,
+                updated: false,
+            });
+        }
+
+        let conflicts = self._check_occupancy_update_conflicts(id, &update);
+
+        if !conflicts.is_empty() {
+            return Err(conflicts);
+        }
+
+        let updated = self._apply_occupancy_update(id, update);
+
+        if updated {
+            self.set_dirty();
+        }
+
+        Ok(UpdateStatus {
+            found: true,
+            updated,
         })
     }
 
+    fn occupancies_batch(
+        &mut self,
+        add: Vec<NewOccupancy>,
+        update: Vec<(u32, OccupancyUpdate)>,
+        remove: Vec<u32>,
+    ) -> BatchReport {
+        let add_status: Vec<BatchItemStatus> = add
+            .iter()
+            .map(|occupancy| self._check_new_occupancy_status(occupancy))
+            .collect();
+
+        let update_status: Vec<BatchItemStatus> = update
+            .iter()
+            .map(|(id, item)| self._check_occupancy_update_status(*id, item))
+            .collect();
+
+        let remove_status: Vec<BatchItemStatus> = remove
+            .iter()
+            .map(|id| {
+                if self.occupancies.contains_key(id) {
+                    BatchItemStatus::Ok
+                } else {
+                    BatchItemStatus::NotFound
+                }
+            })
+            .collect();
+
+        let committed = add_status.iter().chain(&update_status).chain(&remove_status)
+            .all(|status| matches!(status, BatchItemStatus::Ok));
+
+        if committed {
+            for occupancy in add {
+                self._add_occupancy(occupancy);
+            }
+
+            for (id, item) in update {
+                self._apply_occupancy_update(id, item);
+            }
+
+            self.occupancies_remove(&remove);
+
+            self.set_dirty();
+        }
+
+        let to_results = |statuses: Vec<BatchItemStatus>| {
+            statuses
+                .into_iter()
+                .enumerate()
+                .map(|(index, status)| BatchItemResult { index, status })
+                .collect()
+        };
+
+        BatchReport {
+            committed,
+            add: to_results(add_status),
+            update: to_results(update_status),
+            remove: to_results(remove_status),
+        }
+    }
+
+    /// Validates a batch `add` item against the same rules `occupancies_add_checked` relies on
+    /// routes to have already checked before calling it: the referenced teacher/subject/classroom
+    /// must exist and the time range must be well-formed. `occupancies_batch` needs this itself
+    /// since its items aren't scoped to one subject route.
+    fn _check_new_occupancy_status(&self, occupancy: &NewOccupancy) -> BatchItemStatus {
+        if occupancy.end_datetime < occupancy.start_datetime {
+            return BatchItemStatus::Invalid;
+        }
+
+        if self.user_get_teacher_by_id(occupancy.teacher_id).is_none() {
+            return BatchItemStatus::Invalid;
+        }
+
+        if let Some(subject_id) = occupancy.subject_id {
+            if self.subject_get(subject_id).is_none() {
+                return BatchItemStatus::Invalid;
+            }
+        }
+
+        if let Some(classroom_id) = occupancy.classroom_id {
+            if self.classroom_get(classroom_id).is_none() {
+                return BatchItemStatus::Invalid;
+            }
+        }
+
+        let conflicts = self._check_occupancy_conflicts(occupancy, None);
+
+        if conflicts.is_empty() {
+            BatchItemStatus::Ok
+        } else {
+            BatchItemStatus::Conflict(conflicts)
+        }
+    }
+
+    /// Same as `_check_new_occupancy_status`, but for a batch `update` item: also checks that `id`
+    /// exists and that a `classroom_id` in `update`, if given, is valid.
+    fn _check_occupancy_update_status(&self, id: u32, update: &OccupancyUpdate) -> BatchItemStatus {
+        if !self.occupancies.contains_key(&id) {
+            return BatchItemStatus::NotFound;
+        }
+
+        if let Some(end) = update.end {
+            let start = update.start.unwrap_or(self.occupancies[&id].start_datetime);
+
+            if end < start {
+                return BatchItemStatus::Invalid;
+            }
+        }
+
+        if let Some(classroom_id) = update.classroom_id {
+            if self.classroom_get(classroom_id).is_none() {
+                return BatchItemStatus::Invalid;
+            }
+        }
+
+        let conflicts = self._check_occupancy_update_conflicts(id, update);
+
+        if conflicts.is_empty() {
+            BatchItemStatus::Ok
+        } else {
+            BatchItemStatus::Conflict(conflicts)
+        }
+    }
+
+    fn classroom_free(&self, classroom_id: u32, from: u64, to: u64) -> bool {
+        // Only the occupancies the index says could overlap [from, to) need the exact
+        // recurrence-aware check, instead of every occupancy in the database.
+        !_index_candidates(self.classroom_index.get(&classroom_id), from, to)
+            .into_iter()
+            .any(|occupancy_id| {
+                let occupancy = &self.occupancies[&occupancy_id];
+                !self._occurrences(occupancy, from, to).is_empty()
+            })
+    }
+
+    fn teacher_free(&self, teacher_id: u32, from: u64, to: u64) -> bool {
+        !_index_candidates(self.teacher_index.get(&teacher_id), from, to)
+            .into_iter()
+            .any(|occupancy_id| {
+                let occupancy = &self.occupancies[&occupancy_id];
+                !self._occurrences(occupancy, from, to).is_empty()
+            })
+    }
+
+    fn class_free(&self, class_id: u32, from: u64, to: u64) -> bool {
+        !_index_candidates(self.class_index.get(&class_id), from, to)
+            .into_iter()
+            .any(|occupancy_id| {
+                let occupancy = &self.occupancies[&occupancy_id];
+                !self._occurrences(occupancy, from, to).is_empty()
+            })
+    }
+
     fn group_free(
         &self,
         class_id: u32,
@@ -774,9 +1849,8 @@ impl Database for JSONDatabase {
 
             subject.id == subject_id
                 && subject.class_id == class_id
-                && o.start_datetime >= from
-                && o.end_datetime <= to
                 && o.group_number == Some(group_number)
+                && !self._occurrences(o, from, to).is_empty()
         })
     }
 
@@ -786,6 +1860,18 @@ impl Database for JSONDatabase {
             .map(|v| v.iter().collect())
             .unwrap_or(Vec::new())
     }
+
+    fn occupancies_modifications_since(&self, since: Option<u64>) -> Vec<ModificationEvent> {
+        self.modification_feed_log
+            .iter()
+            .filter(|event| since.map(|since| event.id > since).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    fn occupancies_modifications_subscribe(&self) -> broadcast::Receiver<ModificationEvent> {
+        self.modification_feed.subscribe()
+    }
 }
 
 impl JSONDatabase {
@@ -799,15 +1885,97 @@ impl JSONDatabase {
                 first_name: user.first_name,
                 last_name: user.last_name,
                 username: username.clone(),
-                password: user.password,
+                password: super::auth::hash_password(&user.password),
                 kind: user.kind,
             },
         );
 
         self.next_user_id += 1;
+        self._sled_sync_user(&username);
         username
     }
 
+    /// Finds a classroom by name, or creates it (with a default capacity, since WebUntis doesn't
+    /// expose one) if this is the first time it's seen. Returns the local id and whether it was
+    /// just created.
+    fn _resolve_or_create_classroom(&mut self, name: &str) -> (u32, bool) {
+        if let Some((id, _)) = self.classrooms.iter().find(|(_, c)| c.name == name) {
+            return (*id, false);
+        }
+
+        let id = self.next_classroom_id;
+        self._classroom_add(NewClassroom {
+            name: name.to_string(),
+            capacity: 50,
+        });
+        (id, true)
+    }
+
+    /// Finds a class by name, or creates it (defaulting to L3, since WebUntis doesn't expose our
+    /// notion of class level) if this is the first time it's seen. Returns the local id and
+    /// whether it was just created.
+    fn _resolve_or_create_class(&mut self, name: &str) -> (u32, bool) {
+        if let Some((id, _)) = self.classes.iter().find(|(_, c)| c.name == name) {
+            return (*id, false);
+        }
+
+        let id = self.next_class_id;
+        self._class_add(NewClass {
+            name: name.to_string(),
+            level: ClassLevel::L3,
+        });
+        (id, true)
+    }
+
+    /// Finds a teacher by name, or creates a new teacher account (using the same
+    /// `username_from_name`/password-is-username convention as `seed_db`) if this is the first
+    /// time it's seen. Returns the local id and whether it was just created.
+    fn _resolve_or_create_teacher(&mut self, first_name: &str, last_name: &str) -> (u32, bool) {
+        let existing = self.users.values().find(|u| {
+            u.first_name == first_name && u.last_name == last_name && matches!(u.kind, UserKind::Teacher(_))
+        });
+
+        if let Some(user) = existing {
+            return (user.id, false);
+        }
+
+        let id = self.next_user_id;
+        let username = username_from_name(first_name, last_name);
+        self._user_add(NewUser {
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            password: username,
+            kind: UserKind::Teacher(TeacherInformations {
+                phone_number: None,
+                email: None,
+                rank: Rank::Professor,
+            }),
+        });
+        (id, true)
+    }
+
+    /// Finds a subject by name within `class_id`, or creates it (with `teacher_id` as the
+    /// teacher in charge) if this is the first time it's seen. Returns the local id and whether
+    /// it was just created.
+    fn _resolve_or_create_subject(&mut self, name: &str, class_id: u32, teacher_id: u32) -> (u32, bool) {
+        let existing = self
+            .subjects
+            .iter()
+            .find(|(_, s)| s.name == name && s.class_id == class_id);
+
+        if let Some((id, _)) = existing {
+            return (*id, false);
+        }
+
+        let id = self.next_subject_id;
+        self._subject_add(NewSubject {
+            class_id,
+            name: name.to_string(),
+            teacher_in_charge_id: teacher_id,
+        });
+        (id, true)
+    }
+
     fn _classroom_add(&mut self, classroom: NewClassroom) {
         let classroom = Classroom {
             id: self.next_classroom_id,
@@ -816,6 +1984,7 @@ impl JSONDatabase {
         };
 
         self.classrooms.insert(self.next_classroom_id, classroom);
+        self._sled_sync_classroom(self.next_classroom_id);
         self.next_classroom_id += 1;
     }
 
@@ -827,6 +1996,7 @@ impl JSONDatabase {
         };
 
         self.classes.insert(self.next_class_id, class);
+        self._sled_sync_class(self.next_class_id);
         self.next_class_id += 1;
     }
 
@@ -848,6 +2018,7 @@ impl JSONDatabase {
         };
 
         self.subjects.insert(self.next_subject_id, subject);
+        self._sled_sync_subject(self.next_subject_id);
         self.subjects_teachers
             .insert(self.next_subject_teacher_id, subject_teacher);
         self.next_subject_id += 1;
@@ -916,7 +2087,15 @@ impl JSONDatabase {
         }
     }
 
-    fn _distribute_subject_groups(&mut self, subject_id: u32) {
+    /// Assigns `StudentSubject.group_number` for every student enrolled in `subject_id`, returning
+    /// the ids of the students whose group number actually changed.
+    ///
+    /// If `rebalance` is set, every student is re-sorted by name and reassigned from scratch (the
+    /// original behavior), which also reshuffles students who were already correctly placed. If
+    /// not, only students sitting at the `UNGROUPED` sentinel (freshly enrolled via
+    /// `_subject_add_student`, or orphaned by a `subject_remove_group` shrink) are placed, each
+    /// into whichever group currently has the fewest members; everyone else keeps their group.
+    fn _distribute_subject_groups(&mut self, subject_id: u32, rebalance: bool) -> Vec<u32> {
         let group_count = self
             .subject_get(subject_id)
             .expect("subject should exist.")
@@ -927,21 +2106,58 @@ impl JSONDatabase {
         students.sort_by_key(|s| s.full_name());
         let student_ids: Vec<u32> = students.iter().map(|s| s.id).collect();
 
-        // Assign groups
-        let groups = groups(student_ids.len(), group_count);
+        let assignments: Vec<(u32, u32)> = if rebalance {
+            let groups = groups(student_ids.len(), group_count);
+            student_ids.iter().copied().zip(groups).collect()
+        } else {
+            let mut group_sizes = vec![0usize; group_count as usize];
+            let mut unplaced = Vec::new();
+
+            for &student_id in &student_ids {
+                let group_number = self.student_group(student_id, subject_id);
+
+                if group_number < group_count {
+                    group_sizes[group_number as usize] += 1;
+                } else {
+                    unplaced.push(student_id);
+                }
+            }
+
+            unplaced
+                .into_iter()
+                .map(|student_id| {
+                    let (group_number, _) = group_sizes
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, size)| **size)
+                        .expect("group_count should be at least 1");
 
-        for (student_id, group_number) in student_ids.iter().zip(groups.iter()) {
-            let mut student_subject = self
+                    group_sizes[group_number] += 1;
+
+                    (student_id, group_number as u32)
+                })
+                .collect()
+        };
+
+        let mut changed = Vec::new();
+
+        for (student_id, group_number) in assignments {
+            let student_subject = self
                 .subjects_students
                 .values_mut()
                 .find(|subject_student| {
-                    subject_student.student_id == *student_id
+                    subject_student.student_id == student_id
                         && subject_student.subject_id == subject_id
                 })
                 .expect("student should participate in the subject (checked earlier)");
 
-            student_subject.group_number = *group_number;
+            if student_subject.group_number != group_number {
+                student_subject.group_number = group_number;
+                changed.push(student_id);
+            }
         }
+
+        changed
     }
 
     fn _subject_add_student(&mut self, subject_id: u32, student_id: u32) -> bool {
@@ -958,7 +2174,7 @@ impl JSONDatabase {
                 id: self.next_subject_students_id,
                 student_id,
                 subject_id,
-                group_number: 0, // TODO!!!
+                group_number: UNGROUPED,
             };
 
             self.subjects_students
@@ -972,6 +2188,17 @@ impl JSONDatabase {
     }
 
     fn _add_occupancy(&mut self, occupancy: NewOccupancy) {
+        self._add_occupancy_with_group(occupancy, None);
+    }
+
+    /// Does what `_add_occupancy` does, but also tags the inserted row with
+    /// `recurrence_group_id` (used by `_add_recurring_occupancy` so every slot it generates shares
+    /// the same group), and returns the id the row was inserted under.
+    fn _add_occupancy_with_group(
+        &mut self,
+        occupancy: NewOccupancy,
+        recurrence_group_id: Option<u32>,
+    ) -> u32 {
         let occupancy = Occupancy {
             id: self.next_occupancy_id,
             classroom_id: occupancy.classroom_id,
@@ -982,38 +2209,11 @@ impl JSONDatabase {
             end_datetime: occupancy.end_datetime,
             occupancy_type: occupancy.occupancy_type,
             name: occupancy.name,
+            recurrence: occupancy.recurrence,
+            recurrence_group_id,
         };
 
-        // Initialize with teacher id
-        let mut affected_users: Vec<u32> = vec![occupancy.teacher_id];
-
-        // Add subject id
-        if let Some(subject_id) = occupancy.subject_id {
-            // Find each student in the subject
-            let ss: Vec<(u32, u32)> = self
-                .subjects_students
-                .values()
-                .filter(|ss| ss.subject_id == subject_id)
-                .map(|ss| {
-                    let student = self
-                        .user_get_student_by_id(ss.student_id)
-                        .expect("should be a valid reference");
-
-                    (ss.group_number, student.id)
-                })
-                .collect();
-
-            // If there is a group, then only choose those in that group
-            if let Some(group_number) = occupancy.group_number {
-                affected_users.extend(
-                    ss.iter()
-                        .filter(|(user_group_number, _)| user_group_number == &group_number)
-                        .map(|(_, uid)| uid),
-                );
-            } else {
-                affected_users.extend(ss.iter().map(|(_, uid)| uid));
-            }
-        }
+        let affected_users = self._occupancy_affected_users(&occupancy);
 
         let start = SystemTime::now();
         let since_the_epoch = start
@@ -1023,106 +2223,1262 @@ impl JSONDatabase {
         let subject = occupancy
             .subject_id
             .map(|id| self.subject_get(id).expect("should be a valid reference"));
+        let class_id = subject.map(|s| s.class_id);
 
         let modification = Modification {
             modification_type: ModificationType::Create,
             modification_timestamp: since_the_epoch.as_secs(),
             occupancy: ModificationOccupancy {
+                occupancy_id: occupancy.id,
                 subject_id: occupancy.subject_id,
-                class_id: subject.map(|s| s.class_id),
+                class_id,
                 occupancy_type: occupancy.occupancy_type.clone(),
                 occupancy_start: occupancy.start_datetime,
                 occupancy_end: occupancy.end_datetime,
                 previous_occupancy_start: occupancy.start_datetime,
                 previous_occupancy_end: occupancy.end_datetime,
+                substitution: None,
             },
         };
 
         self._add_modification(&affected_users[..], modification);
 
-        self.occupancies.insert(self.next_occupancy_id, occupancy);
-        self.next_occupancy_id += 1;
-    }
+        let (index_start, index_end) = _occupancy_index_bounds(&occupancy);
 
-    fn _add_modification(&mut self, affected_users: &[u32], modification: Modification) {
-        // TODO: keep to only last 25
-        for uid in affected_users {
-            let vec = self.modifications.entry(*uid).or_insert(Vec::new());
-            vec.insert(0, modification.clone());
-            vec.truncate(25);
+        if let Some(classroom_id) = occupancy.classroom_id {
+            _index_insert(&mut self.classroom_index, classroom_id, index_start, index_end, occupancy.id);
+        }
+
+        _index_insert(&mut self.teacher_index, occupancy.teacher_id, index_start, index_end, occupancy.id);
+
+        if let Some(class_id) = class_id {
+            _index_insert(&mut self.class_index, class_id, index_start, index_end, occupancy.id);
         }
+
+        let id = self.next_occupancy_id;
+        self.occupancies.insert(id, occupancy);
+        self._sled_sync_occupancy(id);
+        self.next_occupancy_id += 1;
+
+        id
     }
-}
 
-fn _search<'a, T, F>(
-    collection: impl Iterator<Item = &'a T>,
-    property: F,
-    page: Option<usize>,
-    query: Option<&str>,
-    custom_filter: impl Fn(&T) -> bool,
-) -> (usize, Vec<&'a T>)
-where
-    F: Fn(&T) -> String,
-{
-    let mut filter = contains_query(query, property);
+    /// Rebuilds the `#[serde(skip)]` free-busy indexes from `self.occupancies`, needed after a
+    /// bincode load since the indexes themselves aren't persisted.
+    fn _rebuild_indexes(&mut self) {
+        self.classroom_index.clear();
+        self.teacher_index.clear();
+        self.class_index.clear();
 
-    // If no page arg is provided, then return the whole collection.
-    let page = match page {
-        Some(page) => page,
-        None => {
-            let vec: Vec<&T> = collection
-                .filter(|row| filter(&row) && custom_filter(&row))
-                .collect();
+        for occupancy in self.occupancies.values() {
+            let (index_start, index_end) = _occupancy_index_bounds(occupancy);
 
-            return (vec.len(), vec);
-        }
-    };
+            let class_id = occupancy
+                .subject_id
+                .and_then(|subject_id| self.subjects.get(&subject_id))
+                .map(|subject| subject.class_id);
+
+            if let Some(classroom_id) = occupancy.classroom_id {
+                _index_insert(&mut self.classroom_index, classroom_id, index_start, index_end, occupancy.id);
+            }
 
-    let mut total = 0;
-    let mut skipped = 0;
-    let mut results: Vec<&T> = Vec::new();
-    let to_skip = (page - 1) * PAGE_SIZE;
+            _index_insert(&mut self.teacher_index, occupancy.teacher_id, index_start, index_end, occupancy.id);
 
-    for row in collection {
-        if !filter(&row) || !custom_filter(&row) {
-            continue;
+            if let Some(class_id) = class_id {
+                _index_insert(&mut self.class_index, class_id, index_start, index_end, occupancy.id);
+            }
         }
+    }
+
+    /// Users who should see a modification to `occupancy`: its teacher, plus the students
+    /// affected by its subject/group (see `_affected_students`).
+    fn _occupancy_affected_users(&self, occupancy: &Occupancy) -> Vec<u32> {
+        let mut affected_users: Vec<u32> = vec![occupancy.teacher_id];
+        affected_users.extend(self._affected_students(occupancy.subject_id, occupancy.group_number));
+        affected_users
+    }
+
+    /// Student ids enrolled in `subject_id`, restricted to `group_number` when given, or an empty
+    /// list when there is no subject at all (e.g. an administrative occupancy).
+    fn _affected_students(&self, subject_id: Option<u32>, group_number: Option<u32>) -> Vec<u32> {
+        let subject_id = match subject_id {
+            Some(subject_id) => subject_id,
+            None => return Vec::new(),
+        };
+
+        let ss: Vec<(u32, u32)> = self
+            .subjects_students
+            .values()
+            .filter(|ss| ss.subject_id == subject_id)
+            .map(|ss| {
+                let student = self
+                    .user_get_student_by_id(ss.student_id)
+                    .expect("should be a valid reference");
 
-        total += 1;
+                (ss.group_number, student.id)
+            })
+            .collect();
 
-        if skipped < to_skip {
-            skipped += 1;
-        } else if results.len() < PAGE_SIZE {
-            results.push(row);
+        match group_number {
+            Some(group_number) => ss
+                .into_iter()
+                .filter(|(ss_group_number, _)| *ss_group_number == group_number)
+                .map(|(_, uid)| uid)
+                .collect(),
+            None => ss.into_iter().map(|(_, uid)| uid).collect(),
         }
     }
 
-    (total, results)
-}
+    /// Cancels `occupancy_id`: keeps the stored occupancy as-is (the raw, unmodified timetable is
+    /// untouched) but records a `Cancellation` substitution so `occupancies_list_effective` shows
+    /// it as cancelled instead of creating a whole removal path for what is, on a real
+    /// timetable, still a booked slot that just isn't happening.
+    fn _cancel_occupancy(&mut self, occupancy_id: u32) -> bool {
+        let occupancy = match self.occupancies.get(&occupancy_id) {
+            Some(occupancy) => occupancy,
+            None => return false,
+        };
 
-/// Returns a function to be used as a filter that checks if the provided query is contained in the
-/// object string.
-fn contains_query<T, F>(query: Option<&str>, property: F) -> impl FnMut(&&T) -> bool
-where
-    F: Fn(&T) -> String,
-{
-    let normalize = |s: &str| unidecode::unidecode(s.trim()).to_ascii_lowercase();
-    let query = query.map(|d| truncate(d, 50)).map(normalize);
+        let affected_users = self._occupancy_affected_users(occupancy);
+        let class_id = occupancy
+            .subject_id
+            .and_then(|subject_id| self.subject_get(subject_id))
+            .map(|subject| subject.class_id);
 
-    move |object: &&T| {
-        if let Some(query) = &query {
-            let name = property(object);
-            let name = normalize(&name);
-            name.contains(query)
-        } else {
-            true
-        }
+        let modification = Modification {
+            modification_type: ModificationType::Edit,
+            modification_timestamp: _now_secs(),
+            occupancy: ModificationOccupancy {
+                occupancy_id,
+                subject_id: occupancy.subject_id,
+                class_id,
+                occupancy_type: occupancy.occupancy_type.clone(),
+                occupancy_start: occupancy.start_datetime,
+                occupancy_end: occupancy.end_datetime,
+                previous_occupancy_start: occupancy.start_datetime,
+                previous_occupancy_end: occupancy.end_datetime,
+                substitution: Some(Substitution::Cancellation),
+            },
+        };
+
+        self._add_modification(&affected_users, modification);
+        self.set_dirty();
+
+        true
     }
-}
 
-fn truncate(s: &str, max_chars: usize) -> &str {
-    match s.char_indices().nth(max_chars) {
-        None => s,
-        Some((idx, _)) => &s[..idx],
+    /// Replaces `occupancy_id`'s teacher with `new_teacher_id`, moving it between the per-teacher
+    /// indexes, and pushes a `TeacherSubstitution` modification naming the teacher it replaces.
+    /// `affected_users` includes both the outgoing and the incoming teacher, alongside the usual
+    /// students.
+    fn _substitute_teacher(&mut self, occupancy_id: u32, new_teacher_id: u32) -> bool {
+        let (previous_teacher_id, index_start) = match self.occupancies.get(&occupancy_id) {
+            Some(occupancy) => {
+                let (start, _) = _occupancy_index_bounds(occupancy);
+                (occupancy.teacher_id, start)
+            }
+            None => return false,
+        };
+
+        if previous_teacher_id == new_teacher_id {
+            return false;
+        }
+
+        let occupancy = self
+            .occupancies
+            .get_mut(&occupancy_id)
+            .expect("existence checked above");
+        occupancy.teacher_id = new_teacher_id;
+
+        let occupancy = self.occupancies.get(&occupancy_id).expect("just updated");
+        let (_, index_end) = _occupancy_index_bounds(occupancy);
+        let class_id = occupancy
+            .subject_id
+            .and_then(|subject_id| self.subject_get(subject_id))
+            .map(|subject| subject.class_id);
+        let mut affected_users = self._occupancy_affected_users(occupancy);
+        affected_users.push(previous_teacher_id);
+
+        let modification = Modification {
+            modification_type: ModificationType::Edit,
+            modification_timestamp: _now_secs(),
+            occupancy: ModificationOccupancy {
+                occupancy_id,
+                subject_id: occupancy.subject_id,
+                class_id,
+                occupancy_type: occupancy.occupancy_type.clone(),
+                occupancy_start: occupancy.start_datetime,
+                occupancy_end: occupancy.end_datetime,
+                previous_occupancy_start: occupancy.start_datetime,
+                previous_occupancy_end: occupancy.end_datetime,
+                substitution: Some(Substitution::TeacherSubstitution { previous_teacher_id }),
+            },
+        };
+
+        _index_remove(&mut self.teacher_index, previous_teacher_id, index_start, occupancy_id);
+        _index_insert(&mut self.teacher_index, new_teacher_id, index_start, index_end, occupancy_id);
+
+        self._add_modification(&affected_users, modification);
+        self.set_dirty();
+
+        true
+    }
+
+    /// Moves `occupancy_id` to a new `[start, end)`, updating the classroom/teacher/class indexes
+    /// to match, and pushes a `Reschedule` modification recording the slot it moved from.
+    fn _move_occupancy(&mut self, occupancy_id: u32, new_start: u64, new_end: u64) -> bool {
+        let (previous_start, previous_end, classroom_id, teacher_id, class_id, old_index_start) =
+            match self.occupancies.get(&occupancy_id) {
+                Some(occupancy) => {
+                    let class_id = occupancy
+                        .subject_id
+                        .and_then(|subject_id| self.subject_get(subject_id))
+                        .map(|subject| subject.class_id);
+                    let (index_start, _) = _occupancy_index_bounds(occupancy);
+
+                    (
+                        occupancy.start_datetime,
+                        occupancy.end_datetime,
+                        occupancy.classroom_id,
+                        occupancy.teacher_id,
+                        class_id,
+                        index_start,
+                    )
+                }
+                None => return false,
+            };
+
+        if let Some(classroom_id) = classroom_id {
+            _index_remove(&mut self.classroom_index, classroom_id, old_index_start, occupancy_id);
+        }
+        _index_remove(&mut self.teacher_index, teacher_id, old_index_start, occupancy_id);
+        if let Some(class_id) = class_id {
+            _index_remove(&mut self.class_index, class_id, old_index_start, occupancy_id);
+        }
+
+        let occupancy = self
+            .occupancies
+            .get_mut(&occupancy_id)
+            .expect("existence checked above");
+        occupancy.start_datetime = new_start;
+        occupancy.end_datetime = new_end;
+
+        let occupancy = self.occupancies.get(&occupancy_id).expect("just updated");
+        let (new_index_start, new_index_end) = _occupancy_index_bounds(occupancy);
+        let affected_users = self._occupancy_affected_users(occupancy);
+        let subject_id = occupancy.subject_id;
+        let occupancy_type = occupancy.occupancy_type.clone();
+
+        if let Some(classroom_id) = classroom_id {
+            _index_insert(&mut self.classroom_index, classroom_id, new_index_start, new_index_end, occupancy_id);
+        }
+        _index_insert(&mut self.teacher_index, teacher_id, new_index_start, new_index_end, occupancy_id);
+        if let Some(class_id) = class_id {
+            _index_insert(&mut self.class_index, class_id, new_index_start, new_index_end, occupancy_id);
+        }
+
+        let modification = Modification {
+            modification_type: ModificationType::Edit,
+            modification_timestamp: _now_secs(),
+            occupancy: ModificationOccupancy {
+                occupancy_id,
+                subject_id,
+                class_id,
+                occupancy_type,
+                occupancy_start: new_start,
+                occupancy_end: new_end,
+                previous_occupancy_start: previous_start,
+                previous_occupancy_end: previous_end,
+                substitution: Some(Substitution::Reschedule),
+            },
+        };
+
+        self._add_modification(&affected_users, modification);
+        self.set_dirty();
+
+        true
+    }
+
+    /// Expands `occupancy` into the concrete occurrences whose `[start, end]` falls fully inside
+    /// `[from, to]`. A non-recurring occupancy yields at most its own single slot; a recurring one
+    /// steps forward week by week from its first occurrence until `recurrence.until`, keeping only
+    /// the weeks whose A/B parity (computed from `schoolyear_anchor`) matches `week_type`.
+    fn _occurrences<'a>(
+        &self,
+        occupancy: &'a Occupancy,
+        from: u64,
+        to: u64,
+    ) -> Vec<OccupancyOccurrence<'a>> {
+        let recurrence = match &occupancy.recurrence {
+            None => {
+                return if occupancy.start_datetime >= from && occupancy.end_datetime <= to {
+                    vec![OccupancyOccurrence {
+                        occupancy,
+                        start_datetime: occupancy.start_datetime,
+                        end_datetime: occupancy.end_datetime,
+                    }]
+                } else {
+                    Vec::new()
+                };
+            }
+            Some(recurrence) => recurrence,
+        };
+
+        let duration = occupancy.end_datetime - occupancy.start_datetime;
+        let mut occurrences = Vec::new();
+        let mut start = occupancy.start_datetime;
+
+        while start <= recurrence.until && start <= to {
+            let end = start + duration;
+
+            let week_index = start.saturating_sub(self.schoolyear_anchor) / WEEK_SECONDS;
+            let matches_week_type = match recurrence.week_type {
+                WeekType::Every => true,
+                WeekType::A => week_index % 2 == 0,
+                WeekType::B => week_index % 2 == 1,
+            };
+
+            if matches_week_type && start >= from && end <= to {
+                occurrences.push(OccupancyOccurrence {
+                    occupancy,
+                    start_datetime: start,
+                    end_datetime: end,
+                });
+            }
+
+            start += WEEK_SECONDS;
+        }
+
+        occurrences
+    }
+
+    /// True-overlap (not containment) conflicts `occupancy` would have against already-stored
+    /// occupancies: the same classroom, the same teacher, the same class, the same
+    /// subject+group, or any student shared between the two (even across unrelated subjects),
+    /// plus a capacity check of the group against the requested classroom. `exclude_id`, when
+    /// given, is left out of the comparison — used by `_check_occupancy_update_conflicts` so a
+    /// moved occupancy isn't reported as conflicting with its own, not-yet-updated self.
+    fn _check_occupancy_conflicts(
+        &self,
+        occupancy: &NewOccupancy,
+        exclude_id: Option<u32>,
+    ) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
+
+        let overlaps = |existing: &Occupancy| {
+            existing.start_datetime < occupancy.end_datetime
+                && occupancy.start_datetime < existing.end_datetime
+        };
+
+        let class_id = occupancy
+            .subject_id
+            .and_then(|subject_id| self.subject_get(subject_id))
+            .map(|subject| subject.class_id);
+
+        let students = self._affected_students(occupancy.subject_id, occupancy.group_number);
+
+        for existing in self.occupancies.values() {
+            if Some(existing.id) == exclude_id {
+                continue;
+            }
+
+            if !overlaps(existing) {
+                continue;
+            }
+
+            if occupancy.classroom_id.is_some() && existing.classroom_id == occupancy.classroom_id {
+                conflicts.push(Conflict::ClassroomBusy {
+                    occupancy_id: existing.id,
+                });
+            }
+
+            if existing.teacher_id == occupancy.teacher_id {
+                conflicts.push(Conflict::TeacherBusy {
+                    occupancy_id: existing.id,
+                });
+            }
+
+            let existing_class_id = existing
+                .subject_id
+                .and_then(|subject_id| self.subject_get(subject_id))
+                .map(|subject| subject.class_id);
+
+            if class_id.is_some() && class_id == existing_class_id {
+                conflicts.push(Conflict::ClassBusy {
+                    occupancy_id: existing.id,
+                });
+            }
+
+            if occupancy.subject_id.is_some()
+                && existing.subject_id == occupancy.subject_id
+                && existing.group_number == occupancy.group_number
+            {
+                conflicts.push(Conflict::GroupBusy {
+                    occupancy_id: existing.id,
+                });
+            }
+
+            if !students.is_empty() {
+                let existing_students =
+                    self._affected_students(existing.subject_id, existing.group_number);
+
+                if students.iter().any(|student_id| existing_students.contains(student_id)) {
+                    conflicts.push(Conflict::StudentBusy {
+                        occupancy_id: existing.id,
+                    });
+                }
+            }
+        }
+
+        if let (Some(classroom_id), Some(subject_id)) = (occupancy.classroom_id, occupancy.subject_id) {
+            if let Some(classroom) = self.classroom_get(classroom_id) {
+                let student_count = self._group_student_count(subject_id, occupancy.group_number);
+
+                if student_count > classroom.capacity as usize {
+                    conflicts.push(Conflict::CapacityExceeded {
+                        classroom_capacity: classroom.capacity,
+                        student_count,
+                    });
+                }
+            }
+        }
+
+        conflicts
+    }
+
+    /// Builds the occupancy `id` would become if `update` were applied, without actually applying
+    /// it, and runs it through `_check_occupancy_conflicts` excluding `id` itself.
+    fn _check_occupancy_update_conflicts(&self, id: u32, update: &OccupancyUpdate) -> Vec<Conflict> {
+        let occupancy = &self.occupancies[&id];
+
+        let prospective = NewOccupancy {
+            classroom_id: update.classroom_id.or(occupancy.classroom_id),
+            group_number: occupancy.group_number,
+            subject_id: occupancy.subject_id,
+            teacher_id: occupancy.teacher_id,
+            start_datetime: update.start.unwrap_or(occupancy.start_datetime),
+            end_datetime: update.end.unwrap_or(occupancy.end_datetime),
+            occupancy_type: occupancy.occupancy_type.clone(),
+            name: update
+                .name
+                .clone()
+                .unwrap_or_else(|| occupancy.name.clone()),
+            recurrence: occupancy.recurrence.clone(),
+        };
+
+        self._check_occupancy_conflicts(&prospective, Some(id))
+    }
+
+    /// Applies `update`'s fields to occupancy `id` (already confirmed to exist by the caller),
+    /// re-threading the free-busy indexes when the classroom or time window actually changes, and
+    /// returns whether anything was actually modified.
+    fn _apply_occupancy_update(&mut self, id: u32, update: OccupancyUpdate) -> bool {
+        let occupancy = &self.occupancies[&id];
+        let (old_index_start, _) = _occupancy_index_bounds(occupancy);
+        let class_id = occupancy
+            .subject_id
+            .and_then(|subject_id| self.subjects.get(&subject_id))
+            .map(|subject| subject.class_id);
+
+        let reindex = update.classroom_id.is_some() || update.start.is_some() || update.end.is_some();
+
+        if reindex {
+            if let Some(classroom_id) = occupancy.classroom_id {
+                _index_remove(&mut self.classroom_index, classroom_id, old_index_start, id);
+            }
+
+            _index_remove(&mut self.teacher_index, occupancy.teacher_id, old_index_start, id);
+
+            if let Some(class_id) = class_id {
+                _index_remove(&mut self.class_index, class_id, old_index_start, id);
+            }
+        }
+
+        let occupancy = self.occupancies.get_mut(&id).expect("checked by caller");
+        let mut updated = false;
+
+        if let Some(classroom_id) = update.classroom_id {
+            if occupancy.classroom_id != Some(classroom_id) {
+                occupancy.classroom_id = Some(classroom_id);
+                updated = true;
+            }
+        }
+
+        if let Some(start) = update.start {
+            if occupancy.start_datetime != start {
+                occupancy.start_datetime = start;
+                updated = true;
+            }
+        }
+
+        if let Some(end) = update.end {
+            if occupancy.end_datetime != end {
+                occupancy.end_datetime = end;
+                updated = true;
+            }
+        }
+
+        if let Some(name) = update.name {
+            if occupancy.name != name {
+                occupancy.name = name;
+                updated = true;
+            }
+        }
+
+        if reindex {
+            let occupancy = &self.occupancies[&id];
+            let (new_index_start, new_index_end) = _occupancy_index_bounds(occupancy);
+
+            if let Some(classroom_id) = occupancy.classroom_id {
+                _index_insert(
+                    &mut self.classroom_index,
+                    classroom_id,
+                    new_index_start,
+                    new_index_end,
+                    id,
+                );
+            }
+
+            _index_insert(
+                &mut self.teacher_index,
+                occupancy.teacher_id,
+                new_index_start,
+                new_index_end,
+                id,
+            );
+
+            if let Some(class_id) = class_id {
+                _index_insert(&mut self.class_index, class_id, new_index_start, new_index_end, id);
+            }
+        }
+
+        if updated {
+            self._sled_sync_occupancy(id);
+        }
+
+        updated
+    }
+
+    /// Number of students enrolled in `subject_id`, restricted to `group_number` when given.
+    fn _group_student_count(&self, subject_id: u32, group_number: Option<u32>) -> usize {
+        self.subjects_students
+            .values()
+            .filter(|ss| {
+                ss.subject_id == subject_id
+                    && group_number.map_or(true, |group_number| ss.group_number == group_number)
+            })
+            .count()
+    }
+
+    /// Expands `template` into concrete `Occupancy` rows per `recurrence`: steps forward week by
+    /// week (`recurrence.interval_weeks` at a time) from the Monday of `template`'s own week,
+    /// generating one candidate slot per requested weekday whose A/B parity (computed from
+    /// `schoolyear_anchor`, same as `_occurrences`) matches `recurrence.week_type`, until
+    /// `recurrence.end` is reached. A slot that conflicts (`_check_occupancy_conflicts`) with an
+    /// existing occupancy is skipped and recorded in the report instead of failing the whole
+    /// series; every inserted row shares one freshly allocated `recurrence_group_id`.
+    fn _add_recurring_occupancy(
+        &mut self,
+        template: NewOccupancy,
+        recurrence: RecurrenceSpec,
+    ) -> RecurrenceReport {
+        let mut report = RecurrenceReport {
+            occupancy_ids: Vec::new(),
+            skipped: Vec::new(),
+        };
+
+        let mut weekdays = recurrence.weekdays.clone();
+        weekdays.sort_by_key(|weekday| weekday.index());
+
+        if weekdays.is_empty() {
+            return report;
+        }
+
+        let interval_weeks = u64::from(recurrence.interval_weeks.max(1));
+        let duration = template.end_datetime - template.start_datetime;
+        let time_of_day = template.start_datetime % DAY_SECONDS;
+        let start_day = template.start_datetime / DAY_SECONDS;
+        let week_monday_day = start_day - u64::from(_weekday_from_epoch_day(start_day).index());
+
+        let group_id = self.next_recurrence_group_id;
+        self.next_recurrence_group_id += 1;
+
+        let mut week_offset: u64 = 0;
+
+        'weeks: loop {
+            let monday_day = week_monday_day + week_offset * 7;
+
+            for &weekday in &weekdays {
+                let slot_day = monday_day + u64::from(weekday.index());
+                let slot_start = slot_day * DAY_SECONDS + time_of_day;
+                let slot_end = slot_start + duration;
+
+                if let RecurrenceEnd::Until(until) = recurrence.end {
+                    if slot_start > until {
+                        break 'weeks;
+                    }
+                }
+
+                if let RecurrenceEnd::Count(count) = recurrence.end {
+                    if (report.occupancy_ids.len() + report.skipped.len()) as u32 >= count {
+                        break 'weeks;
+                    }
+                }
+
+                let week_index = slot_start.saturating_sub(self.schoolyear_anchor) / WEEK_SECONDS;
+                let matches_week_type = match recurrence.week_type {
+                    WeekType::Every => true,
+                    WeekType::A => week_index % 2 == 0,
+                    WeekType::B => week_index % 2 == 1,
+                };
+
+                if !matches_week_type {
+                    continue;
+                }
+
+                let slot = NewOccupancy {
+                    classroom_id: template.classroom_id,
+                    group_number: template.group_number,
+                    subject_id: template.subject_id,
+                    teacher_id: template.teacher_id,
+                    start_datetime: slot_start,
+                    end_datetime: slot_end,
+                    occupancy_type: template.occupancy_type.clone(),
+                    name: template.name.clone(),
+                    recurrence: None,
+                };
+
+                if !self._check_occupancy_conflicts(&slot, None).is_empty() {
+                    report.skipped.push(slot_start);
+                    continue;
+                }
+
+                let id = self._add_occupancy_with_group(slot, Some(group_id));
+                report.occupancy_ids.push(id);
+            }
+
+            week_offset += interval_weeks;
+        }
+
+        report
+    }
+
+    fn _add_modification(&mut self, affected_users: &[u32], modification: Modification) {
+        // TODO: keep to only last 25
+        for uid in affected_users {
+            let vec = self.modifications.entry(*uid).or_insert(Vec::new());
+            vec.insert(0, modification.clone());
+            vec.truncate(25);
+        }
+
+        let event = ModificationEvent {
+            id: self.next_modification_event_id,
+            affected_users: affected_users.to_vec(),
+            modification,
+        };
+        self.next_modification_event_id += 1;
+
+        self.modification_feed_log.push_back(event.clone());
+        if self.modification_feed_log.len() > MODIFICATION_FEED_CAPACITY {
+            self.modification_feed_log.pop_front();
+        }
+
+        // No receiver currently subscribed is the common case (no dashboard open), not an error.
+        let _ = self.modification_feed.send(event);
+    }
+}
+
+/// Weekday of day index `day` (days since the Unix epoch, 1970-01-01, which was a Thursday).
+fn _weekday_from_epoch_day(day: u64) -> Weekday {
+    match (day + 3) % 7 {
+        0 => Weekday::Monday,
+        1 => Weekday::Tuesday,
+        2 => Weekday::Wednesday,
+        3 => Weekday::Thursday,
+        4 => Weekday::Friday,
+        5 => Weekday::Saturday,
+        _ => Weekday::Sunday,
+    }
+}
+
+fn _now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// The `(start, end_bound)` interval a resource index should use for `occupancy`: its own
+/// `[start_datetime, end_datetime]` when it doesn't recur, or `start_datetime` paired with the end
+/// of its last possible occurrence (at `recurrence.until`) when it does, so one index entry still
+/// covers every week the occupancy could land in.
+fn _occupancy_index_bounds(occupancy: &Occupancy) -> (u64, u64) {
+    match &occupancy.recurrence {
+        None => (occupancy.start_datetime, occupancy.end_datetime),
+        Some(recurrence) => {
+            let duration = occupancy.end_datetime - occupancy.start_datetime;
+            (occupancy.start_datetime, recurrence.until + duration)
+        }
+    }
+}
+
+fn _index_insert(index: &mut IntervalIndex, key: u32, start: u64, end: u64, occupancy_id: u32) {
+    index
+        .entry(key)
+        .or_insert_with(BTreeMap::new)
+        .entry(start)
+        .or_insert_with(Vec::new)
+        .push((end, occupancy_id));
+}
+
+fn _index_remove(index: &mut IntervalIndex, key: u32, start: u64, occupancy_id: u32) {
+    let starts = match index.get_mut(&key) {
+        Some(starts) => starts,
+        None => return,
+    };
+
+    if let Some(entries) = starts.get_mut(&start) {
+        entries.retain(|(_, id)| *id != occupancy_id);
+
+        if entries.is_empty() {
+            starts.remove(&start);
+        }
+    }
+
+    if starts.is_empty() {
+        index.remove(&key);
+    }
+}
+
+/// Ids of the occupancies in `index` whose interval truly overlaps `[from, to)`. Only start
+/// buckets before `to` are visited, so this is `O(log n + k)` in the number of intervals
+/// starting before `to` rather than a full scan of every occupancy.
+fn _index_candidates(index: Option<&BTreeMap<u64, Vec<(u64, u32)>>>, from: u64, to: u64) -> Vec<u32> {
+    index
+        .into_iter()
+        .flat_map(|starts| starts.range(..to))
+        .flat_map(|(_, entries)| entries.iter())
+        .filter(|(end, _)| *end > from)
+        .map(|(_, occupancy_id)| *occupancy_id)
+        .collect()
+}
+
+/// Ascending or descending order for a `SortKey`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+/// A value a `SortKey` can extract from a row, ordered the natural way for its variant.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Text(String),
+    Number(i64),
+}
+
+/// One criterion in a multi-key `_search` sort: what to extract from a row, and which direction to
+/// order it in. Stack several to sort by e.g. teacher name then group count descending.
+struct SortKey<'a, T> {
+    key: Box<dyn Fn(&T) -> SortValue + 'a>,
+    direction: SortDirection,
+}
+
+/// Searches `collection`, keeping rows whose `property` typo-tolerantly matches every token of
+/// `query` (see `rank_match`) and that pass `custom_filter` (compose several independent filters
+/// there with `&&` to AND them together), then orders survivors and paginates.
+///
+/// With `sort_keys` empty, survivors sort best-match-first as ranked by `rank_match` — the
+/// original, single-criterion behavior every existing caller still gets. With `sort_keys` given,
+/// they take over ordering entirely (stable, first key wins ties), for callers that want e.g.
+/// "sorted by class, then by name descending" instead of relevance ranking.
+///
+/// With no `page`, the whole collection is returned (still correctly ordered).
+fn _search<'a, T>(
+    collection: impl Iterator<Item = &'a T>,
+    property: impl Fn(&T) -> String,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    query: Option<&str>,
+    custom_filter: impl Fn(&T) -> bool,
+    sort_keys: &[SortKey<T>],
+) -> (usize, Vec<&'a T>) {
+    let mut matches: Vec<(&'a T, MatchRank)> = collection
+        .filter(|row| custom_filter(row))
+        .filter_map(|row| rank_match(query, &property(row)).map(|rank| (row, rank)))
+        .collect();
+
+    if sort_keys.is_empty() {
+        matches.sort_by(|(_, a), (_, b)| a.cmp(b));
+    } else {
+        matches.sort_by(|(a, _), (b, _)| {
+            sort_keys.iter().fold(std::cmp::Ordering::Equal, |ordering, sort_key| {
+                ordering.then_with(|| {
+                    let cmp = (sort_key.key)(a).cmp(&(sort_key.key)(b));
+                    match sort_key.direction {
+                        SortDirection::Ascending => cmp,
+                        SortDirection::Descending => cmp.reverse(),
+                    }
+                })
+            })
+        });
+    }
+
+    let total = matches.len();
+
+    // If no page arg is provided, then return the whole collection.
+    let page = match page {
+        Some(page) => page,
+        None => return (total, matches.into_iter().map(|(row, _)| row).collect()),
+    };
+
+    let page_size = per_page.filter(|s| *s > 0).unwrap_or(PAGE_SIZE);
+    let to_skip = (page - 1) * page_size;
+
+    let results = matches
+        .into_iter()
+        .skip(to_skip)
+        .take(page_size)
+        .map(|(row, _)| row)
+        .collect();
+
+    (total, results)
+}
+
+/// Ranking tuple used to sort search results best-match-first: an exact match beats a
+/// prefix-of-every-token match, which beats a candidate where every token found a proper
+/// word-level match, which beats more typos/gaps, an earlier match, then a shorter candidate.
+/// `Ord` is derived field-by-field in declaration order, so keep the fields in that priority
+/// order; the `not_*` naming (rather than `exact`/`all_tokens_prefix`) is so `false` (the better
+/// outcome) sorts first.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct MatchRank {
+    not_exact: bool,
+    not_all_tokens_prefix: bool,
+    not_token_match: bool,
+    typos: usize,
+    gap_penalty: usize,
+    match_position: usize,
+    candidate_length: usize,
+}
+
+/// Checks whether `candidate` typo-tolerantly matches `query`, and if so, how well. Both strings
+/// are normalized (accents stripped, lowercased) before comparison. `query` is tokenized on
+/// whitespace, and every token must either match some whitespace/hyphen-separated token of
+/// `candidate` within a length-scaled typo budget (0 typos for tokens of at most 3 characters, 1
+/// for at most 7, 2 otherwise), or, failing that, appear as an in-order subsequence somewhere in
+/// `candidate` (e.g. "jsmth" against "John Smith") — ranked behind every proper token match, and
+/// penalized by the total gap between its matched characters. A token matching neither way drops
+/// the candidate entirely.
+fn rank_match(query: Option<&str>, candidate: &str) -> Option<MatchRank> {
+    let normalize = |s: &str| unidecode::unidecode(s.trim()).to_ascii_lowercase();
+    let candidate = normalize(candidate);
+
+    let query = match query.map(|q| normalize(truncate(q, 50))) {
+        Some(query) if !query.is_empty() => query,
+        _ => {
+            return Some(MatchRank {
+                not_exact: true,
+                not_all_tokens_prefix: true,
+                not_token_match: false,
+                typos: 0,
+                gap_penalty: 0,
+                match_position: 0,
+                candidate_length: candidate.chars().count(),
+            })
+        }
+    };
+
+    let candidate_tokens: Vec<&str> = candidate
+        .split(|c: char| c.is_whitespace() || c == '-')
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    let mut typos = 0;
+    let mut gap_penalty = 0;
+    let mut all_tokens_prefix = true;
+    let mut any_fallback_match = false;
+
+    for query_token in query.split_whitespace() {
+        let budget = typo_budget(query_token.chars().count());
+
+        let best_match = candidate_tokens
+            .iter()
+            .filter_map(|candidate_token| {
+                bounded_levenshtein(query_token, candidate_token, budget)
+                    .map(|distance| (distance, candidate_token.starts_with(query_token)))
+            })
+            .min_by_key(|(distance, _)| *distance);
+
+        match best_match {
+            Some((distance, is_prefix)) => {
+                typos += distance;
+                all_tokens_prefix = all_tokens_prefix && is_prefix;
+            }
+            None => {
+                gap_penalty += subsequence_gap(query_token, &candidate)?;
+                any_fallback_match = true;
+                all_tokens_prefix = false;
+            }
+        }
+    }
+
+    Some(MatchRank {
+        not_exact: candidate != query,
+        not_all_tokens_prefix: !all_tokens_prefix,
+        not_token_match: any_fallback_match,
+        typos,
+        gap_penalty,
+        match_position: candidate.find(&query).unwrap_or(candidate.len()),
+        candidate_length: candidate.chars().count(),
+    })
+}
+
+/// Finds `needle`'s characters in `haystack`, in order but not necessarily contiguous, returning
+/// the total gap between consecutive matches (0 for a contiguous run), or `None` if some character
+/// of `needle` never appears after the previous match.
+fn subsequence_gap(needle: &str, haystack: &str) -> Option<usize> {
+    let haystack: Vec<char> = haystack.chars().collect();
+    let mut cursor = 0;
+    let mut gap = 0;
+    let mut last_matched: Option<usize> = None;
+
+    for c in needle.chars() {
+        let found = haystack[cursor..].iter().position(|&h| h == c)? + cursor;
+
+        if let Some(last) = last_matched {
+            gap += found - last - 1;
+        }
+
+        last_matched = Some(found);
+        cursor = found + 1;
+    }
+
+    Some(gap)
+}
+
+fn typo_budget(token_chars: usize) -> usize {
+    if token_chars <= 3 {
+        0
+    } else if token_chars <= 7 {
+        1
+    } else {
+        2
+    }
+}
+
+/// Levenshtein edit distance between `a` and `b`, bailing out with `None` as soon as it's certain
+/// the distance exceeds `max`, to keep this cheap to run over a whole collection.
+fn bounded_levenshtein(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let len_diff = if a.len() > b.len() {
+        a.len() - b.len()
+    } else {
+        b.len() - a.len()
+    };
+
+    if len_diff > max {
+        return None;
+    }
+
+    let mut previous_row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, &ca) in a.iter().enumerate() {
+        let mut current_row = Vec::with_capacity(b.len() + 1);
+        current_row.push(i + 1);
+        let mut row_min = current_row[0];
+
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = if ca == cb { 0 } else { 1 };
+            let value = (previous_row[j] + cost)
+                .min(previous_row[j + 1] + 1)
+                .min(current_row[j] + 1);
+            current_row.push(value);
+            row_min = row_min.min(value);
+        }
+
+        if row_min > max {
+            return None;
+        }
+
+        previous_row = current_row;
+    }
+
+    previous_row.last().copied().filter(|distance| *distance <= max)
+}
+
+fn truncate(s: &str, max_chars: usize) -> &str {
+    match s.char_indices().nth(max_chars) {
+        None => s,
+        Some((idx, _)) => &s[..idx],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_match_with_no_query_matches_everything_without_ranking_it() {
+        let rank = rank_match(None, "Analyse Numerique").expect("no query should always match");
+        assert!(!rank.not_token_match);
+    }
+
+    #[test]
+    fn rank_match_prefers_an_exact_match_over_a_typo_tolerant_one() {
+        let exact = rank_match(Some("analyse"), "Analyse").unwrap();
+        let typo = rank_match(Some("analyze"), "Analyse").unwrap();
+
+        assert!(!exact.not_exact);
+        assert!(typo.not_exact);
+        assert!(exact < typo);
+    }
+
+    #[test]
+    fn rank_match_accepts_accent_and_case_insensitive_matches() {
+        let rank = rank_match(Some("numerique"), "Numérique").unwrap();
+        assert!(!rank.not_exact);
+    }
+
+    #[test]
+    fn rank_match_tolerates_typos_within_budget() {
+        // "numerique" is 9 characters, so it gets a 2-typo budget; "numeriqeu" is a transposition
+        // away (distance 2).
+        let rank = rank_match(Some("numeriqeu"), "numerique").unwrap();
+        assert_eq!(rank.typos, 2);
+    }
+
+    #[test]
+    fn rank_match_rejects_a_token_with_too_many_typos() {
+        assert!(rank_match(Some("xyz"), "numerique").is_none());
+    }
+
+    #[test]
+    fn rank_match_requires_every_query_token_to_match() {
+        assert!(rank_match(Some("analyse numerique avancee"), "Analyse Numerique").is_none());
+    }
+
+    #[test]
+    fn rank_match_falls_back_to_an_in_order_subsequence_match() {
+        let rank = rank_match(Some("jsmth"), "John Smith").unwrap();
+        assert!(rank.not_token_match);
+    }
+
+    #[test]
+    fn rank_match_ranks_a_token_match_above_a_subsequence_fallback() {
+        let token_match = rank_match(Some("john"), "John Smith").unwrap();
+        let fallback = rank_match(Some("jsmth"), "John Smith").unwrap();
+
+        assert!(!token_match.not_token_match);
+        assert!(fallback.not_token_match);
+        assert!(token_match < fallback);
+    }
+
+    #[test]
+    fn typo_budget_scales_with_token_length() {
+        assert_eq!(typo_budget(3), 0);
+        assert_eq!(typo_budget(7), 1);
+        assert_eq!(typo_budget(8), 2);
+    }
+
+    #[test]
+    fn bounded_levenshtein_computes_exact_distance_within_budget() {
+        assert_eq!(bounded_levenshtein("kitten", "sitting", 3), Some(3));
+    }
+
+    #[test]
+    fn bounded_levenshtein_gives_up_past_the_budget() {
+        assert_eq!(bounded_levenshtein("kitten", "sitting", 2), None);
+    }
+
+    #[test]
+    fn subsequence_gap_is_zero_for_a_contiguous_match() {
+        assert_eq!(subsequence_gap("smi", "smith"), Some(0));
+    }
+
+    #[test]
+    fn subsequence_gap_counts_the_characters_skipped_between_matches() {
+        assert_eq!(subsequence_gap("jsmith", "john smith"), Some(4));
+    }
+
+    #[test]
+    fn subsequence_gap_is_none_when_a_character_never_appears() {
+        assert_eq!(subsequence_gap("xyz", "john smith"), None);
+    }
+
+    #[test]
+    fn truncate_leaves_short_strings_untouched() {
+        assert_eq!(truncate("hello", 50), "hello");
+    }
+
+    #[test]
+    fn truncate_cuts_at_the_given_character_count() {
+        assert_eq!(truncate("hello world", 5), "hello");
+    }
+
+    /// A `JSONDatabase` over a backend path that doesn't exist yet, which `new` falls back to
+    /// building in memory rather than from a real fixture on disk (see `JSONDatabase::new`'s
+    /// `from_backend` error branch). That branch runs `reset`, which seeds the demo dataset, so its
+    /// occupancies and their free-busy indexes are cleared here and each test only sees the ones
+    /// it adds. `FlushMode::from_env` defaults to `Interval`, so nothing in these tests ever
+    /// actually touches that path.
+    fn fresh_db() -> JSONDatabase {
+        let mut db = JSONDatabase::new(StorageBackend::File(PathBuf::from(
+            "/tmp/test_server_never_exists_fixture_db",
+        )));
+        db.occupancies.clear();
+        db.classroom_index.clear();
+        db.teacher_index.clear();
+        db.class_index.clear();
+        db
+    }
+
+    fn add_teacher(db: &mut JSONDatabase) -> u32 {
+        db.user_add(NewUser {
+            first_name: "Jane".to_string(),
+            last_name: "Doe".to_string(),
+            password: "hunter2".to_string(),
+            kind: UserKind::Teacher(TeacherInformations {
+                phone_number: None,
+                email: None,
+                rank: Rank::Professor,
+            }),
+        })
+        .id
+    }
+
+    fn occupancy(teacher_id: u32, classroom_id: Option<u32>, start: u64, end: u64) -> NewOccupancy {
+        NewOccupancy {
+            classroom_id,
+            group_number: None,
+            subject_id: None,
+            teacher_id,
+            start_datetime: start,
+            end_datetime: end,
+            occupancy_type: OccupancyType::CM,
+            name: "test occupancy".to_string(),
+            recurrence: None,
+        }
+    }
+
+    #[test]
+    fn occupancies_add_checked_rejects_the_same_teacher_double_booked() {
+        let mut db = fresh_db();
+        let teacher_id = add_teacher(&mut db);
+
+        db.occupancies_add_checked(occupancy(teacher_id, None, 1_000, 2_000))
+            .expect("first occupancy should not conflict with anything");
+
+        let conflicts = db
+            .occupancies_add_checked(occupancy(teacher_id, None, 1_500, 2_500))
+            .expect_err("overlapping occupancy for the same teacher should conflict");
+
+        assert!(matches!(conflicts[..], [Conflict::TeacherBusy { .. }]));
+    }
+
+    #[test]
+    fn occupancies_add_checked_allows_non_overlapping_slots_for_the_same_teacher() {
+        let mut db = fresh_db();
+        let teacher_id = add_teacher(&mut db);
+
+        db.occupancies_add_checked(occupancy(teacher_id, None, 1_000, 2_000))
+            .expect("first occupancy should not conflict with anything");
+
+        db.occupancies_add_checked(occupancy(teacher_id, None, 2_000, 3_000))
+            .expect("back-to-back, non-overlapping occupancy should not conflict");
+    }
+
+    #[test]
+    fn occupancies_add_checked_rejects_the_same_classroom_double_booked() {
+        let mut db = fresh_db();
+        let first_teacher = add_teacher(&mut db);
+        let second_teacher = add_teacher(&mut db);
+        db.classroom_add(NewClassroom {
+            name: "B204".to_string(),
+            capacity: 30,
+        });
+
+        db.occupancies_add_checked(occupancy(first_teacher, Some(0), 1_000, 2_000))
+            .expect("first occupancy should not conflict with anything");
+
+        let conflicts = db
+            .occupancies_add_checked(occupancy(second_teacher, Some(0), 1_500, 2_500))
+            .expect_err("overlapping occupancy in the same classroom should conflict");
+
+        assert!(matches!(conflicts[..], [Conflict::ClassroomBusy { .. }]));
+    }
+
+    fn recurring_occupancy(teacher_id: u32, until: u64, week_type: WeekType) -> NewOccupancy {
+        NewOccupancy {
+            recurrence: Some(crate::models::Recurrence { week_type, until }),
+            ..occupancy(teacher_id, None, 0, 3_600)
+        }
+    }
+
+    #[test]
+    fn occupancies_list_expands_a_weekly_recurrence_every_week() {
+        let mut db = fresh_db();
+        let teacher_id = add_teacher(&mut db);
+        db.occupancies_add(recurring_occupancy(
+            teacher_id,
+            3 * WEEK_SECONDS,
+            WeekType::Every,
+        ));
+
+        let occurrences = db.occupancies_list(None, None);
+        let mut starts: Vec<u64> = occurrences.iter().map(|o| o.start_datetime).collect();
+        starts.sort();
+
+        assert_eq!(
+            starts,
+            vec![0, WEEK_SECONDS, 2 * WEEK_SECONDS, 3 * WEEK_SECONDS]
+        );
+    }
+
+    #[test]
+    fn occupancies_list_keeps_only_the_matching_week_parity() {
+        let mut db = fresh_db();
+        let teacher_id = add_teacher(&mut db);
+        db.occupancies_add(recurring_occupancy(
+            teacher_id,
+            3 * WEEK_SECONDS,
+            WeekType::A,
+        ));
+
+        let occurrences = db.occupancies_list(None, None);
+        let mut starts: Vec<u64> = occurrences.iter().map(|o| o.start_datetime).collect();
+        starts.sort();
+
+        // week_index 0 and 2 are the even ("A") weeks relative to the (default, zero)
+        // schoolyear anchor; 1 and 3 are "B" and should be dropped.
+        assert_eq!(starts, vec![0, 2 * WEEK_SECONDS]);
+    }
+
+    #[test]
+    fn occupancies_list_only_returns_occurrences_fully_inside_the_requested_window() {
+        let mut db = fresh_db();
+        let teacher_id = add_teacher(&mut db);
+        db.occupancies_add(recurring_occupancy(
+            teacher_id,
+            3 * WEEK_SECONDS,
+            WeekType::Every,
+        ));
+
+        let occurrences = db.occupancies_list(Some(WEEK_SECONDS), Some(2 * WEEK_SECONDS));
+        let starts: Vec<u64> = occurrences.iter().map(|o| o.start_datetime).collect();
+
+        assert_eq!(starts, vec![WEEK_SECONDS]);
+    }
+
+    #[test]
+    fn occupancies_list_returns_a_non_recurring_occupancy_only_once() {
+        let mut db = fresh_db();
+        let teacher_id = add_teacher(&mut db);
+        db.occupancies_add(occupancy(teacher_id, None, 1_000, 2_000));
+
+        assert_eq!(db.occupancies_list(None, None).len(), 1);
+        assert_eq!(db.occupancies_list(Some(3_000), None).len(), 0);
     }
 }
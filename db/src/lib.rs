@@ -1,55 +1,127 @@
-use serde::Deserialize;
-use std::{sync::Arc, time::Duration};
-use tokio::sync::{Mutex, MutexGuard};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, RwLock, RwLockReadGuard};
 
 mod assets;
+pub mod auth;
+pub mod ids;
 mod json;
 pub mod models;
 mod seed;
-mod utils;
+pub mod sql;
+pub mod storage;
+pub mod untis_import;
+pub mod utils;
+pub mod webuntis;
+
+use webuntis::UntisPeriod;
 
 use json::JSONDatabase;
 use models::{
-    Class, ClassLevel, Classroom, Modification, Occupancy, OccupancyType, Subject, User, UserKind,
+    Avatar, Class, ClassLevel, Classroom, EffectiveOccupancy, Modification, Occupancy,
+    OccupancyOccurrence, OccupancyType, Recurrence, Subject, User, UserKind, WeekType,
 };
+use storage::StorageBackend;
 
 pub const PAGE_SIZE: usize = 10;
 
 pub type ConcreteDb = JSONDatabase;
-pub type Db = Arc<Mutex<ConcreteDb>>;
-pub type LockedDb<'a> = MutexGuard<'a, ConcreteDb>;
-
+/// A read/write lock rather than a plain mutex: most requests (`subject_get`, `occupancies_list`,
+/// `teacher_teaches`, ...) only ever read, so they can all hold a shared `.read().await` guard at
+/// once; only the handlers that actually mutate (`create`, `occupancies_add`, ...) need the
+/// exclusive `.write().await` guard.
+pub type Db = Arc<RwLock<ConcreteDb>>;
+pub type LockedDb<'a> = RwLockReadGuard<'a, ConcreteDb>;
+
+/// Opens (or creates) the database, picking its storage backend from `DB_BACKEND`/`DB_URL` (see
+/// `storage::StorageBackend`), falling back to a plain file at `filename` if neither is set.
 pub fn new_db(filename: String) -> Db {
-    Arc::new(Mutex::new(JSONDatabase::new(filename)))
+    let backend = StorageBackend::from_env(&filename);
+    Arc::new(RwLock::new(JSONDatabase::new(backend)))
 }
 
 // While the trait is not used at runtime, it allows checking that the impls are complete
+//
+// These methods stay synchronous on purpose: `ConcreteDb` is an in-memory arena, not a connection
+// to something with its own I/O latency, so there's no real awaiting for an `async fn` here to do.
+// The concurrency bottleneck worth fixing was the single `Mutex` serializing every request behind
+// one exclusive guard regardless of whether it reads or writes — `Db` is a `tokio::sync::RwLock`
+// instead (see its doc comment), so read-only handlers (the large majority: `subject_get`,
+// `occupancies_list`, `teacher_teaches`, ...) only take `.read().await` and can run concurrently
+// with each other; only the handlers that actually mutate take `.write().await`. Splitting that
+// single `RwLock` into per-entity locks, or wiring real async I/O underneath these methods, is the
+// much bigger step `storage.rs`/`sql.rs` already call out as a later, separate migration.
 pub trait Database {
     fn from_file(filename: &str) -> Result<ConcreteDb, std::io::Error>;
     fn reset(&mut self);
+
+    /// A cheap clone of the backend this database persists to, so a caller can write
+    /// `dirty_to_bincode`'s bytes to it without holding the database lock for the I/O itself.
+    fn storage_backend(&self) -> StorageBackend;
+    /// Bulk-inserts `seed_db`'s generated users/subjects/occupancies. Classes and classrooms are
+    /// seeded separately, directly through [`Database::class_add`]/[`Database::classroom_add`],
+    /// before this runs — `seed_db` needs their real ids to build `subjects` and the default
+    /// student account in `users`.
     fn seed(
         &mut self,
         users: impl Iterator<Item = NewUser>,
-        classrooms: impl Iterator<Item = NewClassroom>,
-        classes: impl Iterator<Item = NewClass>,
         subjects: impl Iterator<Item = NewSubject>,
         occupancies: impl Iterator<Item = NewOccupancySeed>,
     );
     fn dump_as_json(&self) -> Result<String, serde_json::Error>;
 
+    /// The counterpart to `dump_as_json`: replaces the current contents with what's encoded in
+    /// `json`, leaving the database untouched if it doesn't parse.
+    fn load_from_json(&mut self, json: &str) -> Result<(), serde_json::Error>;
+
+    /// Row counts plus the most recent occupancy's end time, for the `/stats` monitoring endpoint.
+    fn stats(&self) -> Stats;
+
+    /// Ingests periods fetched from a WebUntis-style endpoint, resolving each period's
+    /// classroom/class/subject/teacher by name (creating them if they don't exist yet) and
+    /// skipping periods that were already imported (same teacher/classroom/start/end).
+    fn import_webuntis_periods(&mut self, periods: impl Iterator<Item = UntisPeriod>) -> ImportReport;
+
+    /// Ingests an offline Untis JSON-RPC dump: maps `klasse`→`Class`, `fach`→`Subject`,
+    /// `lehrer`→teacher `User`, and each `period`→`Occupancy`, deduplicating against existing
+    /// entities by name so repeated imports are idempotent.
+    fn import_untis(&mut self, reader: impl std::io::Read) -> ImportReport;
+
     fn delay_set(&mut self, delay: Duration);
     fn delay_get(&self) -> Duration;
 
+    /// Sets the start-of-schoolyear date used to compute which calendar weeks count as "week A"
+    /// vs "week B" for recurring occupancies.
+    fn schoolyear_anchor_set(&mut self, anchor: u64);
+    fn schoolyear_anchor_get(&self) -> u64;
+
     fn auth_login(&mut self, username: &str, password: &str) -> Option<(&User, String)>;
     fn auth_logout(&mut self, token: &str) -> bool;
     fn auth_get_user<'a, 'b>(&'a self, token: &str) -> Option<&'a User>;
+    /// Whether `jti` (a decoded token's [`auth::Claims::jti`]) was revoked by `auth_logout` before
+    /// it would have expired on its own. Lets a caller that already has `Claims` (e.g.
+    /// `filters::authed_claims`) check revocation without a second token decode.
+    fn auth_is_revoked(&self, jti: &str) -> bool;
 
-    fn classroom_list(&self, page: usize, query: Option<&str>) -> (usize, Vec<&Classroom>);
+    fn classroom_list(
+        &self,
+        page: usize,
+        per_page: Option<usize>,
+        query: Option<&str>,
+    ) -> (usize, Vec<&Classroom>);
     fn classroom_get(&self, id: u32) -> Option<&Classroom>;
     fn classroom_add(&mut self, classroom: NewClassroom);
     fn classroom_remove(&mut self, classrooms: &[u32]) -> bool;
     fn classroom_update(&mut self, id: u32, update: ClassroomUpdate) -> UpdateStatus;
 
+    /// Stores `avatar` as `user_id`'s profile picture, replacing whatever was there before.
+    fn avatar_set(&mut self, user_id: u32, avatar: Avatar);
+    fn avatar_get(&self, user_id: u32) -> Option<&Avatar>;
+
     fn user_add(&mut self, user: NewUser) -> &User;
     fn user_get(&self, username: &str) -> Option<&User>;
     fn user_get_by_id(&self, id: u32) -> Option<&User>;
@@ -57,6 +129,7 @@ pub trait Database {
     fn user_list(
         &self,
         page: Option<usize>,
+        per_page: Option<usize>,
         query: Option<&str>,
         filter: impl Fn(&User) -> bool,
     ) -> (usize, Vec<&User>);
@@ -94,7 +167,12 @@ pub trait Database {
         }
     }
 
-    fn class_list(&self, page: usize, query: Option<&str>) -> (usize, Vec<&Class>);
+    fn class_list(
+        &self,
+        page: usize,
+        per_page: Option<usize>,
+        query: Option<&str>,
+    ) -> (usize, Vec<&Class>);
     fn class_add(&mut self, class: NewClass);
     fn class_remove(&mut self, classes: &[u32]) -> bool;
     fn class_get(&self, id: u32) -> Option<&Class>;
@@ -103,6 +181,7 @@ pub trait Database {
     fn subject_list(
         &self,
         page: usize,
+        per_page: Option<usize>,
         query: Option<&str>,
         filter: impl Fn(&Subject) -> bool,
     ) -> (usize, Vec<&Subject>);
@@ -120,17 +199,80 @@ pub trait Database {
     fn teacher_set_teaches(&mut self, teacher_id: u32, subject_id: u32);
     fn teacher_unset_teaches(&mut self, teacher_id: u32, subject_id: u32);
     fn teacher_subjects(&self, teacher_id: u32) -> Vec<&Subject>;
+
+    /// The teachers of each subject in `subject_ids`, with whether each is in charge, in a single
+    /// pass over the teacher/subject links rather than one `user_list` scan plus one
+    /// `teacher_teaches`/`teacher_in_charge` pair per teacher per subject. Subjects with no
+    /// teachers are simply absent from the map rather than mapping to an empty `Vec`.
+    fn subjects_teachers(&self, subject_ids: &[u32]) -> HashMap<u32, Vec<(&User, bool)>>;
     fn student_subjects(&self, student_id: u32) -> Vec<&Subject>;
     fn student_subjects_with_groups(&self, student_id: u32) -> Vec<(&Subject, u32)>;
     fn student_group(&self, student_id: u32, subject_id: u32) -> u32;
 
-    fn distribute_subject_groups(&mut self, subject_id: u32);
+    /// Assigns each student enrolled in `subject_id` a `StudentSubject.group_number`. In
+    /// incremental mode (`rebalance: false`), students already holding a valid group number keep
+    /// it; only newly-enrolled students and ones orphaned by a shrunk `group_count` are placed,
+    /// each into whichever group currently has the fewest members. `rebalance: true` reassigns
+    /// everyone from scratch, sorted by name, the original behavior. Either way, returns the ids
+    /// of the students whose group number actually changed.
+    fn distribute_subject_groups(&mut self, subject_id: u32, rebalance: bool) -> Vec<u32>;
+
+    /// Lists occupancies whose instances fall fully inside `[from, to]`, expanding any recurring
+    /// occupancy into the concrete occurrences that land in that window.
+    fn occupancies_list(&self, from: Option<u64>, to: Option<u64>) -> Vec<OccupancyOccurrence>;
+
+    /// Like `occupancies_list`, but overlays the latest applicable substitution (room change,
+    /// teacher change, time shift, cancellation, or one-off addition) onto each occurrence, for a
+    /// substitution-board view instead of the raw timetable.
+    fn occupancies_list_effective(&self, from: Option<u64>, to: Option<u64>) -> Vec<EffectiveOccupancy>;
 
-    fn occupancies_list(&self, from: Option<u64>, to: Option<u64>) -> Vec<&Occupancy>;
     fn occupancies_remove(&mut self, occupancies: &[u32]) -> bool;
     fn occupancies_add(&mut self, occupancy: NewOccupancy);
+
+    /// Like `occupancies_add`, but rejects the booking instead of inserting it when it truly
+    /// overlaps (`existing.start < new.end && new.start < existing.end`, not the `*_free` checks'
+    /// containment semantics) an existing occupancy in the same classroom, for the same teacher,
+    /// in the same class, in the same subject+group, or sharing any enrolled student, or when the
+    /// group would exceed the classroom's capacity.
+    fn occupancies_add_checked(&mut self, occupancy: NewOccupancy) -> Result<u32, Vec<Conflict>>;
+
+    /// Expands `template` into a series of concrete `Occupancy` rows per `recurrence` (every N
+    /// weeks, on a set of weekdays, optionally alternating A/B, until a date or a count is
+    /// reached), sharing a `recurrence_group_id` so the whole series can later be edited or
+    /// cancelled as a unit. Slots that conflict (see `occupancies_add_checked`) are skipped
+    /// rather than failing the whole series.
+    fn occupancies_add_recurring(
+        &mut self,
+        template: NewOccupancy,
+        recurrence: RecurrenceSpec,
+    ) -> RecurrenceReport;
+
+    /// Applies `update` unconditionally, without checking it against the `*_free` checks below —
+    /// used for the admin `?force=true` override, and as the building block
+    /// `occupancies_update_checked` applies once it has confirmed there's no conflict.
     fn occupancies_update(&mut self, id: u32, update: OccupancyUpdate) -> UpdateStatus;
 
+    /// Like `occupancies_update`, but rejects the move instead of applying it when the updated
+    /// occupancy would truly overlap (see `occupancies_add_checked`) another occupancy, the
+    /// occupancy itself excluded from the comparison.
+    fn occupancies_update_checked(
+        &mut self,
+        id: u32,
+        update: OccupancyUpdate,
+    ) -> Result<UpdateStatus, Vec<Conflict>>;
+
+    /// Applies `add`, `update`, and `remove` as a single all-or-nothing unit: every item is
+    /// checked against the database as it stood before the batch (so, unlike calling
+    /// `occupancies_add_checked`/`occupancies_update_checked` item by item, items within the same
+    /// batch aren't checked against each other), and if any item is invalid, unknown, or
+    /// conflicting, nothing in the batch is applied and the report says so.
+    fn occupancies_batch(
+        &mut self,
+        add: Vec<NewOccupancy>,
+        update: Vec<(u32, OccupancyUpdate)>,
+        remove: Vec<u32>,
+    ) -> BatchReport;
+
     fn classroom_free(&self, classroom_id: u32, from: u64, to: u64) -> bool;
     fn teacher_free(&self, teacher_id: u32, from: u64, to: u64) -> bool;
     fn class_free(&self, class_id: u32, from: u64, to: u64) -> bool;
@@ -144,6 +286,26 @@ pub trait Database {
     ) -> bool;
 
     fn last_occupancies_modifications(&self, user_id: u32) -> Vec<&Modification>;
+
+    /// Every buffered `ModificationEvent` with `id` greater than `since` (or everything buffered,
+    /// if `since` is `None`), for a change-feed client catching up after a missed poll.
+    fn occupancies_modifications_since(&self, since: Option<u64>) -> Vec<ModificationEvent>;
+
+    /// Subscribes to live `ModificationEvent`s as they're emitted. Pair with
+    /// `occupancies_modifications_since` (using the last event's `id`) to avoid missing anything
+    /// between the initial snapshot and the first live event.
+    fn occupancies_modifications_subscribe(&self) -> broadcast::Receiver<ModificationEvent>;
+}
+
+/// One entry in the global occupancy modification feed backing the `/changes` and `/stream`
+/// routes. `id` is a monotonically increasing, process-local sequence number (not persisted) —
+/// it exists purely so a polling/SSE client can ask "what changed since id N" without re-reading
+/// the whole per-user `Modification` history kept by `last_occupancies_modifications`.
+#[derive(Clone, Serialize)]
+pub struct ModificationEvent {
+    pub id: u64,
+    pub affected_users: Vec<u32>,
+    pub modification: Modification,
 }
 
 pub fn username_from_name(first_name: &str, last_name: &str) -> String {
@@ -159,13 +321,13 @@ pub struct NewUser {
     pub kind: UserKind,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, utoipa::ToSchema)]
 pub struct NewClassroom {
     pub name: String,
     pub capacity: u16,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, utoipa::ToSchema)]
 pub struct ClassroomUpdate {
     pub name: Option<String>,
 }
@@ -257,6 +419,34 @@ pub struct NewOccupancy {
     pub end_datetime: u64,
     pub occupancy_type: OccupancyType,
     pub name: String,
+    pub recurrence: Option<Recurrence>,
+}
+
+/// Summary of an `import_webuntis_periods`/`import_untis` run, returned to the caller instead of
+/// failing the whole batch on the first unresolvable period. `created` counts inserted periods
+/// and, for `import_untis`, also every class, teacher, subject and classroom it had to create.
+/// `skipped` counts periods that were not inserted, whether because they were already imported
+/// or because they clash with an existing occupancy (`_check_occupancy_conflicts`, the same check
+/// `occupancies_add_checked` uses for manual creation), and, for `import_untis`, also every class
+/// and teacher that already existed; `unresolved` names the reason for clashing periods, and for periods that
+/// reference an unknown class/subject/teacher (`import_untis` only — `import_webuntis_periods`
+/// resolves or creates those instead of failing on them).
+#[derive(serde::Serialize, Default)]
+pub struct ImportReport {
+    pub created: usize,
+    pub skipped: usize,
+    pub unresolved: Vec<String>,
+}
+
+/// Row counts reported by the `/stats` monitoring endpoint.
+#[derive(serde::Serialize)]
+pub struct Stats {
+    pub occupancies: usize,
+    pub users: usize,
+    pub classes: usize,
+    pub subjects: usize,
+    pub classrooms: usize,
+    pub most_recent_occupancy_end: Option<u64>,
 }
 
 pub struct NewOccupancySeed {
@@ -278,3 +468,140 @@ pub struct OccupancyUpdate {
     pub end: Option<u64>,
     pub name: Option<String>,
 }
+
+/// An occupancy start/end time accepted from a request body as either a raw epoch integer (the
+/// original wire format) or a human-friendly expression resolved by `utils::parse_human_datetime`
+/// (`-15 minutes`, `+2h`, `tomorrow 08:00`, `next monday 14:30`, or a bare signed integer meaning
+/// minutes from now), anchored to the moment the request is deserialized.
+#[derive(Clone, Copy, Debug)]
+pub struct HumanDatetime(pub u64);
+
+impl<'de> Deserialize<'de> for HumanDatetime {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Epoch(u64),
+            Human(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Epoch(epoch) => Ok(HumanDatetime(epoch)),
+            Raw::Human(expr) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_secs();
+
+                utils::parse_human_datetime(&expr, now)
+                    .map(HumanDatetime)
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
+/// One weekday an `occupancies_add_recurring` series can land on, independent of the A/B
+/// alternation a `RecurrenceSpec`'s `week_type` describes.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// Monday = 0 .. Sunday = 6, in declaration order.
+    fn index(self) -> u32 {
+        match self {
+            Weekday::Monday => 0,
+            Weekday::Tuesday => 1,
+            Weekday::Wednesday => 2,
+            Weekday::Thursday => 3,
+            Weekday::Friday => 4,
+            Weekday::Saturday => 5,
+            Weekday::Sunday => 6,
+        }
+    }
+}
+
+/// When an `occupancies_add_recurring` series stops generating slots: either a fixed end date
+/// (inclusive, like `Recurrence::until`), or after a fixed number of slots, counting both
+/// inserted and skipped-for-conflict ones.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum RecurrenceEnd {
+    Until(u64),
+    Count(u32),
+}
+
+/// Describes the series `occupancies_add_recurring` expands `template` into: every
+/// `interval_weeks` weeks, on each of `weekdays`, restricted to the weeks whose A/B parity
+/// (computed the same way `Recurrence` does, relative to `schoolyear_anchor_get`) matches
+/// `week_type`, until `end` is reached. The time of day and duration of each generated slot come
+/// from `template`'s own `start_datetime`/`end_datetime`.
+#[derive(Deserialize)]
+pub struct RecurrenceSpec {
+    pub interval_weeks: u32,
+    pub weekdays: Vec<Weekday>,
+    pub week_type: WeekType,
+    pub end: RecurrenceEnd,
+}
+
+/// Outcome of an `occupancies_add_recurring` run: the ids of the rows it actually inserted
+/// (sharing one `recurrence_group_id`), and the slot start times it skipped because they
+/// conflicted with an existing occupancy.
+#[derive(Serialize)]
+pub struct RecurrenceReport {
+    pub occupancy_ids: Vec<u32>,
+    pub skipped: Vec<u64>,
+}
+
+/// A reason `occupancies_add_checked` rejected a new occupancy, naming the existing occupancy it
+/// overlaps with when the conflict is against another booking rather than a capacity limit.
+#[derive(Serialize, Debug)]
+pub enum Conflict {
+    ClassroomBusy { occupancy_id: u32 },
+    TeacherBusy { occupancy_id: u32 },
+    ClassBusy { occupancy_id: u32 },
+    GroupBusy { occupancy_id: u32 },
+    /// A student enrolled in the new occupancy's group is already enrolled in `occupancy_id`,
+    /// independent of subject or group — unlike `GroupBusy`, this catches a student double-booked
+    /// across two unrelated subjects, not just the same subject's group.
+    StudentBusy { occupancy_id: u32 },
+    CapacityExceeded { classroom_capacity: u16, student_count: usize },
+}
+
+/// Outcome of one item in an `occupancies_batch` request.
+#[derive(Serialize)]
+pub enum BatchItemStatus {
+    Ok,
+    NotFound,
+    /// The item references a teacher/subject/classroom that doesn't exist, or has a malformed
+    /// time range (`end` before `start`).
+    Invalid,
+    Conflict(Vec<Conflict>),
+}
+
+#[derive(Serialize)]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub status: BatchItemStatus,
+}
+
+/// Report returned by `occupancies_batch`. `committed` is `false` whenever any item's status
+/// isn't `Ok`, in which case none of `add`/`update`/`remove` were actually applied — the batch is
+/// rejected wholesale rather than partially applied.
+#[derive(Serialize)]
+pub struct BatchReport {
+    pub committed: bool,
+    pub add: Vec<BatchItemResult>,
+    pub update: Vec<BatchItemResult>,
+    pub remove: Vec<BatchItemResult>,
+}
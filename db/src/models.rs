@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, utoipa::ToSchema)]
 pub struct Classroom {
     pub id: u32,
     pub name: String,
@@ -23,6 +23,17 @@ impl User {
     }
 }
 
+/// A user's profile picture, stored as two already re-encoded, metadata-stripped variants so
+/// `GET /api/profile/avatar/{id}` never has to touch the `image` crate on the read path: `full` is
+/// the upload clamped to a max dimension, `thumbnail` is a center-cropped square of it. Both share
+/// `content_type` since `avatar::reencode` always re-saves both variants in the same format.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Avatar {
+    pub content_type: &'static str,
+    pub full: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum UserKind {
     Administrator,
@@ -115,9 +126,51 @@ pub struct Occupancy {
     pub end_datetime: u64,
     pub occupancy_type: OccupancyType,
     pub name: String,
+    pub recurrence: Option<Recurrence>,
+    /// Ties together the concrete rows `_add_recurring_occupancy` generated from one series, so
+    /// the whole series can later be edited or cancelled as a unit. `None` for a one-off
+    /// occupancy, or one materialized the old way via `recurrence` instead.
+    #[serde(default)]
+    pub recurrence_group_id: Option<u32>,
+}
+
+/// Which weeks of a school's A/B alternating timetable an occupancy's `Recurrence` applies to.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WeekType {
+    Every,
+    A,
+    B,
+}
+
+/// Makes a stored `Occupancy` repeat weekly instead of describing a single slot, so a semester of
+/// classes doesn't have to be inserted as one row per week. The weekday and time-of-day of each
+/// occurrence come from the occupancy's own `start_datetime`/`end_datetime`, which also double as
+/// its first occurrence; `until` is the last datetime a repeated occurrence may start at
+/// (inclusive).
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Recurrence {
+    pub week_type: WeekType,
+    pub until: u64,
+}
+
+/// A concrete occurrence of an `Occupancy`: the stored row plus the `[start, end]` this specific
+/// instance falls into. A recurring occupancy expands into many of these from a single stored
+/// row; a non-recurring one always expands into exactly one, identical to the row itself.
+pub struct OccupancyOccurrence<'a> {
+    pub occupancy: &'a Occupancy,
+    pub start_datetime: u64,
+    pub end_datetime: u64,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+impl<'a> std::ops::Deref for OccupancyOccurrence<'a> {
+    type Target = Occupancy;
+
+    fn deref(&self) -> &Occupancy {
+        self.occupancy
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, utoipa::ToSchema)]
 pub enum OccupancyType {
     CM,
     TD,
@@ -139,6 +192,7 @@ pub struct Modification {
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ModificationOccupancy {
+    pub occupancy_id: u32,
     pub subject_id: Option<u32>,
     pub class_id: Option<u32>,
     pub occupancy_type: OccupancyType,
@@ -146,12 +200,41 @@ pub struct ModificationOccupancy {
     pub occupancy_end: u64,
     pub previous_occupancy_start: u64,
     pub previous_occupancy_end: u64,
+    pub substitution: Option<Substitution>,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, utoipa::ToSchema)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum ModificationType {
     Create,
     Edit,
     Delete,
 }
+
+/// The kind of substitution-board change a `Modification` represents, when it's not a plain
+/// creation: swapping the room or teacher, shifting the time, rescheduling to another slot
+/// entirely, cancelling the slot, or adding a one-off lesson.
+#[derive(Deserialize, Serialize, Clone)]
+pub enum Substitution {
+    RoomChange { previous_classroom_id: Option<u32> },
+    TeacherSubstitution { previous_teacher_id: u32 },
+    TimeShift,
+    Reschedule,
+    Cancellation,
+    Added,
+}
+
+/// An occupancy occurrence with the latest applicable substitution (if any) overlaid on top, for
+/// rendering a substitution board ("room moved" / "cancelled") instead of the raw timetable.
+pub struct EffectiveOccupancy<'a> {
+    pub occurrence: OccupancyOccurrence<'a>,
+    pub substitution: Option<Substitution>,
+}
+
+impl<'a> std::ops::Deref for EffectiveOccupancy<'a> {
+    type Target = OccupancyOccurrence<'a>;
+
+    fn deref(&self) -> &OccupancyOccurrence<'a> {
+        &self.occurrence
+    }
+}
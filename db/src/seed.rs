@@ -4,18 +4,56 @@ use super::{
 };
 use crate::assets::{Event, EventType, StudentName};
 use crate::{models::OccupancyType, utils::UniqueExt, NewOccupancySeed};
-use rand::{self, Rng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Fallback for [`seed_rng`] when `SEED_DB_RNG_SEED` isn't set: arbitrary but fixed, so a default
+/// boot/demo/test run generates the same phone numbers and passwords every time.
+const DEFAULT_RNG_SEED: u64 = 0x5EED_DB5EED_DB5E;
+
+/// The RNG seed_db and its helpers draw from, so the data they generate (phone numbers, teacher
+/// passwords) is reproducible across runs instead of different on every boot. Configurable via
+/// `SEED_DB_RNG_SEED` for whoever wants a different deterministic dataset.
+fn seed_rng() -> StdRng {
+    let seed = std::env::var("SEED_DB_RNG_SEED")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_RNG_SEED);
+
+    StdRng::seed_from_u64(seed)
+}
 
 pub fn seed_db<D: Database>(db: &mut D) {
+    let mut rng = seed_rng();
+
     let events = Event::from_parsed_ical();
     let student_names = StudentName::from_parsed_json();
 
-    let users = test_users();
-    let classrooms = test_classrooms(&events);
-    let teachers = test_teachers(&events);
-    let classes = test_classes();
-    let students = test_students(&student_names);
-    let subjects = test_subjects(&events);
+    // The class is seeded first (there's currently only ever one) so everything that references
+    // it below can resolve its real id instead of assuming it lands on 0.
+    for class in test_classes() {
+        db.class_add(class);
+    }
+
+    let class_id = db
+        .class_list(1, None, None)
+        .1
+        .first()
+        .expect("test_classes should have seeded at least one class")
+        .id;
+
+    for classroom in test_classrooms(&events) {
+        db.classroom_add(classroom);
+    }
+
+    // Teachers are seeded one at a time (rather than through the bulk `db.seed` call below) so
+    // `test_subjects` can look up each one's real id once it's inserted.
+    for teacher in test_teachers(&events, &mut rng) {
+        db.user_add(teacher);
+    }
+
+    let users = test_users(&mut rng, class_id);
+    let students = test_students(&student_names, &mut rng, class_id);
+    let subjects = test_subjects(db, &events, class_id);
 
     let mut occupancies: Vec<NewOccupancySeed> = Vec::new();
 
@@ -52,18 +90,13 @@ pub fn seed_db<D: Database>(db: &mut D) {
     }
 
     db.seed(
-        users
-            .into_iter()
-            .chain(teachers.into_iter())
-            .chain(students.into_iter()),
-        classrooms.into_iter(),
-        classes.into_iter(),
+        users.into_iter().chain(students.into_iter()),
         subjects.into_iter(),
         occupancies.into_iter(),
     );
 }
 
-fn test_users() -> Vec<NewUser> {
+fn test_users(rng: &mut StdRng, class_id: u32) -> Vec<NewUser> {
     vec![
         NewUser {
             first_name: "Admin".to_string(),
@@ -76,7 +109,7 @@ fn test_users() -> Vec<NewUser> {
             last_name: "User".to_string(),
             password: "user.teacher".to_string(),
             kind: UserKind::Teacher(TeacherInformations {
-                phone_number: Some(random_phone_number(rand::thread_rng())),
+                phone_number: Some(random_phone_number(rng)),
                 email: Some("teacher@edu.univ-amu.fr".to_string()),
                 rank: Rank::Professor,
             }),
@@ -85,9 +118,7 @@ fn test_users() -> Vec<NewUser> {
             first_name: "Student".to_string(),
             last_name: "User".to_string(),
             password: "user.student".to_string(),
-            kind: UserKind::Student(StudentInformations {
-                class_id: 0, // TODO
-            }),
+            kind: UserKind::Student(StudentInformations { class_id }),
         },
     ]
 }
@@ -104,14 +135,13 @@ fn test_classrooms(events: &Vec<Event>) -> Vec<NewClassroom> {
         .collect()
 }
 
-fn test_teachers(events: &Vec<Event>) -> Vec<NewUser> {
+fn test_teachers(events: &Vec<Event>, rng: &mut StdRng) -> Vec<NewUser> {
     let teachers: Vec<&String> = events
         .iter()
         .filter_map(|e| e.professor.as_ref())
         .unique()
         .collect();
 
-    let rng = rand::thread_rng();
     let mut new_users = Vec::new();
 
     for teacher_name in teachers {
@@ -140,7 +170,7 @@ fn test_teachers(events: &Vec<Event>) -> Vec<NewUser> {
 }
 
 /// Generates a random french mobile phone number, with a prefix of 0[6-7]
-fn random_phone_number(mut rng: impl Rng) -> String {
+fn random_phone_number(rng: &mut impl Rng) -> String {
     (0..10)
         .map(|i| {
             format!(
@@ -164,15 +194,15 @@ fn test_classes() -> Vec<NewClass> {
     }]
 }
 
-fn test_students(names: &Vec<StudentName>) -> Vec<NewUser> {
+// `_rng` isn't drawn from yet, but is threaded through so any future per-student randomness joins
+// the same reproducible stream as everything else `seed_db` generates.
+fn test_students(names: &Vec<StudentName>, _rng: &mut StdRng, class_id: u32) -> Vec<NewUser> {
     let mut new_users = Vec::new();
 
     for name in names {
         let username = username_from_name(&name.first_name, &name.last_name);
 
-        let informations = StudentInformations {
-            class_id: 0, // TODO
-        };
+        let informations = StudentInformations { class_id };
 
         new_users.push(NewUser {
             first_name: name.first_name.clone(),
@@ -185,16 +215,40 @@ fn test_students(names: &Vec<StudentName>) -> Vec<NewUser> {
     new_users
 }
 
-fn test_subjects(events: &Vec<Event>) -> Vec<NewSubject> {
+/// Resolves each unique subject name to the real id of the teacher running it, by matching the
+/// professor named on one of its events against the teacher accounts `test_teachers` already
+/// seeded into `db`. Falls back to an arbitrary teacher if a subject's events never name one, so
+/// every subject still ends up with someone nominally in charge of it.
+fn test_subjects<D: Database>(db: &D, events: &Vec<Event>, class_id: u32) -> Vec<NewSubject> {
     events
         .iter()
-        .map(|e| e.subject.to_string())
+        .map(|e| e.subject.as_str())
         .unique()
-        .enumerate()
-        .map(|(index, name)| NewSubject {
-            name,
-            class_id: 0,                            // TODO
-            teacher_in_charge_id: 3 + index as u32, // TODO
+        .map(|name| {
+            let teacher_in_charge_id = events
+                .iter()
+                .find(|e| e.subject == name && e.professor.is_some())
+                .and_then(|e| e.professor.as_ref())
+                .and_then(|professor| {
+                    let mut parts = professor.splitn(2, " ");
+                    let last_name = parts.next()?;
+                    let first_name = parts.next()?;
+                    db.user_get(&username_from_name(first_name, last_name))
+                })
+                .or_else(|| {
+                    db.user_list(None, None, None, |u| matches!(u.kind, UserKind::Teacher(_)))
+                        .1
+                        .into_iter()
+                        .next()
+                })
+                .expect("at least one teacher should always have been seeded")
+                .id;
+
+            NewSubject {
+                name: name.to_string(),
+                class_id,
+                teacher_in_charge_id,
+            }
         })
         .collect()
 }
@@ -0,0 +1,196 @@
+//! First concrete step of the SQLite-via-`sqlx` persistence layer: a migration-backed schema
+//! (`migrations/`) for users/teachers/subjects/classes/occupancies, plus typed queries against the
+//! `users` and `occupancies` tables.
+//!
+//! `Db` staying `Arc<RwLock<ConcreteDb>>` (see `storage.rs`) rather than wrapping a `sqlx::Pool`
+//! directly is deliberate, for the same reason the `Sqlite` `StorageBackend` doesn't give
+//! per-entity queries yet: reworking every `Database` method to hand out owned rows instead of
+//! references into the in-memory arena is a much bigger change than adding the schema and the
+//! pool underneath it. This module is meant to grow into that full backing store one table at a
+//! time rather than in one step; `occupancy_list_range` below is that step for occupancies, with
+//! the `start`/`end` range and `subject_id`/`group_number` filters pushed down into the query
+//! instead of being applied to an already-fetched `Vec`, the same filtering `occupancies_list`
+//! does in memory today.
+
+use sqlx::sqlite::SqlitePool;
+use sqlx::FromRow;
+
+/// Opens (creating if needed) the SQLite database at `url` and applies every migration under
+/// `migrations/` that hasn't run yet.
+pub async fn connect(url: &str) -> Result<SqlitePool, sqlx::Error> {
+    let pool = SqlitePool::connect(url).await?;
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    Ok(pool)
+}
+
+#[derive(FromRow)]
+pub struct UserRow {
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub password_hash: String,
+    pub kind: String,
+}
+
+pub async fn user_add(
+    pool: &SqlitePool,
+    username: &str,
+    first_name: &str,
+    last_name: &str,
+    password_hash: &str,
+    kind: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO users (username, first_name, last_name, password_hash, kind)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )
+    .bind(username)
+    .bind(first_name)
+    .bind(last_name)
+    .bind(password_hash)
+    .bind(kind)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn user_update_password(
+    pool: &SqlitePool,
+    username: &str,
+    password_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET password_hash = ?1 WHERE username = ?2")
+        .bind(password_hash)
+        .bind(username)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn user_remove(pool: &SqlitePool, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM users WHERE username = ?1")
+        .bind(username)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn user_list(pool: &SqlitePool) -> Result<Vec<UserRow>, sqlx::Error> {
+    sqlx::query_as::<_, UserRow>(
+        "SELECT username, first_name, last_name, password_hash, kind FROM users",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct TeacherSubjectRow {
+    pub subject_id: i64,
+    pub group_number: Option<i64>,
+}
+
+/// The subjects (and, where relevant, group) a teacher teaches, per `teacher_teaches`.
+pub async fn teacher_subjects(
+    pool: &SqlitePool,
+    username: &str,
+) -> Result<Vec<TeacherSubjectRow>, sqlx::Error> {
+    sqlx::query_as::<_, TeacherSubjectRow>(
+        "SELECT subject_id, group_number FROM teacher_teaches WHERE username = ?1",
+    )
+    .bind(username)
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct OccupancyRow {
+    pub id: i64,
+    pub classroom_id: Option<i64>,
+    pub group_number: Option<i64>,
+    pub subject_id: Option<i64>,
+    pub teacher_id: i64,
+    pub start_datetime: i64,
+    pub end_datetime: i64,
+    pub occupancy_type: String,
+    pub name: String,
+    pub recurrence_week_type: Option<String>,
+    pub recurrence_until: Option<i64>,
+    pub recurrence_group_id: Option<i64>,
+}
+
+pub async fn occupancy_add(pool: &SqlitePool, row: &OccupancyRow) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO occupancies (
+             id, classroom_id, group_number, subject_id, teacher_id, start_datetime,
+             end_datetime, occupancy_type, name, recurrence_week_type, recurrence_until,
+             recurrence_group_id
+         ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+         ON CONFLICT(id) DO UPDATE SET
+             classroom_id = excluded.classroom_id,
+             group_number = excluded.group_number,
+             subject_id = excluded.subject_id,
+             teacher_id = excluded.teacher_id,
+             start_datetime = excluded.start_datetime,
+             end_datetime = excluded.end_datetime,
+             occupancy_type = excluded.occupancy_type,
+             name = excluded.name,
+             recurrence_week_type = excluded.recurrence_week_type,
+             recurrence_until = excluded.recurrence_until,
+             recurrence_group_id = excluded.recurrence_group_id",
+    )
+    .bind(row.id)
+    .bind(row.classroom_id)
+    .bind(row.group_number)
+    .bind(row.subject_id)
+    .bind(row.teacher_id)
+    .bind(row.start_datetime)
+    .bind(row.end_datetime)
+    .bind(&row.occupancy_type)
+    .bind(&row.name)
+    .bind(&row.recurrence_week_type)
+    .bind(row.recurrence_until)
+    .bind(row.recurrence_group_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn occupancy_remove(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM occupancies WHERE id = ?1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Occupancies whose `start_datetime` falls in `[from, to]`, narrowed to one subject/group when
+/// given, with both predicates pushed down into the query instead of filtering an already-fetched
+/// `Vec` the way `occupancies_list` does against the in-memory arena.
+pub async fn occupancy_list_range(
+    pool: &SqlitePool,
+    from: i64,
+    to: i64,
+    subject_id: Option<i64>,
+    group_number: Option<i64>,
+) -> Result<Vec<OccupancyRow>, sqlx::Error> {
+    sqlx::query_as::<_, OccupancyRow>(
+        "SELECT id, classroom_id, group_number, subject_id, teacher_id, start_datetime,
+                end_datetime, occupancy_type, name, recurrence_week_type, recurrence_until,
+                recurrence_group_id
+         FROM occupancies
+         WHERE start_datetime BETWEEN ?1 AND ?2
+           AND (?3 IS NULL OR subject_id = ?3)
+           AND (?4 IS NULL OR group_number = ?4)",
+    )
+    .bind(from)
+    .bind(to)
+    .bind(subject_id)
+    .bind(group_number)
+    .fetch_all(pool)
+    .await
+}
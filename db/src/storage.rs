@@ -0,0 +1,209 @@
+//! Where `ConcreteDb`'s serialized bytes actually live, abstracted behind a couple of
+//! interchangeable backends so the (de)serialization code in `json.rs` doesn't need to know
+//! whether it's talking to a plain file, a SQLite database, or a sled tree.
+//!
+//! `File` and `Sqlite` both store the exact same versioned bincode blob `dirty_to_bincode`
+//! produces, under a single path/row, so their save/migration path is unchanged from before this
+//! module existed. `Sled` is different on purpose: it keeps one tree per core collection
+//! (`users`, `classrooms`, `classes`, `subjects`, `occupancies`), each row keyed by that entity's
+//! own id (or username, for `users`, since that's what `JSONDatabase.users` is already keyed by),
+//! and `json.rs`'s per-entity mutators (`_sled_sync_*`, see there) write only the one row that
+//! changed and flush, instead of waiting on the periodic/immediate whole-arena blob the other two
+//! backends depend on. `Sled` still also round-trips the full `dirty_to_bincode` blob through a
+//! `snapshot` key for everything *outside* those five collections (`delay`, `schoolyear_anchor`,
+//! `revoked_tokens`, the id counters, ...) — `JSONDatabase::from_backend` overlays the five
+//! per-entity trees on top of that blob once it's decoded, so a save from before this existed (or
+//! a backend with empty trees) still loads correctly.
+
+use std::path::{Path, PathBuf};
+
+const SLED_SNAPSHOT_KEY: &[u8] = b"snapshot";
+
+/// Resolved once at startup from `DB_BACKEND` (`file`, `sqlite`, or `sled`, defaults to `file`) and
+/// `DB_URL` (defaults to whatever path the caller would have used for the plain-file backend).
+#[derive(Debug, Clone)]
+pub enum StorageBackend {
+    File(PathBuf),
+    Sqlite(PathBuf),
+    /// Holds the `sled::Db` handle itself (sled's handle is cheaply `Clone`, backed by an `Arc`),
+    /// opened once in `from_env` and kept for the process lifetime rather than reopened on every
+    /// read/write — recovering a sled instance from disk isn't free, and doing it on every save
+    /// would undo the whole point of picking an LSM-backed store over a whole-file rewrite.
+    Sled(sled::Db),
+}
+
+impl StorageBackend {
+    pub fn from_env(default_path: &str) -> Self {
+        let url = std::env::var("DB_URL").unwrap_or_else(|_| default_path.to_string());
+
+        match std::env::var("DB_BACKEND").as_deref() {
+            Ok("sqlite") => StorageBackend::Sqlite(PathBuf::from(url)),
+            Ok("sled") => {
+                let db = sled::open(&url)
+                    .unwrap_or_else(|e| panic!("failed to open sled database at {}: {}", url, e));
+                StorageBackend::Sled(db)
+            }
+            _ => StorageBackend::File(PathBuf::from(url)),
+        }
+    }
+
+    pub fn read(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            StorageBackend::File(path) => std::fs::read(path),
+            StorageBackend::Sqlite(path) => {
+                let connection = open(path)?;
+
+                connection
+                    .query_row("SELECT bytes FROM snapshot WHERE id = 0", [], |row| {
+                        row.get(0)
+                    })
+                    .map_err(sqlite_error)
+            }
+            StorageBackend::Sled(db) => db
+                .get(SLED_SNAPSHOT_KEY)
+                .map_err(sled_error)?
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, "no snapshot in sled db")
+                }),
+        }
+    }
+
+    pub fn write(&self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            StorageBackend::File(path) => std::fs::write(path, bytes),
+            StorageBackend::Sqlite(path) => {
+                let connection = open(path)?;
+
+                connection
+                    .execute(
+                        "INSERT INTO snapshot (id, bytes) VALUES (0, ?1)
+                         ON CONFLICT(id) DO UPDATE SET bytes = excluded.bytes",
+                        rusqlite::params![bytes],
+                    )
+                    .map_err(sqlite_error)?;
+
+                Ok(())
+            }
+            StorageBackend::Sled(db) => {
+                db.insert(SLED_SNAPSHOT_KEY, bytes).map_err(sled_error)?;
+                // sled batches writes in memory until flushed; since this already only runs on the
+                // same immediate/interval cadence as the other backends (see `FlushMode`), there's
+                // no point in sled's own background flush thread doing it again a few ms later.
+                db.flush().map_err(sled_error)?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether this backend keeps per-entity trees (only `Sled` does) that `json.rs`'s
+    /// `_sled_sync_*` helpers should write to on every mutation, in addition to `write`'s
+    /// whole-arena blob covering everything else.
+    pub fn is_per_entity(&self) -> bool {
+        matches!(self, StorageBackend::Sled(_))
+    }
+
+    /// Upserts one entity's own bincode bytes into `collection`'s tree under `key` (a `u32` id's
+    /// big-endian bytes, or a username's own bytes for `users`), and flushes — a no-op for
+    /// `File`/`Sqlite`, which have nothing incremental to do between whole-arena writes.
+    pub fn put_entity(&self, collection: &str, key: &[u8], bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            StorageBackend::Sled(db) => {
+                let tree = db.open_tree(collection).map_err(sled_error)?;
+                tree.insert(key, bytes).map_err(sled_error)?;
+                tree.flush().map_err(sled_error)?;
+                Ok(())
+            }
+            StorageBackend::File(_) | StorageBackend::Sqlite(_) => Ok(()),
+        }
+    }
+
+    /// Removes `key` from `collection`'s tree and flushes — a no-op for `File`/`Sqlite`.
+    pub fn remove_entity(&self, collection: &str, key: &[u8]) -> std::io::Result<()> {
+        match self {
+            StorageBackend::Sled(db) => {
+                let tree = db.open_tree(collection).map_err(sled_error)?;
+                tree.remove(key).map_err(sled_error)?;
+                tree.flush().map_err(sled_error)?;
+                Ok(())
+            }
+            StorageBackend::File(_) | StorageBackend::Sqlite(_) => Ok(()),
+        }
+    }
+
+    /// All `(key, bytes)` pairs currently in `collection`'s tree, for `JSONDatabase::from_backend`
+    /// to overlay onto the whole-arena blob it just decoded. Always empty for `File`/`Sqlite`,
+    /// which don't keep per-entity trees.
+    pub fn entities(&self, collection: &str) -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            StorageBackend::Sled(db) => {
+                let tree = db.open_tree(collection).map_err(sled_error)?;
+
+                tree.iter()
+                    .map(|entry| {
+                        let (key, value) = entry.map_err(sled_error)?;
+                        Ok((key.to_vec(), value.to_vec()))
+                    })
+                    .collect()
+            }
+            StorageBackend::File(_) | StorageBackend::Sqlite(_) => Ok(Vec::new()),
+        }
+    }
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::File(PathBuf::from("db.bin"))
+    }
+}
+
+/// How soon a mutation becomes durable. `Interval` is the long-standing default: `main.rs`'s
+/// `save_regurarly` loop flushes the whole arena every few seconds when it's dirty, which is
+/// plenty for a file/SQLite backend that pays a full-snapshot cost on every write, and is what
+/// tests and local development want (no I/O on every single mutation). `Immediate`, resolved from
+/// `DB_FLUSH_MODE=immediate`, makes a short, named list of mutations (the ones called out as
+/// needing crash-durability: `subject_add`, `teacher_set_teaches`, `subject_add_group`,
+/// `distribute_subject_groups`, `occupancies_add`) flush to `StorageBackend` synchronously before
+/// returning, at the cost of a full serialize+write on each of those calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    Interval,
+    Immediate,
+}
+
+impl FlushMode {
+    pub fn from_env() -> Self {
+        match std::env::var("DB_FLUSH_MODE").as_deref() {
+            Ok("immediate") => FlushMode::Immediate,
+            _ => FlushMode::Interval,
+        }
+    }
+}
+
+impl Default for FlushMode {
+    fn default() -> Self {
+        FlushMode::Interval
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<rusqlite::Connection> {
+    let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+
+    connection
+        .execute(
+            "CREATE TABLE IF NOT EXISTS snapshot (id INTEGER PRIMARY KEY, bytes BLOB NOT NULL)",
+            [],
+        )
+        .map_err(sqlite_error)?;
+
+    Ok(connection)
+}
+
+fn sqlite_error(error: rusqlite::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, error)
+}
+
+fn sled_error(error: sled::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, error)
+}
@@ -0,0 +1,51 @@
+//! Parsing for an Untis-style JSON-RPC timetable export (schoolyears/tenants/classes/subjects/
+//! teachers/periods), as an offline alternative to the live `webuntis` client for users who
+//! already have an export file handy.
+
+use serde::Deserialize;
+
+use crate::webuntis::UntisLessonType;
+
+#[derive(Deserialize)]
+pub struct UntisDump {
+    #[serde(default)]
+    pub klassen: Vec<UntisKlasse>,
+    #[serde(default)]
+    pub faecher: Vec<UntisFach>,
+    #[serde(default)]
+    pub lehrer: Vec<UntisLehrer>,
+    #[serde(default)]
+    pub periods: Vec<UntisDumpPeriod>,
+}
+
+#[derive(Deserialize)]
+pub struct UntisKlasse {
+    pub id: u32,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct UntisFach {
+    pub id: u32,
+    pub name: String,
+    pub klasse_id: u32,
+}
+
+#[derive(Deserialize)]
+pub struct UntisLehrer {
+    pub id: u32,
+    pub vorname: String,
+    pub nachname: String,
+}
+
+#[derive(Deserialize)]
+pub struct UntisDumpPeriod {
+    pub id: u32,
+    pub fach_id: u32,
+    pub lehrer_id: u32,
+    pub room_name: String,
+    pub group_number: Option<u32>,
+    pub start_datetime: u64,
+    pub end_datetime: u64,
+    pub lesson_type: UntisLessonType,
+}
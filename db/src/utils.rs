@@ -0,0 +1,138 @@
+pub use human_datetime::{parse_human_datetime, HumanDatetimeError};
+pub use unique::UniqueExt;
+
+mod unique {
+    use std::{cmp::Eq, collections::HashSet, hash::Hash};
+
+    pub struct Unique<I: Iterator> {
+        iter: I,
+        seen: HashSet<I::Item>,
+    }
+
+    impl<I: Iterator> Iterator for Unique<I>
+    where
+        I::Item: Eq + Hash + Clone,
+    {
+        type Item = I::Item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while let Some(next) = self.iter.next() {
+                if !self.seen.contains(&next) {
+                    // TODO: may be able to remove the clone with a hash?
+                    self.seen.insert(next.clone());
+                    return Some(next);
+                }
+            }
+
+            None
+        }
+    }
+
+    pub trait UniqueExt: Iterator {
+        fn unique(self) -> Unique<Self>
+        where
+            Self::Item: Eq + Hash + Clone,
+            Self: Sized,
+        {
+            Unique {
+                iter: self,
+                seen: HashSet::new(),
+            }
+        }
+    }
+
+    impl<I: Iterator> UniqueExt for I {}
+}
+
+mod human_datetime {
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use chrono_english::{parse_date_string, Dialect};
+
+    /// A human-friendly datetime expression `parse_human_datetime` couldn't resolve, either
+    /// because it isn't a recognized offset or natural-language shape, or because it resolves
+    /// before the Unix epoch.
+    #[derive(Debug)]
+    pub struct HumanDatetimeError {
+        pub input: String,
+    }
+
+    impl std::fmt::Display for HumanDatetimeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "could not resolve \"{}\" to a datetime", self.input)
+        }
+    }
+
+    impl std::error::Error for HumanDatetimeError {}
+
+    /// Resolves a human-friendly datetime expression to `u64` epoch seconds, the form occupancy
+    /// start/end times are stored as. Tries, in order:
+    ///
+    /// 1. A signed offset in minutes from `now`: a bare signed integer (`-15`, `+120`, meaning
+    ///    minutes), or one with an explicit unit (`-15 minutes`, `+2h`, `3 days`).
+    /// 2. A natural-language expression anchored to `now` (`tomorrow 08:00`, `next monday
+    ///    14:30`), via a full date parser.
+    ///
+    /// Rejects any result that would resolve before the Unix epoch.
+    pub fn parse_human_datetime(input: &str, now: u64) -> Result<u64, HumanDatetimeError> {
+        let input = input.trim();
+        let err = || HumanDatetimeError {
+            input: input.to_string(),
+        };
+
+        if let Some(offset_seconds) = parse_offset(input) {
+            return apply_offset(now, offset_seconds).ok_or_else(err);
+        }
+
+        let anchor: DateTime<Utc> =
+            DateTime::from_utc(NaiveDateTime::from_timestamp(now as i64, 0), Utc);
+
+        let parsed = parse_date_string(input, anchor, Dialect::Us).map_err(|_| err())?;
+        let timestamp = parsed.timestamp();
+
+        if timestamp < 0 {
+            Err(err())
+        } else {
+            Ok(timestamp as u64)
+        }
+    }
+
+    /// Parses a signed, optionally-unit-suffixed offset (`-15 minutes`, `+2h`, or a bare `-15`
+    /// meaning minutes) into a signed number of seconds, or `None` if `input` isn't shaped like
+    /// one at all, so the caller can fall back to the natural-language parser.
+    fn parse_offset(input: &str) -> Option<i64> {
+        let (sign, rest) = match input.strip_prefix('-') {
+            Some(rest) => (-1i64, rest),
+            None => (1i64, input.strip_prefix('+').unwrap_or(input)),
+        };
+
+        let rest = rest.trim_start();
+        let digit_count = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+
+        if digit_count == 0 {
+            return None;
+        }
+
+        let amount: i64 = rest[..digit_count].parse().ok()?;
+        let unit = rest[digit_count..].trim().to_ascii_lowercase();
+
+        let unit_seconds = match unit.as_str() {
+            "" | "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hour" | "hours" => 3600,
+            "d" | "day" | "days" => 86400,
+            _ => return None,
+        };
+
+        Some(sign * amount * unit_seconds)
+    }
+
+    /// Adds a signed offset in seconds to `now`, rejecting a result before the Unix epoch.
+    fn apply_offset(now: u64, offset_seconds: i64) -> Option<u64> {
+        let result = now as i64 + offset_seconds;
+
+        if result < 0 {
+            None
+        } else {
+            Some(result as u64)
+        }
+    }
+}
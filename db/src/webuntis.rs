@@ -0,0 +1,361 @@
+//! Client for pulling timetable data out of a WebUntis-style JSON-RPC endpoint, so a school
+//! already running WebUntis can seed this server instead of entering every period by hand.
+
+use std::collections::HashMap;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+
+/// Credentials and endpoint needed to talk to a school's WebUntis instance.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebUntisConfig {
+    pub base_url: String,
+    pub school: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum WebUntisError {
+    Request(String),
+    Rpc { code: i64, message: String },
+    NotAuthenticated,
+    MalformedPeriod { period_id: u32, reason: String },
+}
+
+impl std::fmt::Display for WebUntisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebUntisError::Request(message) => write!(f, "WebUntis request failed: {}", message),
+            WebUntisError::Rpc { code, message } => {
+                write!(f, "WebUntis RPC error {}: {}", code, message)
+            }
+            WebUntisError::NotAuthenticated => {
+                write!(f, "WebUntis client was not logged in before use")
+            }
+            WebUntisError::MalformedPeriod { period_id, reason } => write!(
+                f,
+                "WebUntis period {} could not be resolved: {}",
+                period_id, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WebUntisError {}
+
+/// One timetable period, already resolved to the plain names `import_webuntis_periods` needs
+/// (WebUntis itself returns numeric room/teacher/subject/class ids, which is why the client does
+/// the `getRooms`/`getTeachers`/`getSubjects`/`getKlassen` lookups internally before handing
+/// periods back).
+#[derive(Debug, Clone, Deserialize)]
+pub struct UntisPeriod {
+    pub external_id: u32,
+    pub start_datetime: u64,
+    pub end_datetime: u64,
+    pub classroom_name: String,
+    pub subject_name: String,
+    pub teacher_first_name: String,
+    pub teacher_last_name: String,
+    pub class_name: String,
+    pub group_number: Option<u32>,
+    pub lesson_type: UntisLessonType,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum UntisLessonType {
+    #[serde(rename = "ls")]
+    Lesson,
+    #[serde(rename = "oh")]
+    OfficeHour,
+    #[serde(rename = "ex")]
+    Exam,
+}
+
+/// One `kl`/`te`/`su`/`ro` entry of a raw `getTimetable` period: just enough to look the real name
+/// up in the matching `getTeachers`/`getSubjects`/`getRooms`/`getKlassen` table.
+#[derive(Deserialize)]
+struct RawElement {
+    id: u32,
+}
+
+/// A period exactly as `getTimetable` returns it: dates and times packed into WebUntis's own
+/// integer encoding, and every reference (room, teacher, subject, class) given as a bare id rather
+/// than a name, which is why `fetch_periods` resolves each of these against a lookup table before
+/// handing periods back as `UntisPeriod`.
+#[derive(Deserialize)]
+struct RawPeriod {
+    id: u32,
+    /// `year * 10000 + month * 100 + day`, e.g. `20260312` for 2026-03-12.
+    date: u32,
+    /// `hour * 100 + minute`, e.g. `800` for 08:00 and `1430` for 14:30.
+    #[serde(rename = "startTime")]
+    start_time: u32,
+    #[serde(rename = "endTime")]
+    end_time: u32,
+    #[serde(default)]
+    kl: Vec<RawElement>,
+    #[serde(default)]
+    te: Vec<RawElement>,
+    #[serde(default)]
+    su: Vec<RawElement>,
+    #[serde(default)]
+    ro: Vec<RawElement>,
+    #[serde(rename = "lstype", default = "default_lesson_type")]
+    lesson_type: UntisLessonType,
+}
+
+fn default_lesson_type() -> UntisLessonType {
+    UntisLessonType::Lesson
+}
+
+#[derive(Deserialize)]
+struct RawTeacher {
+    id: u32,
+    #[serde(rename = "foreName")]
+    fore_name: String,
+    #[serde(rename = "longName")]
+    long_name: String,
+}
+
+#[derive(Deserialize)]
+struct RawNamedElement {
+    id: u32,
+    #[serde(rename = "longName")]
+    long_name: String,
+}
+
+/// Decodes a WebUntis `date`/`startTime`-or-`endTime` pair into unix seconds, treating the result
+/// as UTC (WebUntis itself has no timezone concept beyond the school's own local time).
+fn decode_untis_datetime(date: u32, time: u32) -> Option<u64> {
+    let year = date / 10000;
+    let month = (date / 100) % 100;
+    let day = date % 100;
+    let hour = time / 100;
+    let minute = time % 100;
+
+    let date = NaiveDate::from_ymd_opt(year as i32, month, day)?;
+    let datetime: NaiveDateTime = date.and_hms_opt(hour, minute, 0)?;
+
+    u64::try_from(datetime.timestamp()).ok()
+}
+
+#[derive(Serialize)]
+struct RpcRequest<'a, T: Serialize> {
+    id: &'a str,
+    method: &'a str,
+    params: T,
+    jsonrpc: &'a str,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    #[serde(default)]
+    result: Option<T>,
+    #[serde(default)]
+    error: Option<RpcErrorBody>,
+}
+
+#[derive(Deserialize)]
+struct RpcErrorBody {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct AuthenticateResult {
+    #[serde(rename = "sessionId")]
+    session_id: String,
+}
+
+pub struct WebUntisClient {
+    config: WebUntisConfig,
+    http: reqwest::Client,
+    session_id: Option<String>,
+}
+
+impl WebUntisClient {
+    pub fn new(config: WebUntisConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            session_id: None,
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        format!(
+            "{}/WebUntis/jsonrpc.do?school={}",
+            self.config.base_url, self.config.school
+        )
+    }
+
+    async fn call<P: Serialize, R: for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<R, WebUntisError> {
+        let body = RpcRequest {
+            id: "test_server",
+            method,
+            params,
+            jsonrpc: "2.0",
+        };
+
+        let response: RpcResponse<R> = self
+            .http
+            .post(self.endpoint())
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| WebUntisError::Request(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| WebUntisError::Request(e.to_string()))?;
+
+        match response.result {
+            Some(result) => Ok(result),
+            None => match response.error {
+                Some(error) => Err(WebUntisError::Rpc {
+                    code: error.code,
+                    message: error.message,
+                }),
+                None => Err(WebUntisError::Request(
+                    "empty response from WebUntis".to_string(),
+                )),
+            },
+        }
+    }
+
+    /// Authenticates against the school's WebUntis instance and stores the returned session id
+    /// for subsequent calls.
+    pub async fn login(&mut self) -> Result<(), WebUntisError> {
+        #[derive(Serialize)]
+        struct Params<'a> {
+            user: &'a str,
+            password: &'a str,
+            client: &'a str,
+        }
+
+        let result: AuthenticateResult = self
+            .call(
+                "authenticate",
+                Params {
+                    user: &self.config.username,
+                    password: &self.config.password,
+                    client: "test_server",
+                },
+            )
+            .await?;
+
+        self.session_id = Some(result.session_id);
+        Ok(())
+    }
+
+    /// Fetches every period scheduled in `[start, end]` (as unix timestamps), resolving each
+    /// period's bare `kl`/`te`/`su`/`ro` ids against `getKlassen`/`getTeachers`/`getSubjects`/
+    /// `getRooms` and decoding its WebUntis `date`/`startTime`/`endTime` encoding into unix
+    /// seconds, so the caller only ever deals in plain names and epoch timestamps.
+    pub async fn fetch_periods(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<UntisPeriod>, WebUntisError> {
+        #[derive(Serialize)]
+        struct Params {
+            start: u64,
+            end: u64,
+        }
+
+        if self.session_id.is_none() {
+            return Err(WebUntisError::NotAuthenticated);
+        }
+
+        let raw_periods: Vec<RawPeriod> =
+            self.call("getTimetable", Params { start, end }).await?;
+
+        let classes: HashMap<u32, String> = self
+            .call::<_, Vec<RawNamedElement>>("getKlassen", ())
+            .await?
+            .into_iter()
+            .map(|class| (class.id, class.long_name))
+            .collect();
+
+        let teachers: HashMap<u32, (String, String)> = self
+            .call::<_, Vec<RawTeacher>>("getTeachers", ())
+            .await?
+            .into_iter()
+            .map(|teacher| (teacher.id, (teacher.fore_name, teacher.long_name)))
+            .collect();
+
+        let subjects: HashMap<u32, String> = self
+            .call::<_, Vec<RawNamedElement>>("getSubjects", ())
+            .await?
+            .into_iter()
+            .map(|subject| (subject.id, subject.long_name))
+            .collect();
+
+        let rooms: HashMap<u32, String> = self
+            .call::<_, Vec<RawNamedElement>>("getRooms", ())
+            .await?
+            .into_iter()
+            .map(|room| (room.id, room.long_name))
+            .collect();
+
+        let malformed = |period: &RawPeriod, reason: &str| WebUntisError::MalformedPeriod {
+            period_id: period.id,
+            reason: reason.to_string(),
+        };
+
+        raw_periods
+            .into_iter()
+            .map(|period| {
+                let start_datetime = decode_untis_datetime(period.date, period.start_time)
+                    .ok_or_else(|| malformed(&period, "invalid start date/time"))?;
+                let end_datetime = decode_untis_datetime(period.date, period.end_time)
+                    .ok_or_else(|| malformed(&period, "invalid end date/time"))?;
+
+                let class_name = period
+                    .kl
+                    .first()
+                    .and_then(|element| classes.get(&element.id))
+                    .ok_or_else(|| malformed(&period, "unresolved class"))?
+                    .clone();
+
+                let (teacher_first_name, teacher_last_name) = period
+                    .te
+                    .first()
+                    .and_then(|element| teachers.get(&element.id))
+                    .ok_or_else(|| malformed(&period, "unresolved teacher"))?
+                    .clone();
+
+                let subject_name = period
+                    .su
+                    .first()
+                    .and_then(|element| subjects.get(&element.id))
+                    .ok_or_else(|| malformed(&period, "unresolved subject"))?
+                    .clone();
+
+                let classroom_name = period
+                    .ro
+                    .first()
+                    .and_then(|element| rooms.get(&element.id))
+                    .ok_or_else(|| malformed(&period, "unresolved room"))?
+                    .clone();
+
+                Ok(UntisPeriod {
+                    external_id: period.id,
+                    start_datetime,
+                    end_datetime,
+                    classroom_name,
+                    subject_name,
+                    teacher_first_name,
+                    teacher_last_name,
+                    class_name,
+                    group_number: None,
+                    lesson_type: period.lesson_type,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,141 @@
+use crate::with_db;
+use db::Database;
+use db::{
+    auth::{Claims, Role},
+    Db,
+};
+
+use warp::{Filter, Rejection};
+
+/// Filter that checks if the user is authenticated or not, and rejects the request if he/she isn't
+pub fn authed(db: &Db) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    with_db(db.clone())
+        .and(warp::header::optional::<String>("Authorization"))
+        .and_then(guard)
+}
+
+/// Like [`authed`], but yields the bearer token's signed [`Claims`] (user id + role) instead of a
+/// bare username, so handlers that only need to authorize by id/role (e.g. "an admin, or this
+/// resource's own teacher") don't have to look the user back up. Still checks the token's `jti`
+/// against the revocation set so a logged-out token (see `auth_logout`) is rejected even if its
+/// signature and expiry are still valid.
+pub fn authed_claims(db: &Db) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    with_db(db.clone())
+        .and(warp::header::optional::<String>("Authorization"))
+        .and_then(guard_claims)
+}
+
+#[derive(Eq, PartialEq)]
+pub enum PossibleUserKind {
+    Administrator,
+    Teacher,
+    Student,
+}
+
+impl From<Role> for PossibleUserKind {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Administrator => PossibleUserKind::Administrator,
+            Role::Teacher => PossibleUserKind::Teacher,
+            Role::Student => PossibleUserKind::Student,
+        }
+    }
+}
+
+/// Filters that checks if the user is of the requested kind, and rejects the request if he/she doesn't
+/// have the authorization ; also checks if the user is authenticated. The role check itself is
+/// decided from the token's signed `role` claim rather than a fresh database lookup; the database
+/// is still read for the revocation check done by [`authed_claims`], and to look up the claimed
+/// user's username, rejecting a token whose user no longer exists.
+pub fn authed_is_of_kind<'a>(
+    db: &Db,
+    role: &'a [PossibleUserKind],
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone + 'a {
+    with_db(db.clone())
+        .and(authed_claims(db))
+        .map(move |db, claims| (db, claims, role))
+        .untuple_one()
+        .and_then(guard_kind)
+}
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// No `Authorization` header was sent at all, as opposed to [`Forbidden`] (a header was sent, but
+/// its scheme, token, or signature didn't check out). Kept distinct so `handle_rejection` can
+/// report "you're not logged in" separately from "that token isn't valid", even though both
+/// currently end up as `401`.
+#[derive(Debug)]
+pub struct NoAuthToken;
+
+impl warp::reject::Reject for NoAuthToken {}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Pulls the bearer token out of an `Authorization: Bearer <token>` header, shared by [`guard`]
+/// and [`guard_claims`].
+fn bearer_token(authorization: Option<String>) -> Result<String, warp::Rejection> {
+    let authorization = authorization.ok_or_else(|| warp::reject::custom(NoAuthToken {}))?;
+
+    let (auth_type, token) = {
+        let mut parts = authorization.splitn(2, " ");
+        (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+    };
+
+    if auth_type.to_ascii_lowercase() == "bearer" {
+        Ok(token.to_string())
+    } else {
+        Err(warp::reject::custom(Forbidden {}))
+    }
+}
+
+async fn guard(db: Db, authorization: Option<String>) -> Result<String, warp::Rejection> {
+    let token = bearer_token(authorization)?;
+    let db = db.read().await;
+
+    match db.auth_get_user(&token) {
+        Some(user) => Ok(user.username.clone()), // TODO: remove extra allocation + remove extra DB lock
+        None => Err(warp::reject::custom(Forbidden {})),
+    }
+}
+
+async fn guard_claims(db: Db, authorization: Option<String>) -> Result<Claims, warp::Rejection> {
+    let token = bearer_token(authorization)?;
+
+    let claims =
+        db::auth::verify_token(&token).ok_or_else(|| warp::reject::custom(Forbidden {}))?;
+
+    // The signature and expiry check above aren't enough on their own: a token revoked by
+    // `auth_logout` must stop working immediately, not just once it expires.
+    let db = db.read().await;
+    if db.auth_is_revoked(claims.jti()) {
+        return Err(warp::reject::custom(Forbidden {}));
+    }
+
+    Ok(claims)
+}
+
+async fn guard_kind(
+    db: Db,
+    claims: Claims,
+    wanted_kind: &[PossibleUserKind],
+) -> Result<String, warp::Rejection> {
+    if !wanted_kind.contains(&PossibleUserKind::from(claims.role)) {
+        return Err(warp::reject::custom(Unauthorized {}));
+    }
+
+    let db = db.read().await;
+
+    // The claimed user may have been deleted after the token was issued; `user_remove` doesn't
+    // revoke still-unexpired tokens, so this is reachable with an otherwise-valid signature.
+    let user = db
+        .user_get_by_id(claims.sub)
+        .ok_or_else(|| warp::reject::custom(Forbidden {}))?;
+
+    Ok(user.username.clone())
+}
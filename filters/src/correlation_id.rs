@@ -0,0 +1,19 @@
+use rand::{distributions::Alphanumeric, Rng};
+use std::convert::Infallible;
+use warp::Filter;
+
+/// Generates a short random id for one incoming request, so every `tracing` event emitted while
+/// handling it — including ones several calls deep, like `validate_new_occupancy_base`'s
+/// rejection warnings — can be correlated back to the same request by filtering on
+/// `correlation_id`, the same way `subject_id`/`teacher_id` let an operator filter on one subject
+/// or one rejected occupancy.
+pub fn correlation_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::any().map(|| {
+        let mut rng = rand::thread_rng();
+
+        std::iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .take(12)
+            .collect()
+    })
+}
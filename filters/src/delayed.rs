@@ -13,7 +13,7 @@ pub fn delayed(db: &Db) -> impl Filter<Extract = (), Error = Rejection> + Clone
 async fn delay(db: Db) -> Result<(), warp::Rejection> {
     // Release db as soon as possible
     let delay = {
-        let db = db.lock().await;
+        let db = db.read().await;
         db.delay_get()
     };
     tokio::time::delay_for(delay).await;
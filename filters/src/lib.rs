@@ -1,10 +1,21 @@
 mod authed;
+mod correlation_id;
 mod delayed;
+mod metrics;
+mod timed_lock;
 mod with_db;
+mod with_ids;
 
-pub use authed::{authed, authed_is_of_kind, Forbidden, PossibleUserKind, Unauthorized};
+pub use authed::{
+    authed, authed_claims, authed_is_of_kind, Forbidden, NoAuthToken, PossibleUserKind,
+    Unauthorized,
+};
+pub use correlation_id::correlation_id;
 pub use delayed::delayed;
+pub use metrics::{instrument, new_metrics, with_metrics, Metrics};
+pub use timed_lock::{timed_read, timed_write};
 pub use with_db::with_db;
+pub use with_ids::with_ids;
 
 #[derive(Debug)]
 pub struct Malformed;
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use warp::filters::log::Info;
+
+use std::convert::Infallible;
+use warp::Filter;
+
+/// Shared Prometheus state: the `Registry` every collector below is registered into, plus the
+/// per-request counters/histogram `instrument` feeds on every completed request. `db_rows` and
+/// `service_hours` are set by the `/metrics` handler itself right before a scrape, since computing
+/// them needs the locked `Db` and isn't something worth doing on every request.
+pub struct MetricsInner {
+    pub registry: Registry,
+    http_requests_total: IntCounterVec,
+    http_request_duration_seconds: HistogramVec,
+    /// Current row counts, labeled by `entity` (`users`, `classes`, `subjects`, `classrooms`,
+    /// `occupancies`).
+    pub db_rows: IntGaugeVec,
+    /// Aggregated teaching-service hours, labeled by `occupancy_type`.
+    pub service_hours: GaugeVec,
+    /// How many occupancies an `occupancies_list`-backed handler returned, labeled by `endpoint`
+    /// (e.g. `occupancies_get`, `occupancies_group_get`), so operators can spot clients hammering
+    /// wide date ranges without needing per-subject/per-group labels (which would blow up
+    /// cardinality — that detail goes to the structured `tracing` span on each handler instead).
+    occupancies_returned: HistogramVec,
+    /// Total `ErrorCode::InvalidID` rejections across the occupancy query handlers.
+    invalid_id_total: IntCounter,
+}
+
+pub type Metrics = Arc<MetricsInner>;
+
+pub fn new_metrics() -> Metrics {
+    let registry = Registry::new();
+
+    let http_requests_total = IntCounterVec::new(
+        Opts::new("http_requests_total", "Total number of HTTP requests handled"),
+        &["method", "path", "status"],
+    )
+    .expect("metric name/labels should be valid");
+
+    let http_request_duration_seconds = HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "HTTP request latency in seconds",
+        ),
+        &["method", "path"],
+    )
+    .expect("metric name/labels should be valid");
+
+    let db_rows = IntGaugeVec::new(
+        Opts::new("db_rows", "Current row count per entity"),
+        &["entity"],
+    )
+    .expect("metric name/labels should be valid");
+
+    let service_hours = GaugeVec::new(
+        Opts::new("service_hours", "Aggregated teaching-service hours per occupancy type"),
+        &["occupancy_type"],
+    )
+    .expect("metric name/labels should be valid");
+
+    let occupancies_returned = HistogramVec::new(
+        HistogramOpts::new(
+            "occupancies_returned",
+            "Number of occupancies returned by an occupancy query handler",
+        ),
+        &["endpoint"],
+    )
+    .expect("metric name/labels should be valid");
+
+    let invalid_id_total = IntCounter::new(
+        "invalid_id_total",
+        "Total InvalidID rejections from the occupancy query handlers",
+    )
+    .expect("metric name/labels should be valid");
+
+    registry
+        .register(Box::new(http_requests_total.clone()))
+        .expect("collector should only be registered once");
+    registry
+        .register(Box::new(http_request_duration_seconds.clone()))
+        .expect("collector should only be registered once");
+    registry
+        .register(Box::new(db_rows.clone()))
+        .expect("collector should only be registered once");
+    registry
+        .register(Box::new(service_hours.clone()))
+        .expect("collector should only be registered once");
+    registry
+        .register(Box::new(occupancies_returned.clone()))
+        .expect("collector should only be registered once");
+    registry
+        .register(Box::new(invalid_id_total.clone()))
+        .expect("collector should only be registered once");
+
+    Arc::new(MetricsInner {
+        registry,
+        http_requests_total,
+        http_request_duration_seconds,
+        db_rows,
+        service_hours,
+        occupancies_returned,
+        invalid_id_total,
+    })
+}
+
+impl MetricsInner {
+    fn record(&self, info: &Info) {
+        let method = info.method().as_str();
+        let path = info.path();
+        let status = info.status().as_u16().to_string();
+
+        self.http_requests_total
+            .with_label_values(&[method, path, &status])
+            .inc();
+
+        self.http_request_duration_seconds
+            .with_label_values(&[method, path])
+            .observe(info.elapsed().as_secs_f64());
+    }
+
+    /// Records how many occupancies `endpoint` just returned, feeding the `occupancies_returned`
+    /// histogram's average/quantiles.
+    pub fn record_occupancies_returned(&self, endpoint: &str, count: usize) {
+        self.occupancies_returned
+            .with_label_values(&[endpoint])
+            .observe(count as f64);
+    }
+
+    /// Counts one more `ErrorCode::InvalidID` rejection from an occupancy query handler.
+    pub fn record_invalid_id(&self) {
+        self.invalid_id_total.inc();
+    }
+
+    /// Renders every registered collector as Prometheus text-format exposition.
+    pub fn encode(&self) -> String {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+
+        encoder
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("encoding to the Prometheus text format should not fail");
+
+        String::from_utf8(buffer).expect("the Prometheus text format is valid UTF-8")
+    }
+}
+
+/// A `warp::log`-style filter (see `warp::log::custom`) that feeds every completed request's
+/// method/path/status/latency into `metrics` instead of a log line, so instrumentation is applied
+/// once at the top of the filter chain rather than threaded through every handler.
+pub fn instrument(metrics: Metrics) -> warp::filters::log::Log<impl Fn(Info) + Clone> {
+    warp::log::custom(move |info| metrics.record(&info))
+}
+
+/// Like `with_db`, but hands handlers the shared `Metrics` instead.
+pub fn with_metrics(metrics: Metrics) -> impl Filter<Extract = (Metrics,), Error = Infallible> + Clone {
+    warp::any().map(move || metrics.clone())
+}
@@ -0,0 +1,32 @@
+use std::time::Instant;
+
+use db::{ConcreteDb, Db, LockedDb};
+use tokio::sync::RwLockWriteGuard;
+
+/// Like `db.read().await`, but emits a `tracing` event with how long the caller waited for the
+/// lock, so contention on the shared `Arc<RwLock<ConcreteDb>>` shows up in traces instead of only
+/// as otherwise-unexplained handler latency.
+pub async fn timed_read(db: &Db) -> LockedDb {
+    let start = Instant::now();
+    let guard = db.read().await;
+
+    tracing::trace!(
+        wait_ms = start.elapsed().as_millis() as u64,
+        "acquired db read lock"
+    );
+
+    guard
+}
+
+/// Write-lock counterpart to [`timed_read`].
+pub async fn timed_write(db: &Db) -> RwLockWriteGuard<'_, ConcreteDb> {
+    let start = Instant::now();
+    let guard = db.write().await;
+
+    tracing::trace!(
+        wait_ms = start.elapsed().as_millis() as u64,
+        "acquired db write lock"
+    );
+
+    guard
+}
@@ -0,0 +1,8 @@
+use db::ids::Ids;
+use std::convert::Infallible;
+use warp::Filter;
+
+/// Simple filter to add the shared id codec to the request, same as `with_db`.
+pub fn with_ids(ids: Ids) -> impl Filter<Extract = (Ids,), Error = Infallible> + Clone {
+    warp::any().map(move || ids.clone())
+}
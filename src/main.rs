@@ -2,16 +2,16 @@
 
 // TODO: validate incoming data
 
-use fern::colors::{Color, ColoredLevelConfig};
-use log::info;
-use tokio::io::AsyncWriteExt;
-use warp::{http::StatusCode, Filter, Rejection, Reply};
+use log::{error, info};
+use warp::{Filter, Rejection, Reply};
 
 mod routes;
 
-use db::{new_db, Db};
-use filters::{Forbidden, Malformed, Unauthorized};
-use routes::{routes, ErrorCode, FailureResponse};
+use db::webuntis::{WebUntisClient, WebUntisConfig};
+use db::{new_db, Database, Db};
+use filters::{Forbidden, Malformed, NoAuthToken, Unauthorized};
+use routes::{routes, ErrorCode, FailureResponse, InternalError};
+use serde::Deserialize;
 use std::time::{Duration, Instant};
 
 // TODO: persist if dirty periodically instead of for every request
@@ -23,10 +23,21 @@ const DB_FNAME: &'static str = "db.bin";
 async fn main() {
     setup_logging();
 
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(config_path) = args
+        .iter()
+        .position(|arg| arg == "--import-webuntis")
+        .and_then(|index| args.get(index + 1))
+    {
+        return run_webuntis_import(config_path).await;
+    }
+
     let global_db = new_db(DB_FNAME.to_string());
-    let filters = routes(&global_db);
+    let metrics = filters::new_metrics();
+    let ids = db::ids::new_id_codec();
+    let filters = routes(&global_db, &metrics, &ids);
 
-    tokio::spawn(save_regurarly(global_db));
+    tokio::spawn(save_regurarly(global_db.clone()));
 
     let cors = warp::cors()
         .allow_any_origin()
@@ -37,10 +48,24 @@ async fn main() {
         // Before logging for correct status codes, before CORS for proper headers
         .recover(handle_rejection)
         .with(warp::log("dummy"))
+        .with(filters::instrument(metrics))
+        .with(warp::trace::request())
         .with(cors);
 
     info!("Open http://127.0.0.1:3030 for more information");
-    warp::serve(filters).run(([0, 0, 0, 0], 3030)).await;
+
+    let (_, server) =
+        warp::serve(filters).bind_with_graceful_shutdown(([0, 0, 0, 0], 3030), async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to listen for ctrl-c");
+        });
+    server.await;
+
+    // `save_regurarly`'s own flush already keeps most backends current, but this covers whatever
+    // mutated since its last tick, so a clean shutdown never drops the last few seconds of writes.
+    save(&global_db).await;
+    info!("Database flushed, exiting");
 }
 
 async fn save_regurarly(db: Db) {
@@ -56,13 +81,14 @@ async fn save(db: &Db) {
     let start = Instant::now();
 
     // Release DB as fast as possible
-    let serialized = {
-        let mut db = db.lock().await;
+    let (serialized, backend) = {
+        let mut db = db.write().await;
+        let backend = db.storage_backend();
 
         if db.is_dirty() {
-            Some(db.dirty_to_bincode())
+            (Some(db.dirty_to_bincode()), backend)
         } else {
-            None
+            (None, backend)
         }
     };
 
@@ -72,57 +98,90 @@ async fn save(db: &Db) {
         None => return,
     };
 
-    let mut output = tokio::fs::File::create(DB_FNAME)
-        .await
-        .expect("could not create DB");
+    match backend.write(&serialized) {
+        Ok(()) => info!("DB persisted [{:?}]", start.elapsed()),
+        Err(e) => error!("could not persist DB: {}", e),
+    }
+}
+
+#[derive(Deserialize)]
+struct WebUntisImportConfig {
+    #[serde(flatten)]
+    client: WebUntisConfig,
+    start: u64,
+    end: u64,
+}
+
+/// One-shot CLI mode: `test_server --import-webuntis <config.json>` seeds the local DB from a
+/// WebUntis instance without starting the web server, for schools bootstrapping this server from
+/// their existing timetable instead of entering every period by hand.
+async fn run_webuntis_import(config_path: &str) {
+    let config = std::fs::read_to_string(config_path).expect("could not read import config file");
+    let config: WebUntisImportConfig =
+        serde_json::from_str(&config).expect("invalid import config file");
+
+    let mut client = WebUntisClient::new(config.client);
+    client.login().await.expect("could not log in to WebUntis");
 
-    output
-        .write_all(&serialized[..])
+    let periods = client
+        .fetch_periods(config.start, config.end)
         .await
-        .expect("could not persist DB");
+        .expect("could not fetch periods from WebUntis");
 
-    info!("DB persisted [{:?}]", start.elapsed());
+    let db = new_db(DB_FNAME.to_string());
+    let report = {
+        let mut db = db.write().await;
+        db.import_webuntis_periods(periods.into_iter())
+    };
+    save(&db).await;
+
+    info!(
+        "WebUntis import done: {} created, {} skipped, {} unresolved",
+        report.created,
+        report.skipped,
+        report.unresolved.len()
+    );
 }
 
+/// Sets up structured logging: `tracing`/`tracing-subscriber` drive the actual output (respecting
+/// `RUST_LOG`, e.g. `RUST_LOG=test_server=debug,warp=info`), while `tracing_log::LogTracer`
+/// bridges the existing `log` call sites (e.g. the `info!`/`error!` ones in `db/src/json.rs`)
+/// into the same subscriber so nothing already relying on `log` needs touching.
+/// `LOG_FORMAT` picks the output encoding: `json` for log aggregators, anything else (including
+/// unset) for the default human-readable format used in local development.
 fn setup_logging() {
-    let colors = ColoredLevelConfig::new().debug(Color::Magenta);
-
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "{}{} {}",
-                colors.color(record.level()),
-                chrono::Local::now().format("[%H:%M:%S]"),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .apply()
-        .expect("Could not apply logging configuration");
+    tracing_log::LogTracer::init().expect("could not install the log-to-tracing bridge");
+
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    if std::env::var("LOG_FORMAT").as_deref() == Ok("json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
 }
 
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, warp::Rejection> {
-    let error_code;
-    let status_code;
-
-    if err.is_not_found() {
-        error_code = ErrorCode::NotFound;
-        status_code = StatusCode::NOT_FOUND;
+    let error_code = if err.is_not_found() {
+        ErrorCode::NotFound
+    } else if let Some(NoAuthToken) = err.find() {
+        ErrorCode::NoAuthToken
     } else if let Some(Forbidden) = err.find() {
-        error_code = ErrorCode::InvalidCredentials;
-        status_code = StatusCode::FORBIDDEN;
+        ErrorCode::InvalidCredentials
     } else if let Some(Unauthorized) = err.find() {
-        error_code = ErrorCode::InsufficientAuthorization;
-        status_code = StatusCode::UNAUTHORIZED;
+        ErrorCode::InsufficientAuthorization
     } else if let Some(Malformed) = err.find() {
-        error_code = ErrorCode::MalformedData;
-        status_code = StatusCode::BAD_REQUEST;
+        ErrorCode::MalformedData
+    } else if let Some(InternalError(message)) = err.find() {
+        error!("internal error: {}", message);
+        ErrorCode::Unknown
     } else {
         // Unknown error : pass it along, will be handled by warp.
         return Err(err);
-    }
+    };
 
-    let json = warp::reply::json(&FailureResponse::new(error_code));
-    Ok(warp::reply::with_status(json, status_code))
+    Ok(FailureResponse::reply(error_code))
 }
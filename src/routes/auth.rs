@@ -5,21 +5,21 @@ use super::globals::SimpleSuccessResponse;
 use db::{models::UserKind, Database, Db};
 use filters::{delayed, with_db, Forbidden};
 
-#[derive(Deserialize)]
-struct LoginRequest {
+#[derive(Deserialize, utoipa::ToSchema)]
+pub struct LoginRequest {
     username: String,
     password: String,
 }
 
-#[derive(Serialize)]
-struct LoginResponse<'a> {
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct LoginResponse<'a> {
     status: &'a str,
     token: &'a str,
     user: LoginResponseUser<'a>,
 }
 
-#[derive(Serialize)]
-struct LoginResponseUser<'a> {
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct LoginResponseUser<'a> {
     id: u32,
     first_name: &'a str,
     last_name: &'a str,
@@ -46,33 +46,66 @@ pub fn routes(db: &Db) -> impl Filter<Extract = impl Reply, Error = Rejection> +
     post_session_route.or(delete_session_route)
 }
 
-async fn post_session(request: LoginRequest, db: Db) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+/// Logs in with a username and password, returning a bearer token to send as
+/// `Authorization: Bearer <token>` on subsequent requests.
+#[utoipa::path(
+    post,
+    path = "/api/session",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Logged in successfully", body = LoginResponse),
+        (status = 401, description = "Invalid username or password"),
+    ),
+)]
+#[tracing::instrument(skip(db, request), fields(username = %request.username, outcome = tracing::field::Empty))]
+pub(crate) async fn post_session(
+    request: LoginRequest,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = filters::timed_write(&db).await;
 
     match db.auth_login(&request.username, &request.password) {
-        Some((user, token)) => Ok(warp::reply::json(&LoginResponse {
-            status: "success",
-            token: &token,
-            user: LoginResponseUser {
-                id: user.id,
-                first_name: &user.first_name,
-                last_name: &user.last_name,
-                kind: match user.kind {
-                    UserKind::Administrator => "ADM",
-                    UserKind::Teacher(_) => "TEA",
-                    UserKind::Student(_) => "STU",
+        Some((user, token)) => {
+            tracing::Span::current().record("outcome", &"success");
+
+            Ok(warp::reply::json(&LoginResponse {
+                status: "success",
+                token: &token,
+                user: LoginResponseUser {
+                    id: user.id,
+                    first_name: &user.first_name,
+                    last_name: &user.last_name,
+                    kind: match user.kind {
+                        UserKind::Administrator => "ADM",
+                        UserKind::Teacher(_) => "TEA",
+                        UserKind::Student(_) => "STU",
+                    },
                 },
-            },
-        })),
-        None => Err(warp::reject::custom(Forbidden {})),
+            }))
+        }
+        None => {
+            tracing::warn!("login rejected: invalid username or password");
+            tracing::Span::current().record("outcome", &"forbidden");
+            Err(warp::reject::custom(Forbidden {}))
+        }
     }
 }
 
-async fn delete_session(
+/// Logs out the bearer token sent in the `Authorization` header, revoking it immediately.
+#[utoipa::path(
+    delete,
+    path = "/api/session",
+    responses(
+        (status = 200, description = "Logged out successfully", body = SimpleSuccessResponse),
+        (status = 401, description = "Invalid bearer token"),
+    ),
+)]
+#[tracing::instrument(skip(db, authorization), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn delete_session(
     authorization: String,
     db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let (auth_type, token) = {
         let mut parts = authorization.splitn(2, " ");
@@ -82,8 +115,11 @@ async fn delete_session(
     let logged_out = auth_type.to_ascii_lowercase() == "bearer" && db.auth_logout(&token);
 
     if logged_out {
+        tracing::Span::current().record("outcome", &"success");
         Ok(warp::reply::json(&SimpleSuccessResponse::new()))
     } else {
+        tracing::warn!("logout rejected: missing or invalid bearer token");
+        tracing::Span::current().record("outcome", &"forbidden");
         Err(warp::reject::custom(Forbidden {}))
     }
 }
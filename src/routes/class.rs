@@ -4,18 +4,22 @@ use warp::{http::StatusCode, Filter, Rejection, Reply};
 use super::{
     globals::{
         OccupanciesListResponse, OccupanciesRequest, PaginatedQueryableListRequest,
-        SimpleSuccessResponse,
+        PaginationMeta, SimpleSuccessResponse,
     },
     ErrorCode, FailureResponse,
 };
 use crate::service::service_value;
 use db::{
-    models::{Class, ClassLevel, Occupancy},
-    ClassUpdate, Database, Db, NewClass,
+    ids::Ids,
+    models::{Class, ClassLevel, OccupancyOccurrence},
+    ClassUpdate, Database, Db, LockedDb, NewClass, PAGE_SIZE,
 };
-use filters::{authed_is_of_kind, delayed, with_db, PossibleUserKind};
+use filters::{authed_is_of_kind, delayed, with_db, with_ids, PossibleUserKind};
 
-pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn routes(
+    db: &Db,
+    ids: &Ids,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let list_route = warp::path!("api" / "classes")
         .and(warp::get())
         .and(authed_is_of_kind(
@@ -23,6 +27,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
             &[PossibleUserKind::Administrator, PossibleUserKind::Teacher],
         ))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::query::<PaginatedQueryableListRequest>())
         .and_then(list)
         .and(delayed(db))
@@ -43,93 +48,154 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
         .and(warp::delete())
         .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(delete)
         .and(delayed(db))
         .boxed();
 
-    let get_route = warp::path!("api" / "classes" / u32)
+    let get_route = warp::path!("api" / "classes" / String)
         .and(warp::get())
+        .and(authed_is_of_kind(
+            db,
+            &[PossibleUserKind::Administrator, PossibleUserKind::Teacher],
+        ))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and_then(get)
         .and(delayed(db))
         .boxed();
 
-    let update_route = warp::path!("api" / "classes" / u32)
+    let update_route = warp::path!("api" / "classes" / String)
         .and(warp::put())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(update)
         .and(delayed(db))
         .boxed();
 
-    let occupancies_get_route = warp::path!("api" / "classes" / u32 / "occupancies")
+    let occupancies_get_route = warp::path!("api" / "classes" / String / "occupancies")
         .and(warp::get())
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::query::<OccupanciesRequest>())
         .and_then(occupancies_get)
         .and(delayed(db))
         .boxed();
 
+    let occupancies_ics_route = warp::path!("api" / "classes" / String / "occupancies.ics")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
+        .and(warp::query::<OccupanciesRequest>())
+        .and_then(occupancies_ics)
+        .and(delayed(db))
+        .boxed();
+
     list_route
         .or(create_route)
         .or(delete_route)
         .or(get_route)
         .or(update_route)
         .or(occupancies_get_route)
+        .or(occupancies_ics_route)
+}
+
+#[derive(Serialize)]
+struct ClassView<'a> {
+    id: String,
+    name: &'a str,
+    level: &'a ClassLevel,
+}
+
+impl<'a> ClassView<'a> {
+    fn new(class: &'a Class, ids: &Ids) -> Self {
+        Self {
+            id: ids.encode(class.id),
+            name: &class.name,
+            level: &class.level,
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct ListResponse<'a> {
     status: &'static str,
     total: usize,
-    classes: Vec<&'a Class>,
+    classes: Vec<ClassView<'a>>,
+    pagination: PaginationMeta,
 }
 
+#[tracing::instrument(skip(_username, db, ids, request), fields(outcome = tracing::field::Empty))]
 async fn list(
     _username: String,
     db: Db,
+    ids: Ids,
     request: PaginatedQueryableListRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     let page = request.normalized_page_number();
-    let (total, classes) = db.class_list(page, request.query.as_deref());
+    let per_page = request.normalized_per_page();
+    let (total, classes) = db.class_list(page, per_page, request.query.as_deref());
+
+    tracing::Span::current().record("outcome", &"success");
 
     Ok(warp::reply::json(&ListResponse {
         status: "success",
         total,
-        classes,
+        pagination: PaginationMeta::new(total, page, per_page.unwrap_or(PAGE_SIZE)),
+        classes: classes
+            .into_iter()
+            .map(|class| ClassView::new(class, &ids))
+            .collect(),
     }))
 }
 
+#[tracing::instrument(skip(_username, db, request), fields(outcome = tracing::field::Empty))]
 async fn create(
     _username: String,
     db: Db,
     request: NewClass,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
     db.class_add(request);
+    tracing::Span::current().record("outcome", &"success");
     Ok(warp::reply::json(&SimpleSuccessResponse::new()))
 }
 
+#[tracing::instrument(skip(_username, db, ids, request), fields(outcome = tracing::field::Empty))]
 async fn delete(
     _username: String,
     db: Db,
-    request: Vec<u32>,
+    ids: Ids,
+    request: Vec<String>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
+
+    let decoded: Option<Vec<u32>> = request.iter().map(|token| ids.decode(token)).collect();
+
+    let decoded = match decoded {
+        Some(decoded) => decoded,
+        None => {
+            tracing::warn!("refused to delete: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
 
-    if db.class_remove(&request) {
+    if db.class_remove(&decoded) {
+        tracing::Span::current().record("outcome", &"success");
         Ok(warp::reply::with_status(
             warp::reply::json(&SimpleSuccessResponse::new()),
             StatusCode::OK,
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        tracing::warn!("refused to delete: no such class");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
 
@@ -146,8 +212,24 @@ struct GetResponseClass<'a> {
     level: &'a ClassLevel,
 }
 
-async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+#[tracing::instrument(skip(token, _username, db, ids), fields(outcome = tracing::field::Empty))]
+async fn get(
+    token: String,
+    _username: String,
+    db: Db,
+    ids: Ids,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to get: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
+
     let class = db.class_get(id);
 
     match class {
@@ -155,7 +237,7 @@ async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallib
             // Total service: somme de tous les cours
             let occupancies_list = db.occupancies_list(None, None);
 
-            let occupancies_list: Vec<&Occupancy> = occupancies_list
+            let occupancies_list: Vec<OccupancyOccurrence> = occupancies_list
                 .into_iter()
                 .filter(|o| {
                     let subject_id = match o.subject_id {
@@ -174,6 +256,8 @@ async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallib
 
             let total_service = service_value(occupancies_list.as_slice()) as u32;
 
+            tracing::Span::current().record("outcome", &"success");
+
             Ok(warp::reply::with_status(
                 warp::reply::json(&GetResponse {
                     status: "success",
@@ -186,22 +270,39 @@ async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallib
                 StatusCode::OK,
             ))
         }
-        None => Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        )),
+        None => {
+            tracing::warn!("no such class");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            Ok(FailureResponse::reply(ErrorCode::InvalidID))
+        }
     }
 }
 
+#[tracing::instrument(skip(token, _username, db, ids, request), fields(outcome = tracing::field::Empty))]
 async fn update(
-    id: u32,
+    token: String,
+    _username: String,
     db: Db,
+    ids: Ids,
     request: ClassUpdate,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to update: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
+
     let status = db.class_update(id, request);
 
     if status.found {
+        tracing::Span::current()
+            .record("outcome", &if status.updated { "success" } else { "noop" });
+
         Ok(warp::reply::with_status(
             warp::reply::json(&SimpleSuccessResponse::new()),
             if status.updated {
@@ -211,30 +312,20 @@ async fn update(
             },
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        tracing::warn!("no such class");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
 
-async fn occupancies_get(
+/// Occupancies belonging to a subject attached to class `id`. Shared by `occupancies_get` and
+/// `occupancies_ics` so both routes stay in sync on what counts as "this class's occupancies".
+fn class_occupancies<'a>(
+    db: &'a LockedDb,
     id: u32,
-    db: Db,
-    request: OccupanciesRequest,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
-
-    if db.class_get(id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
-    }
-
-    let occupancies_list = db.occupancies_list(request.start, request.end);
-
-    let occupancies_list = occupancies_list
+    request: &OccupanciesRequest,
+) -> Vec<OccupancyOccurrence<'a>> {
+    db.occupancies_list(request.start, request.end)
         .into_iter()
         .filter(|o| match o.subject_id {
             Some(subject_id) => {
@@ -250,13 +341,81 @@ async fn occupancies_get(
             }
             None => false,
         })
-        .collect();
+        .collect()
+}
+
+#[tracing::instrument(skip(token, db, ids, request), fields(outcome = tracing::field::Empty))]
+async fn occupancies_get(
+    token: String,
+    db: Db,
+    ids: Ids,
+    request: OccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to get occupancies: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
+
+    if db.class_get(id).is_none() {
+        tracing::warn!("no such class");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+    }
+
+    let occupancies_list = class_occupancies(&db, id, &request);
 
     let response =
         OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
 
+    tracing::Span::current().record("outcome", &"success");
+
     Ok(warp::reply::with_status(
         warp::reply::json(&response),
         StatusCode::OK,
     ))
 }
+
+#[tracing::instrument(skip(token, db, ids, request), fields(outcome = tracing::field::Empty))]
+async fn occupancies_ics(
+    token: String,
+    db: Db,
+    ids: Ids,
+    request: OccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to get occupancies: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response());
+        }
+    };
+
+    if db.class_get(id).is_none() {
+        tracing::warn!("no such class");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response());
+    }
+
+    let occupancies_list = class_occupancies(&db, id, &request);
+
+    let response =
+        OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
+
+    tracing::Span::current().record("outcome", &"success");
+
+    Ok(warp::reply::with_header(
+        response.to_ics(),
+        "content-type",
+        "text/calendar; charset=utf-8",
+    )
+    .into_response())
+}
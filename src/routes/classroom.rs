@@ -2,13 +2,19 @@ use serde::Serialize;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
 use super::{
-    globals::{PaginatedQueryableListRequest, SimpleSuccessResponse},
+    globals::{
+        OccupanciesListResponse, OccupanciesRequest, PaginatedQueryableListRequest, PaginationMeta,
+        SimpleSuccessResponse,
+    },
     ErrorCode, FailureResponse,
 };
-use db::{models::Classroom, ClassroomUpdate, Database, Db, NewClassroom};
-use filters::{authed_is_of_kind, delayed, with_db, PossibleUserKind};
+use db::{ids::Ids, models::Classroom, ClassroomUpdate, Database, Db, NewClassroom, PAGE_SIZE};
+use filters::{authed_is_of_kind, delayed, with_db, with_ids, PossibleUserKind};
 
-pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn routes(
+    db: &Db,
+    ids: &Ids,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let list_route = warp::path!("api" / "classrooms")
         .and(warp::get())
         .and(authed_is_of_kind(
@@ -16,6 +22,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
             &[PossibleUserKind::Administrator, PossibleUserKind::Teacher],
         ))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::query::<PaginatedQueryableListRequest>())
         .and_then(list)
         .and(delayed(db))
@@ -36,121 +43,309 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
         .and(warp::delete())
         .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(delete)
         .and(delayed(db))
         .boxed();
 
-    let get_route = warp::path!("api" / "classrooms" / u32)
+    let get_route = warp::path!("api" / "classrooms" / String)
         .and(warp::get())
+        .and(authed_is_of_kind(
+            db,
+            &[PossibleUserKind::Administrator, PossibleUserKind::Teacher],
+        ))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and_then(get)
         .and(delayed(db))
         .boxed();
 
-    let update_route = warp::path!("api" / "classrooms" / u32)
+    let update_route = warp::path!("api" / "classrooms" / String)
         .and(warp::put())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(update)
         .and(delayed(db))
         .boxed();
 
+    let occupancies_ics_route = warp::path!("api" / "classrooms" / String / "occupancies.ics")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(with_ids(ids.clone()))
+        .and(warp::query::<OccupanciesRequest>())
+        .and_then(occupancies_ics)
+        .and(delayed(db))
+        .boxed();
+
     list_route
         .or(create_route)
         .or(delete_route)
         .or(get_route)
         .or(update_route)
+        .or(occupancies_ics_route)
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct ClassroomView<'a> {
+    id: String,
+    name: &'a str,
+    capacity: u16,
+}
+
+impl<'a> ClassroomView<'a> {
+    fn new(classroom: &'a Classroom, ids: &Ids) -> Self {
+        Self {
+            id: ids.encode(classroom.id),
+            name: &classroom.name,
+            capacity: classroom.capacity,
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct ListResponse<'a> {
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct ListResponse<'a> {
     status: &'static str,
     total: usize,
-    classrooms: Vec<&'a Classroom>,
+    classrooms: Vec<ClassroomView<'a>>,
+    pagination: PaginationMeta,
 }
 
-async fn list(
+/// Lists classrooms, optionally filtered by name and paginated.
+#[utoipa::path(
+    get,
+    path = "/api/classrooms",
+    responses(
+        (status = 200, description = "Classrooms matching the query", body = ListResponse),
+    ),
+)]
+#[tracing::instrument(skip(_username, db, ids, request), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn list(
     _username: String,
     db: Db,
+    ids: Ids,
     request: PaginatedQueryableListRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     let page = request.normalized_page_number();
-    let (total, classrooms) = db.classroom_list(page, request.query.as_deref());
+    let per_page = request.normalized_per_page();
+    let (total, classrooms) = db.classroom_list(page, per_page, request.query.as_deref());
+
+    tracing::Span::current().record("outcome", &"success");
 
     Ok(warp::reply::json(&ListResponse {
         status: "success",
         total,
-        classrooms,
+        pagination: PaginationMeta::new(total, page, per_page.unwrap_or(PAGE_SIZE)),
+        classrooms: classrooms
+            .into_iter()
+            .map(|classroom| ClassroomView::new(classroom, &ids))
+            .collect(),
     }))
 }
 
-async fn create(
+/// Creates a new classroom. Administrator-only.
+#[utoipa::path(
+    post,
+    path = "/api/classrooms",
+    request_body = NewClassroom,
+    responses(
+        (status = 200, description = "Classroom created", body = SimpleSuccessResponse),
+    ),
+)]
+#[tracing::instrument(skip(_username, db, request), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn create(
     _username: String,
     db: Db,
     request: NewClassroom,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
     db.classroom_add(request);
+    tracing::Span::current().record("outcome", &"success");
     Ok(warp::reply::json(&SimpleSuccessResponse::new()))
 }
 
-async fn delete(
+/// Deletes the classrooms whose ids are given. Administrator-only.
+#[utoipa::path(
+    delete,
+    path = "/api/classrooms",
+    request_body = Vec<String>,
+    responses(
+        (status = 200, description = "Classrooms deleted", body = SimpleSuccessResponse),
+        (status = 404, description = "One of the given ids does not exist", body = FailureResponse),
+    ),
+)]
+#[tracing::instrument(skip(_username, db, ids, request), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn delete(
     _username: String,
     db: Db,
-    request: Vec<u32>,
+    ids: Ids,
+    request: Vec<String>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
+
+    let decoded: Option<Vec<u32>> = request.iter().map(|token| ids.decode(token)).collect();
 
-    if db.classroom_remove(&request) {
+    let decoded = match decoded {
+        Some(decoded) => decoded,
+        None => {
+            tracing::warn!("refused to delete: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
+
+    if db.classroom_remove(&decoded) {
+        tracing::Span::current().record("outcome", &"success");
         Ok(warp::reply::with_status(
             warp::reply::json(&SimpleSuccessResponse::new()),
             StatusCode::OK,
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        tracing::warn!("refused to delete: no such classroom");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
 
-#[derive(Serialize)]
-struct GetResponse<'a> {
+#[tracing::instrument(skip(token, db, ids, request), fields(outcome = tracing::field::Empty))]
+async fn occupancies_ics(
+    token: String,
+    db: Db,
+    ids: Ids,
+    request: OccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to get occupancies: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response());
+        }
+    };
+
+    if db.classroom_get(id).is_none() {
+        tracing::warn!("no such classroom");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response());
+    }
+
+    let occupancies_list = db
+        .occupancies_list(request.start, request.end)
+        .into_iter()
+        .filter(|o| o.classroom_id == Some(id))
+        .collect();
+
+    let response =
+        OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
+
+    tracing::Span::current().record("outcome", &"success");
+
+    Ok(warp::reply::with_header(
+        response.to_ics(),
+        "content-type",
+        "text/calendar; charset=utf-8",
+    )
+    .into_response())
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct GetResponse<'a> {
     status: &'static str,
-    classroom: &'a Classroom,
+    classroom: ClassroomView<'a>,
 }
 
-async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+/// Looks up a single classroom by id.
+#[utoipa::path(
+    get,
+    path = "/api/classrooms/{id}",
+    params(("id" = String, Path, description = "Classroom id")),
+    responses(
+        (status = 200, description = "The classroom", body = GetResponse),
+        (status = 404, description = "No classroom with this id", body = FailureResponse),
+    ),
+)]
+#[tracing::instrument(skip(token, _username, db, ids), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn get(
+    token: String,
+    _username: String,
+    db: Db,
+    ids: Ids,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to get: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
+
     let classroom = db.classroom_get(id);
 
     match classroom {
-        Some(classroom) => Ok(warp::reply::with_status(
-            warp::reply::json(&GetResponse {
-                status: "success",
-                classroom,
-            }),
-            StatusCode::OK,
-        )),
-        None => Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        )),
+        Some(classroom) => {
+            tracing::Span::current().record("outcome", &"success");
+            Ok(warp::reply::with_status(
+                warp::reply::json(&GetResponse {
+                    status: "success",
+                    classroom: ClassroomView::new(classroom, &ids),
+                }),
+                StatusCode::OK,
+            ))
+        }
+        None => {
+            tracing::warn!("no such classroom");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            Ok(FailureResponse::reply(ErrorCode::InvalidID))
+        }
     }
 }
 
-async fn update(
-    id: u32,
+/// Updates a classroom's editable fields.
+#[utoipa::path(
+    put,
+    path = "/api/classrooms/{id}",
+    params(("id" = String, Path, description = "Classroom id")),
+    request_body = ClassroomUpdate,
+    responses(
+        (status = 200, description = "Classroom updated", body = SimpleSuccessResponse),
+        (status = 204, description = "Classroom found but nothing changed"),
+        (status = 404, description = "No classroom with this id", body = FailureResponse),
+    ),
+)]
+#[tracing::instrument(skip(token, _username, db, ids, request), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn update(
+    token: String,
+    _username: String,
     db: Db,
+    ids: Ids,
     request: ClassroomUpdate,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
+
+    let id = match ids.decode(&token) {
+        Some(id) => id,
+        None => {
+            tracing::warn!("refused to update: malformed id token");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    };
+
     let status = db.classroom_update(id, request);
 
     if status.found {
+        tracing::Span::current()
+            .record("outcome", &if status.updated { "success" } else { "noop" });
+
         Ok(warp::reply::with_status(
             warp::reply::json(&SimpleSuccessResponse::new()),
             if status.updated {
@@ -160,9 +355,8 @@ async fn update(
             },
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        tracing::warn!("no such classroom");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
@@ -1,21 +1,27 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
 use db::{
-    models::{Occupancy, OccupancyType},
-    Database, LockedDb,
+    models::{Class, Classroom, OccupancyOccurrence, OccupancyType, Subject, User},
+    Conflict, Database, LockedDb, PAGE_SIZE,
 };
 use serde::{Deserialize, Deserializer, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize)]
+#[derive(Serialize, utoipa::ToSchema)]
 pub struct FailureResponse {
     status: &'static str,
     code: ErrorCode,
+    #[serde(rename = "type")]
+    error_type: &'static str,
+    message: &'static str,
 }
 
 impl FailureResponse {
     pub fn new(code: ErrorCode) -> Self {
         Self {
             status: "error",
+            error_type: code.error_type(),
+            message: code.message(),
             code,
         }
     }
@@ -23,12 +29,20 @@ impl FailureResponse {
     pub fn new_reply(code: ErrorCode) -> warp::reply::Json {
         warp::reply::json(&Self::new(code))
     }
+
+    /// Builds the JSON body together with the HTTP status code that `code` maps to, so callers
+    /// don't have to keep the two in sync by hand.
+    pub fn reply(code: ErrorCode) -> warp::reply::WithStatus<warp::reply::Json> {
+        let status_code = code.status_code();
+        warp::reply::with_status(Self::new_reply(code), status_code)
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Copy, utoipa::ToSchema)]
 #[allow(dead_code)]
 pub enum ErrorCode {
     InvalidCredentials,
+    NoAuthToken,
     InsufficientAuthorization,
     MalformedData,
     InvalidOldPassword,
@@ -56,9 +70,214 @@ pub enum ErrorCode {
     Unknown,
     NotFound,
     IllegalRequest,
+    InvalidImage,
+    ImageTooLarge,
 }
 
+impl ErrorCode {
+    /// The HTTP status code a client should see for this error.
+    pub fn status_code(&self) -> warp::http::StatusCode {
+        use warp::http::StatusCode;
+
+        match self {
+            ErrorCode::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ErrorCode::NoAuthToken => StatusCode::UNAUTHORIZED,
+            ErrorCode::InsufficientAuthorization => StatusCode::FORBIDDEN,
+            ErrorCode::MalformedData => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidOldPassword => StatusCode::FORBIDDEN,
+            ErrorCode::PasswordTooSimple => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidEmail => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidPhoneNumber => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRank => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidID => StatusCode::NOT_FOUND,
+            ErrorCode::InvalidCapacity => StatusCode::BAD_REQUEST,
+            ErrorCode::TeacherInCharge => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ClassroomUsed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::InvalidLevel => StatusCode::BAD_REQUEST,
+            ErrorCode::ClassUsed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::StudentInClass => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::SubjectUsed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::TeacherNotInCharge => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::LastTeacherInSubject => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::LastGroupInSubject => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ClassroomAlreadyOccupied => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ClassOrGroupAlreadyOccupied => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::InvalidOccupancyType => StatusCode::BAD_REQUEST,
+            ErrorCode::EndBeforeStart => StatusCode::BAD_REQUEST,
+            ErrorCode::TeacherDoesNotTeach => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::IllegalOccupancyType => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::IllegalRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidImage => StatusCode::BAD_REQUEST,
+            ErrorCode::ImageTooLarge => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// A stable, machine-readable identifier for this error, meant to be matched on by clients.
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidCredentials => "invalid_credentials",
+            ErrorCode::NoAuthToken => "no_auth_token",
+            ErrorCode::InsufficientAuthorization => "insufficient_authorization",
+            ErrorCode::MalformedData => "malformed_data",
+            ErrorCode::InvalidOldPassword => "invalid_old_password",
+            ErrorCode::PasswordTooSimple => "password_too_simple",
+            ErrorCode::InvalidEmail => "invalid_email",
+            ErrorCode::InvalidPhoneNumber => "invalid_phone_number",
+            ErrorCode::InvalidRank => "invalid_rank",
+            ErrorCode::InvalidID => "invalid_id",
+            ErrorCode::InvalidCapacity => "invalid_capacity",
+            ErrorCode::TeacherInCharge => "teacher_in_charge",
+            ErrorCode::ClassroomUsed => "classroom_used",
+            ErrorCode::InvalidLevel => "invalid_level",
+            ErrorCode::ClassUsed => "class_used",
+            ErrorCode::StudentInClass => "student_in_class",
+            ErrorCode::SubjectUsed => "subject_used",
+            ErrorCode::TeacherNotInCharge => "teacher_not_in_charge",
+            ErrorCode::LastTeacherInSubject => "last_teacher_in_subject",
+            ErrorCode::LastGroupInSubject => "last_group_in_subject",
+            ErrorCode::ClassroomAlreadyOccupied => "classroom_already_occupied",
+            ErrorCode::ClassOrGroupAlreadyOccupied => "class_or_group_already_occupied",
+            ErrorCode::InvalidOccupancyType => "invalid_occupancy_type",
+            ErrorCode::EndBeforeStart => "end_before_start",
+            ErrorCode::TeacherDoesNotTeach => "teacher_does_not_teach",
+            ErrorCode::IllegalOccupancyType => "illegal_occupancy_type",
+            ErrorCode::Unknown => "unknown",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::IllegalRequest => "illegal_request",
+            ErrorCode::InvalidImage => "invalid_image",
+            ErrorCode::ImageTooLarge => "image_too_large",
+        }
+    }
+
+    /// A human-readable message that can be shown to an end user.
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidCredentials => "The provided username or password is incorrect.",
+            ErrorCode::NoAuthToken => "No authentication token was provided.",
+            ErrorCode::InsufficientAuthorization => {
+                "You are not allowed to perform this action."
+            }
+            ErrorCode::MalformedData => "The request body is malformed.",
+            ErrorCode::InvalidOldPassword => "The provided current password is incorrect.",
+            ErrorCode::PasswordTooSimple => "The new password is too simple.",
+            ErrorCode::InvalidEmail => "The provided email address is invalid.",
+            ErrorCode::InvalidPhoneNumber => "The provided phone number is invalid.",
+            ErrorCode::InvalidRank => "The provided rank is invalid.",
+            ErrorCode::InvalidID => "No resource was found for the given ID.",
+            ErrorCode::InvalidCapacity => "The provided capacity is invalid.",
+            ErrorCode::TeacherInCharge => "This teacher is in charge of the subject.",
+            ErrorCode::ClassroomUsed => "This classroom is used by at least one occupancy.",
+            ErrorCode::InvalidLevel => "The provided level is invalid.",
+            ErrorCode::ClassUsed => "This class is used by at least one subject.",
+            ErrorCode::StudentInClass => "This student is enrolled in a class.",
+            ErrorCode::SubjectUsed => "This subject is used by at least one occupancy.",
+            ErrorCode::TeacherNotInCharge => "This teacher is not in charge of the subject.",
+            ErrorCode::LastTeacherInSubject => {
+                "This is the last teacher teaching the subject."
+            }
+            ErrorCode::LastGroupInSubject => "This is the last group in the subject.",
+            ErrorCode::ClassroomAlreadyOccupied => {
+                "This classroom is already occupied during this time slot."
+            }
+            ErrorCode::ClassOrGroupAlreadyOccupied => {
+                "This class or group is already occupied during this time slot."
+            }
+            ErrorCode::InvalidOccupancyType => "The provided occupancy type is invalid.",
+            ErrorCode::EndBeforeStart => "The end datetime is before the start datetime.",
+            ErrorCode::TeacherDoesNotTeach => "This teacher does not teach this subject.",
+            ErrorCode::IllegalOccupancyType => {
+                "This occupancy type is not allowed in this context."
+            }
+            ErrorCode::Unknown => "An unknown error occurred.",
+            ErrorCode::NotFound => "The requested resource was not found.",
+            ErrorCode::IllegalRequest => "This request is not allowed.",
+            ErrorCode::InvalidImage => "The uploaded file is not a valid image.",
+            ErrorCode::ImageTooLarge => "The uploaded image exceeds the maximum allowed size.",
+        }
+    }
+}
+
+/// An internal invariant was violated — e.g. a relation pointing at a row that no longer exists.
+/// There's no `ErrorCode` variant a caller could pick for this the way there is for a malformed
+/// request: it's always a bug, so [`handle_rejection`](crate::handle_rejection) always reports it
+/// to the client as the generic `ErrorCode::Unknown` and logs the real detail server-side. The
+/// point of having this type (instead of `.expect()`/`unreachable!()`ing) is just to let call
+/// sites `?`-propagate out of a corrupt-state branch instead of crashing the worker over it.
+#[derive(Debug)]
+pub struct InternalError(pub String);
+
+impl std::fmt::Display for InternalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl warp::reject::Reject for InternalError {}
+
+impl From<InternalError> for warp::Rejection {
+    fn from(error: InternalError) -> Self {
+        warp::reject::custom(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, InternalError>;
+
+/// Body returned when an occupancy create/update is rejected because it truly overlaps another
+/// occupancy (see `Conflict`), naming every clashing occupancy id and which resource caused the
+/// clash instead of just a flat `ErrorCode`. Always served with `409 Conflict`.
 #[derive(Serialize)]
+pub struct OccupancyConflictResponse {
+    status: &'static str,
+    conflicts: Vec<Conflict>,
+}
+
+impl OccupancyConflictResponse {
+    pub fn reply(conflicts: Vec<Conflict>) -> warp::reply::WithStatus<warp::reply::Json> {
+        warp::reply::with_status(
+            warp::reply::json(&Self {
+                status: "error",
+                conflicts,
+            }),
+            warp::http::StatusCode::CONFLICT,
+        )
+    }
+}
+
+/// Body returned when an uploaded database dump fails to parse. Unlike a flat `ErrorCode`, the
+/// message carries whatever field/position `serde_json` reports, so the client can tell which
+/// part of the dump was malformed.
+#[derive(Serialize)]
+pub struct ImportErrorResponse {
+    status: &'static str,
+    #[serde(rename = "type")]
+    error_type: &'static str,
+    message: String,
+}
+
+impl ImportErrorResponse {
+    pub fn reply(error: serde_json::Error) -> warp::reply::WithStatus<warp::reply::Json> {
+        warp::reply::with_status(
+            warp::reply::json(&Self {
+                status: "error",
+                error_type: "malformed_data",
+                message: format!("The uploaded dump is invalid: {}", error),
+            }),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+    }
+}
+
+/// `?force=true` lets an administrator bypass conflict checking on occupancy create/update, for
+/// the rare case where a double-booking is intentional (e.g. a one-off room share).
+#[derive(Deserialize, Debug, Default)]
+pub struct ForceQuery {
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
 pub struct SimpleSuccessResponse {
     status: &'static str,
 }
@@ -73,6 +292,7 @@ impl SimpleSuccessResponse {
 pub struct PaginatedQueryableListRequest {
     pub query: Option<String>,
     pub page: Option<usize>,
+    pub per_page: Option<usize>,
 }
 
 impl PaginatedQueryableListRequest {
@@ -82,6 +302,42 @@ impl PaginatedQueryableListRequest {
             .map(|v| if v >= 1 { v } else { 1 })
             .unwrap_or(1usize)
     }
+
+    /// Caps the requested page size so a client can't ask for an unbounded result set.
+    pub fn normalized_per_page(&self) -> Option<usize> {
+        self.per_page.map(|v| v.clamp(1, 100))
+    }
+}
+
+/// Pagination metadata attached to list responses, computed from the total number of matching
+/// rows, the page that was returned and the page size that was used.
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct PaginationMeta {
+    pub total_results: usize,
+    pub total_pages: usize,
+    pub current_page: usize,
+    pub per_page: usize,
+    pub has_next: bool,
+    pub has_previous: bool,
+}
+
+impl PaginationMeta {
+    pub fn new(total_results: usize, current_page: usize, per_page: usize) -> Self {
+        let total_pages = if total_results == 0 {
+            1
+        } else {
+            (total_results + per_page - 1) / per_page
+        };
+
+        Self {
+            total_results,
+            total_pages,
+            current_page,
+            per_page,
+            has_next: current_page < total_pages,
+            has_previous: current_page > 1,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -104,6 +360,13 @@ pub struct OccupanciesRequest {
     pub start: Option<u64>,
     pub end: Option<u64>,
     pub occupancies_per_day: Option<u32>,
+    /// Caps how many occupancies `GET /api/occupancies` returns in one page. Unset means
+    /// unbounded, same as before this field existed.
+    pub limit: Option<u16>,
+    /// Cursor for the next page: the `id` of the last occupancy the caller already has, once the
+    /// result set has been sorted by `(start_datetime, id)`. Everything up to and including this
+    /// id is skipped.
+    pub from_id: Option<u32>,
 }
 
 #[derive(Serialize)]
@@ -133,11 +396,177 @@ pub struct OccupanciesListItemResponse<'a> {
 }
 
 impl<'a> OccupanciesListResponse<'a> {
+    pub fn to_ics(&self) -> String {
+        let mut lines: Vec<String> = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "VERSION:2.0".to_string(),
+            "PRODID:-//test_server//occupancies//FR".to_string(),
+            "CALSCALE:GREGORIAN".to_string(),
+        ];
+
+        let stamp = format_ics_datetime(now_timestamp());
+
+        for day in &self.days {
+            for occupancy in &day.occupancies {
+                lines.push("BEGIN:VEVENT".to_string());
+                lines.push(format!("UID:{}@test-server", occupancy.id));
+                lines.push(format!("DTSTAMP:{}", stamp));
+                lines.push(format!("DTSTART:{}", format_ics_datetime(occupancy.start)));
+                lines.push(format!("DTEND:{}", format_ics_datetime(occupancy.end)));
+                lines.push(format!(
+                    "SUMMARY:{}",
+                    ics_escape(occupancy.subject_name.unwrap_or(occupancy.name))
+                ));
+
+                if let Some(classroom_name) = occupancy.classroom_name {
+                    lines.push(format!("LOCATION:{}", ics_escape(classroom_name)));
+                }
+
+                let description = [
+                    Some(occupancy.teacher_name.clone()),
+                    occupancy.group_name.clone(),
+                    occupancy.class_name.map(|c| c.to_string()),
+                    Some(format!("{:?}", occupancy.occupancy_type)),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>()
+                .join(" - ");
+
+                lines.push(format!("DESCRIPTION:{}", ics_escape(&description)));
+                lines.push("END:VEVENT".to_string());
+            }
+        }
+
+        lines.push("END:VCALENDAR".to_string());
+
+        lines
+            .iter()
+            .map(|line| ics_fold(line))
+            .collect::<Vec<String>>()
+            .join("\r\n")
+            + "\r\n"
+    }
+
+    /// Renders a printable, print-to-PDF-friendly weekly grid: one column per day, one row per
+    /// distinct time slot found in the result set.
+    pub fn to_printable_html(&self, title: &str) -> String {
+        let mut days: Vec<&OccupanciesListItemResponse> = self.days.iter().collect();
+        days.sort_by_key(|day| day.occupancies.first().map(|o| o.start).unwrap_or(0));
+
+        let mut time_slots: Vec<String> = days
+            .iter()
+            .flat_map(|day| day.occupancies.iter())
+            .map(|o| format_time_of_day(o.start))
+            .collect();
+        time_slots.sort();
+        time_slots.dedup();
+
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n");
+        html.push_str(&format!("<title>{}</title>\n", html_escape(title)));
+        html.push_str(
+            "<style>\
+             table { border-collapse: collapse; width: 100%; } \
+             th, td { border: 1px solid #333; padding: 4px 8px; vertical-align: top; font-size: 12px; } \
+             th { background: #eee; } \
+             @media print { body { margin: 0; } }\
+             </style>\n</head>\n<body>\n",
+        );
+        html.push_str(&format!("<h1>{}</h1>\n", html_escape(title)));
+        html.push_str("<table>\n<thead>\n<tr><th>Heure</th>");
+
+        for day in &days {
+            html.push_str(&format!("<th>{}</th>", html_escape(&day.date)));
+        }
+
+        html.push_str("</tr>\n</thead>\n<tbody>\n");
+
+        for slot in &time_slots {
+            html.push_str(&format!("<tr><td>{}</td>", html_escape(slot)));
+
+            for day in &days {
+                let cell = day
+                    .occupancies
+                    .iter()
+                    .filter(|o| &format_time_of_day(o.start) == slot)
+                    .map(|o| {
+                        format!(
+                            "{}{}{}",
+                            html_escape(o.subject_name.unwrap_or(o.name)),
+                            o.classroom_name
+                                .map(|c| format!("<br><em>{}</em>", html_escape(c)))
+                                .unwrap_or_default(),
+                            o.group_name
+                                .as_ref()
+                                .map(|g| format!("<br>{}", html_escape(g)))
+                                .unwrap_or_default(),
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("<hr>");
+
+                html.push_str(&format!("<td>{}</td>", cell));
+            }
+
+            html.push_str("</tr>\n");
+        }
+
+        html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+
+        html
+    }
+
     pub fn from_list(
         db: &'a LockedDb,
-        occupancies_list: Vec<&'a Occupancy>,
+        occupancies_list: Vec<OccupancyOccurrence<'a>>,
         occupancies_per_day: Option<u32>,
     ) -> Self {
+        // Resolve each distinct reference once instead of once per occupancy.
+        let subject_ids: HashSet<u32> = occupancies_list.iter().filter_map(|o| o.subject_id).collect();
+
+        let subjects: HashMap<u32, &Subject> = subject_ids
+            .into_iter()
+            .map(|id| {
+                (
+                    id,
+                    db.subject_get(id).expect("subject should be a valid reference"),
+                )
+            })
+            .collect();
+
+        let class_ids: HashSet<u32> = subjects.values().map(|s| s.class_id).collect();
+
+        let classes: HashMap<u32, &Class> = class_ids
+            .into_iter()
+            .map(|id| (id, db.class_get(id).expect("class should be a valid reference")))
+            .collect();
+
+        let classroom_ids: HashSet<u32> = occupancies_list.iter().filter_map(|o| o.classroom_id).collect();
+
+        let classrooms: HashMap<u32, &Classroom> = classroom_ids
+            .into_iter()
+            .map(|id| {
+                (
+                    id,
+                    db.classroom_get(id).expect("classroom should be a valid reference"),
+                )
+            })
+            .collect();
+
+        let teacher_ids: HashSet<u32> = occupancies_list.iter().map(|o| o.teacher_id).collect();
+
+        let teachers: HashMap<u32, &User> = teacher_ids
+            .into_iter()
+            .map(|id| {
+                (
+                    id,
+                    db.user_get_teacher_by_id(id)
+                        .expect("should be a valid reference"),
+                )
+            })
+            .collect();
+
         let mut occupancies: HashMap<String, Vec<OccupanciesListElement>> = HashMap::new();
 
         for occupancy in occupancies_list {
@@ -148,22 +577,25 @@ impl<'a> OccupanciesListResponse<'a> {
             let entry = occupancies.entry(key).or_insert(Vec::new());
 
             let subject = occupancy.subject_id.map(|subject_id| {
-                db.subject_get(subject_id)
+                *subjects
+                    .get(&subject_id)
                     .expect("subject should be a valid reference")
             });
 
             let class = subject.map(|subject| {
-                db.class_get(subject.class_id)
+                *classes
+                    .get(&subject.class_id)
                     .expect("class should be a valid reference")
             });
 
             let classroom = occupancy.classroom_id.map(|classroom_id| {
-                db.classroom_get(classroom_id)
+                *classrooms
+                    .get(&classroom_id)
                     .expect("classroom should be a valid reference")
             });
 
-            let teacher = db
-                .user_get_teacher_by_id(occupancy.teacher_id)
+            let teacher = *teachers
+                .get(&occupancy.teacher_id)
                 .expect("should be a valid reference");
 
             entry.push(OccupanciesListElement {
@@ -208,3 +640,71 @@ impl<'a> OccupanciesListResponse<'a> {
         }
     }
 }
+
+fn now_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
+}
+
+fn format_ics_datetime(timestamp: u64) -> String {
+    let date = NaiveDateTime::from_timestamp(timestamp as i64, 0);
+    let datetime: DateTime<Utc> = DateTime::from_utc(date, Utc);
+    datetime.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn format_time_of_day(timestamp: u64) -> String {
+    let date = NaiveDateTime::from_timestamp(timestamp as i64, 0);
+    let datetime: DateTime<Utc> = DateTime::from_utc(date, Utc);
+    datetime.format("%H:%M").to_string()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes `,`, `;`, `\` and newlines in a text value, as required by RFC 5545.
+fn ics_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Folds a single logical line so no physical line exceeds 75 octets, continuation lines
+/// starting with a single leading space, per RFC 5545 section 3.1.
+fn ics_fold(line: &str) -> String {
+    const LIMIT: usize = 75;
+
+    let mut out = String::new();
+    let mut remaining = line;
+    let mut first_line = true;
+
+    loop {
+        let limit = if first_line { LIMIT } else { LIMIT - 1 };
+
+        if remaining.len() <= limit {
+            out.push_str(remaining);
+            break;
+        }
+
+        let mut split_at = 0;
+        for (idx, ch) in remaining.char_indices() {
+            if idx + ch.len_utf8() > limit {
+                break;
+            }
+            split_at = idx + ch.len_utf8();
+        }
+
+        out.push_str(&remaining[..split_at]);
+        out.push_str("\r\n ");
+        remaining = &remaining[split_at..];
+        first_line = false;
+    }
+
+    out
+}
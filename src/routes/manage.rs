@@ -1,36 +1,64 @@
-use db::{ConcreteDb, Database, Db};
-use filters::with_db;
+use crate::service::count_hours;
+use db::{Database, Db, Stats};
+use filters::{authed_is_of_kind, with_db, with_metrics, Metrics, PossibleUserKind};
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use std::io::Write;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
 use warp::{Filter, Rejection, Reply};
 
-pub fn routes(db: &Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+use super::{
+    globals::{ImportErrorResponse, SimpleSuccessResponse},
+    openapi::ApiDoc,
+    ErrorCode, FailureResponse,
+};
+use utoipa::OpenApi;
+
+pub fn routes(db: &Db, metrics: &Metrics) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let index_route = warp::get().and(warp::path::end()).and_then(index);
 
     let dump_route = warp::path!("api" / "dump")
         .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and_then(dump)
         .boxed();
 
     let reset_route = warp::path!("api" / "reset")
         .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and_then(reset)
         .boxed();
 
     let delay_route = warp::path!("api" / "delay")
         .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and_then(delay)
         .boxed();
 
     let set_delay_route = warp::path!("api" / "delay" / u64)
         .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and_then(set_delay)
         .boxed();
 
+    let schoolyear_anchor_route = warp::path!("api" / "schoolyear-anchor")
+        .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and_then(schoolyear_anchor)
+        .boxed();
+
+    let set_schoolyear_anchor_route = warp::path!("api" / "schoolyear-anchor" / u64)
+        .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and_then(set_schoolyear_anchor)
+        .boxed();
+
     let swagger_route = warp::path!("swagger.json")
         .and(warp::get())
         .and_then(swagger)
@@ -40,25 +68,68 @@ pub fn routes(db: &Db) -> impl Filter<Extract = impl Reply, Error = Rejection> +
 
     let export_route = warp::path!("api" / "export")
         .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(warp::header::optional::<String>("accept-encoding"))
         .and_then(export)
         .boxed();
 
     let import_route = warp::path!("api" / "import")
-        .and(warp::get())
+        .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 1024 * 16).and(warp::body::bytes()))
         .and_then(import)
         .boxed();
 
+    let import_untis_route = warp::path!("api" / "import" / "untis")
+        .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 1024 * 16).and(warp::body::bytes()))
+        .and_then(import_untis)
+        .boxed();
+
+    let health_route = warp::path!("api" / "health")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and_then(health)
+        .boxed();
+
+    let version_route = warp::path!("api" / "version")
+        .and(warp::get())
+        .and_then(version)
+        .boxed();
+
+    let stats_route = warp::path!("api" / "stats")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and_then(stats)
+        .boxed();
+
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and_then(metrics_handler)
+        .boxed();
+
     index_route
         .or(dump_route)
         .or(reset_route)
         .or(delay_route)
         .or(set_delay_route)
+        .or(schoolyear_anchor_route)
+        .or(set_schoolyear_anchor_route)
         .or(swagger_route)
         .or(swagger_ui_route)
         .or(export_route)
         .or(import_route)
+        .or(import_untis_route)
+        .or(health_route)
+        .or(version_route)
+        .or(stats_route)
+        .or(metrics_route)
 }
 
 async fn index() -> Result<impl warp::Reply, warp::Rejection> {
@@ -69,22 +140,49 @@ async fn index() -> Result<impl warp::Reply, warp::Rejection> {
     Ok(warp::reply::html(html))
 }
 
+// Serves a code-derived OpenAPI document instead of the hand-maintained
+// `assets/swagger.json` this route used to read, so it can't drift from the handlers it
+// describes. See `super::openapi::ApiDoc` for which part of the route tree it covers.
 async fn swagger() -> Result<impl warp::Reply, warp::Rejection> {
     Ok(warp::reply::with_header(
-        include_str!("../../assets/swagger.json"),
+        ApiDoc::openapi()
+            .to_json()
+            .expect("a derived OpenAPI document should always serialize"),
         "content-type",
         "application/json",
     ))
 }
 
 async fn swagger_ui() -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(warp::reply::html(include_str!(
-        "../../assets/swagger_ui.html"
-    )))
+    Ok(warp::reply::html(SWAGGER_UI_HTML))
 }
 
-async fn dump(db: Db) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+// Points the bundled Swagger UI at our own `/swagger.json` instead of shipping (and keeping in
+// sync) a vendored copy of the asset, since that's the only thing this page needs to customize.
+const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>test_server API docs</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({
+        url: "/swagger.json",
+        dom_id: "#swagger-ui",
+      });
+    };
+  </script>
+</body>
+</html>
+"##;
+
+#[tracing::instrument(skip(_username, db))]
+async fn dump(_username: String, db: Db) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
 
     Ok(warp::reply::with_header(
         db.dump_as_json().unwrap(),
@@ -94,47 +192,245 @@ async fn dump(db: Db) -> Result<impl warp::Reply, warp::Rejection> {
 }
 
 // Resets the database
-async fn reset(db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+#[tracing::instrument(skip(_username, db))]
+async fn reset(_username: String, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let mut db = filters::timed_write(&db).await;
     db.reset();
+    tracing::warn!("database reset");
     Ok(warp::reply::json(&"ok".to_string()))
 }
 
-async fn delay(db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+async fn delay(_username: String, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
     let delay = db.delay_get().as_millis();
     Ok(warp::reply::json(&delay))
 }
 
-async fn set_delay(delay: u64, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+async fn set_delay(
+    delay: u64,
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let mut db = filters::timed_write(&db).await;
     db.delay_set(Duration::from_millis(delay));
     Ok(warp::reply::json(&"ok".to_string()))
 }
 
-async fn export(db: Db) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+// The schoolyear anchor is the start-of-schoolyear date used to tell "week A" from "week B" for
+// recurring occupancies.
+async fn schoolyear_anchor(
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
+    Ok(warp::reply::json(&db.schoolyear_anchor_get()))
+}
+
+async fn set_schoolyear_anchor(
+    anchor: u64,
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let mut db = filters::timed_write(&db).await;
+    db.schoolyear_anchor_set(anchor);
+    Ok(warp::reply::json(&"ok".to_string()))
+}
 
+// Streams the full dump as a downloadable attachment instead of writing it to `save.json` on the
+// server's own disk, so backup/restore works from a remote client. Gzips the body when the
+// client advertises `Accept-Encoding: gzip`.
+#[tracing::instrument(skip(_username, db, accept_encoding))]
+async fn export(
+    _username: String,
+    db: Db,
+    accept_encoding: Option<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
     let dump = db.dump_as_json().expect("could not dump");
 
-    let mut output = tokio::fs::File::create("save.json")
-        .await
-        .expect("could not create DB");
+    let wants_gzip = accept_encoding
+        .map(|header| header.split(',').any(|encoding| encoding.trim() == "gzip"))
+        .unwrap_or(false);
 
-    output
-        .write_all(dump.as_bytes())
-        .await
-        .expect("could not persist DB");
+    if wants_gzip {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(dump.as_bytes())
+            .expect("could not gzip dump");
+        let compressed = encoder.finish().expect("could not gzip dump");
 
-    Ok(warp::reply::json(&"ok".to_string()))
+        Ok(warp::reply::with_header(
+            warp::reply::with_header(
+                warp::reply::with_header(
+                    compressed,
+                    "content-type",
+                    "application/json",
+                ),
+                "content-disposition",
+                "attachment; filename=\"dump.json.gz\"",
+            ),
+            "content-encoding",
+            "gzip",
+        )
+        .into_response())
+    } else {
+        Ok(warp::reply::with_header(
+            warp::reply::with_header(dump, "content-type", "application/json"),
+            "content-disposition",
+            "attachment; filename=\"dump.json\"",
+        )
+        .into_response())
+    }
 }
 
-async fn import(db: Db) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
-    let new_db = match ConcreteDb::from_file("save.json") {
-        Ok(db) => db,
-        Err(_) => return Ok(warp::reply::json(&"failed to read file".to_string())),
+// Accepts an uploaded dump in the request body instead of reading `save.json` off the server's
+// disk, and only swaps it into the locked `Db` once it parses in full (`load_from_json` itself
+// parses into a scratch value before replacing `self`, so a failed parse never leaves a partial
+// dump in place).
+#[tracing::instrument(skip(_username, db, body), fields(outcome = tracing::field::Empty))]
+async fn import(
+    _username: String,
+    db: Db,
+    body: bytes::Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let contents = match std::str::from_utf8(&body) {
+        Ok(contents) => contents,
+        Err(_) => {
+            tracing::warn!("refused to import: body is not valid UTF-8");
+            tracing::Span::current().record("outcome", &"malformed");
+            return Ok(FailureResponse::reply(ErrorCode::MalformedData).into_response());
+        }
     };
-    *db = new_db;
-    Ok(warp::reply::json(&"ok".to_string()))
+
+    let mut db = filters::timed_write(&db).await;
+
+    match db.load_from_json(contents) {
+        Ok(()) => {
+            tracing::warn!("database replaced from an imported dump");
+            tracing::Span::current().record("outcome", &"success");
+            Ok(warp::reply::json(&SimpleSuccessResponse::new()).into_response())
+        }
+        Err(error) => {
+            tracing::warn!(%error, "refused to import: malformed dump");
+            tracing::Span::current().record("outcome", &"malformed");
+            Ok(ImportErrorResponse::reply(error).into_response())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImportUntisResponse {
+    status: &'static str,
+    report: db::ImportReport,
+}
+
+// Bootstraps the database from an offline Untis JSON-RPC dump instead of only `seed_db`.
+#[tracing::instrument(skip(_username, db, body))]
+async fn import_untis(
+    _username: String,
+    db: Db,
+    body: bytes::Bytes,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let mut db = filters::timed_write(&db).await;
+    let report = db.import_untis(&body[..]);
+
+    tracing::info!(
+        created = report.created,
+        skipped = report.skipped,
+        unresolved = report.unresolved.len(),
+        "imported an Untis dump"
+    );
+
+    Ok(warp::reply::json(&ImportUntisResponse {
+        status: "success",
+        report,
+    }))
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+// Locking the DB and running a trivial query is enough to tell whether the server is actually
+// able to serve requests, as opposed to only answering pings.
+#[tracing::instrument(skip(db))]
+async fn health(db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
+    db.stats();
+
+    Ok(warp::reply::json(&HealthResponse { status: "success" }))
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    status: &'static str,
+    name: &'static str,
+    version: &'static str,
+}
+
+async fn version() -> Result<impl warp::Reply, std::convert::Infallible> {
+    Ok(warp::reply::json(&VersionResponse {
+        status: "success",
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    status: &'static str,
+    stats: Stats,
+}
+
+#[tracing::instrument(skip(db))]
+async fn stats(db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
+
+    Ok(warp::reply::json(&StatsResponse {
+        status: "success",
+        stats: db.stats(),
+    }))
+}
+
+// Prometheus text-format exposition of request counters/latency (updated on every request by
+// `filters::instrument`) plus a snapshot of row counts and teaching-service hours, refreshed here
+// since those need the locked DB and aren't worth recomputing on every single request.
+#[tracing::instrument(skip(db, metrics))]
+async fn metrics_handler(
+    db: Db,
+    metrics: Metrics,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db = filters::timed_read(&db).await;
+
+    let stats = db.stats();
+    metrics.db_rows.with_label_values(&["users"]).set(stats.users as i64);
+    metrics.db_rows.with_label_values(&["classes"]).set(stats.classes as i64);
+    metrics.db_rows.with_label_values(&["subjects"]).set(stats.subjects as i64);
+    metrics.db_rows.with_label_values(&["classrooms"]).set(stats.classrooms as i64);
+    metrics.db_rows.with_label_values(&["occupancies"]).set(stats.occupancies as i64);
+
+    let occupancies_list = db.occupancies_list(None, None);
+    let occupancies: Vec<&db::models::Occupancy> =
+        occupancies_list.iter().map(|o| &**o).collect();
+    let service = count_hours(&occupancies);
+
+    metrics.service_hours.with_label_values(&["cm"]).set(service.cm as f64);
+    metrics.service_hours.with_label_values(&["td"]).set(service.td as f64);
+    metrics.service_hours.with_label_values(&["tp"]).set(service.tp as f64);
+    metrics.service_hours.with_label_values(&["projet"]).set(service.projet as f64);
+    metrics
+        .service_hours
+        .with_label_values(&["administration"])
+        .set(service.administration as f64);
+    metrics
+        .service_hours
+        .with_label_values(&["external"])
+        .set(service.external as f64);
+
+    Ok(warp::reply::with_header(
+        metrics.encode(),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
 }
@@ -1,6 +1,7 @@
 use warp::{Filter, Rejection, Reply};
 
-use db::Db;
+use db::{ids::Ids, Db};
+use filters::Metrics;
 
 mod auth;
 mod class;
@@ -8,26 +9,28 @@ mod classroom;
 mod globals;
 mod manage;
 mod occupancy;
+mod openapi;
 mod profile;
 mod student;
 mod subject;
 mod teacher;
+mod webuntis_import;
 
-pub use globals::{ErrorCode, FailureResponse};
+pub use globals::{ErrorCode, FailureResponse, InternalError};
 
-pub fn routes(db: &Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let crash = warp::path!("api" / "crash")
-        .and(warp::get())
-        .map(|| -> &'static str { panic!("This is a sample crash.") });
-
-    manage::routes(db)
+pub fn routes(
+    db: &Db,
+    metrics: &Metrics,
+    ids: &Ids,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    manage::routes(db, metrics)
         .or(auth::routes(db))
         .or(profile::routes(db))
-        .or(classroom::routes(db))
-        .or(class::routes(db))
+        .or(classroom::routes(db, ids))
+        .or(class::routes(db, ids))
         .or(teacher::routes(db))
         .or(student::routes(db))
-        .or(subject::routes(db))
-        .or(occupancy::routes(db))
-        .or(crash)
+        .or(subject::routes(db, metrics))
+        .or(occupancy::routes(db, metrics))
+        .or(webuntis_import::routes(db))
 }
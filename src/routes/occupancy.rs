@@ -1,21 +1,59 @@
+use futures::stream::unfold;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
 use super::{
-    globals::{OccupanciesListResponse, OccupanciesRequest, SimpleSuccessResponse},
+    globals::{
+        ForceQuery, OccupanciesListResponse, OccupanciesRequest, OccupancyConflictResponse,
+        SimpleSuccessResponse,
+    },
     ErrorCode, FailureResponse,
 };
-use db::{Database, Db, LockedDb, OccupancyUpdate};
-use filters::{authed, authed_is_of_kind, delayed, with_db, PossibleUserKind};
+use db::{
+    models::{OccupancyType, Recurrence, Substitution, UserKind},
+    Database, Db, HumanDatetime, LockedDb, ModificationEvent, NewOccupancy, OccupancyUpdate,
+};
+use filters::{authed_is_of_kind, delayed, with_db, with_metrics, Metrics, PossibleUserKind};
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
-pub fn routes(db: &Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+/// How long `GET /api/occupancies/changes` blocks waiting for a new modification before
+/// returning an empty result for the client to poll again.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+pub fn routes(
+    db: &Db,
+    metrics: &Metrics,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let get_route = warp::path!("api" / "occupancies")
         .and(warp::get())
         .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(with_metrics(metrics.clone()))
         .and(warp::query::<OccupanciesRequest>())
         .and_then(get)
         .and(delayed(db))
         .boxed();
 
+    let get_effective_route = warp::path!("api" / "occupancies" / "effective")
+        .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::query::<OccupanciesRequest>())
+        .and_then(get_effective)
+        .and(delayed(db))
+        .boxed();
+
+    let create_route = warp::path!("api" / "occupancies")
+        .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
+        .and_then(create)
+        .and(delayed(db))
+        .boxed();
+
     // TODO: deletion constraints
     let delete_route = warp::path!("api" / "occupancies")
         .and(warp::delete())
@@ -28,28 +66,411 @@ pub fn routes(db: &Db) -> impl Filter<Extract = impl Reply, Error = Rejection> +
 
     let update_route = warp::path!("api" / "occupancies" / u32)
         .and(warp::put())
-        .and(authed(db))
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
+        .and(warp::query::<ForceQuery>())
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(update)
         .and(delayed(db))
         .boxed();
 
-    get_route.or(delete_route).or(update_route)
+    let batch_route = warp::path!("api" / "occupancies" / "batch")
+        .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 64).and(warp::body::json()))
+        .and_then(batch)
+        .and(delayed(db))
+        .boxed();
+
+    let ics_route = warp::path!("api" / "occupancies.ics")
+        .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::query::<OccupanciesRequest>())
+        .and_then(ics)
+        .and(delayed(db))
+        .boxed();
+
+    let changes_route = warp::path!("api" / "occupancies" / "changes")
+        .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::query::<ChangesQuery>())
+        .and_then(changes)
+        .and(delayed(db))
+        .boxed();
+
+    let stream_route = warp::path!("api" / "occupancies" / "stream")
+        .and(warp::get())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and_then(stream)
+        .and(delayed(db))
+        .boxed();
+
+    let print_route = warp::path!("api" / "occupancies" / "print")
+        .and(warp::get())
+        .and(authed_is_of_kind(
+            db,
+            &[PossibleUserKind::Administrator, PossibleUserKind::Teacher],
+        ))
+        .and(with_db(db.clone()))
+        .and(warp::query::<PrintableTimetableRequest>())
+        .and_then(print)
+        .and(delayed(db))
+        .boxed();
+
+    get_route
+        .or(get_effective_route)
+        .or(create_route)
+        .or(delete_route)
+        .or(update_route)
+        .or(batch_route)
+        .or(ics_route)
+        .or(changes_route)
+        .or(stream_route)
+        .or(print_route)
+}
+
+#[derive(Serialize)]
+struct PaginatedOccupanciesResponse<'a> {
+    #[serde(flatten)]
+    inner: OccupanciesListResponse<'a>,
+    /// `from_id` to pass on the next request to continue past this page, or `None` once the
+    /// whole range has been returned.
+    next_cursor: Option<u32>,
 }
 
+#[tracing::instrument(skip(db, metrics, request), fields(result_count = tracing::field::Empty))]
 async fn get(
     _username: String,
     db: Db,
+    metrics: Metrics,
     request: OccupanciesRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db: LockedDb = db.lock().await;
+    let db: LockedDb = filters::timed_read(&db).await;
+
+    let mut occupancies_list = db.occupancies_list(request.start, request.end);
+    occupancies_list.sort_by_key(|o| (o.start_datetime, o.id));
+
+    if let Some(from_id) = request.from_id {
+        if let Some(pos) = occupancies_list.iter().position(|o| o.id == from_id) {
+            occupancies_list.drain(..=pos);
+        }
+    }
+
+    let next_cursor = request.limit.and_then(|limit| {
+        let limit = limit as usize;
+
+        if occupancies_list.len() > limit {
+            occupancies_list.truncate(limit);
+            occupancies_list.last().map(|o| o.id)
+        } else {
+            None
+        }
+    });
+
+    tracing::Span::current().record("result_count", &occupancies_list.len());
+    metrics.record_occupancies_returned("occupancies_get", occupancies_list.len());
+
+    let inner =
+        OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
+
+    Ok(warp::reply::json(&PaginatedOccupanciesResponse {
+        inner,
+        next_cursor,
+    }))
+}
+
+#[derive(Serialize)]
+struct EffectiveOccupancyElement<'a> {
+    pub id: u32,
+    pub classroom_id: Option<u32>,
+    pub group_number: Option<u32>,
+    pub subject_id: Option<u32>,
+    pub teacher_id: u32,
+    pub start: u64,
+    pub end: u64,
+    pub occupancy_type: &'a OccupancyType,
+    pub name: &'a str,
+    pub substitution: &'a Option<Substitution>,
+}
+
+#[derive(Serialize)]
+struct EffectiveOccupanciesResponse<'a> {
+    status: &'static str,
+    occupancies: Vec<EffectiveOccupancyElement<'a>>,
+}
+
+// Substitution-board view: like `get`, but with the latest room/teacher/time change or
+// cancellation overlaid on each occurrence instead of the raw, unmodified timetable.
+async fn get_effective(
+    _username: String,
+    db: Db,
+    request: OccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db: LockedDb = filters::timed_read(&db).await;
+
+    let occupancies_list = db.occupancies_list_effective(request.start, request.end);
+
+    let occupancies = occupancies_list
+        .iter()
+        .map(|o| EffectiveOccupancyElement {
+            id: o.id,
+            classroom_id: o.classroom_id,
+            group_number: o.group_number,
+            subject_id: o.subject_id,
+            teacher_id: o.teacher_id,
+            start: o.start_datetime,
+            end: o.end_datetime,
+            occupancy_type: &o.occupancy_type,
+            name: &o.name,
+            substitution: &o.substitution,
+        })
+        .collect();
+
+    Ok(warp::reply::json(&EffectiveOccupanciesResponse {
+        status: "success",
+        occupancies,
+    }))
+}
+
+async fn ics(
+    _username: String,
+    db: Db,
+    request: OccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db: LockedDb = filters::timed_read(&db).await;
 
     let occupancies_list = db.occupancies_list(request.start, request.end);
     let response =
         OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
 
-    Ok(warp::reply::json(&response))
+    Ok(warp::reply::with_header(
+        response.to_ics(),
+        "content-type",
+        "text/calendar; charset=utf-8",
+    ))
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ChangesQuery {
+    pub since: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ChangesResponse {
+    status: &'static str,
+    modifications: Vec<ModificationEvent>,
+}
+
+// Key-value-store-style long poll: returns immediately with whatever happened since `since`, or,
+// if nothing has yet, blocks up to `LONG_POLL_TIMEOUT` for the next modification before replying
+// with an empty list for the client to poll again. Lets dashboards stay live without re-fetching
+// the full occupancy list on a tight interval.
+async fn changes(
+    _username: String,
+    db: Db,
+    query: ChangesQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let buffered = {
+        let db = filters::timed_read(&db).await;
+        db.occupancies_modifications_since(query.since)
+    };
+
+    if !buffered.is_empty() {
+        return Ok(warp::reply::json(&ChangesResponse {
+            status: "success",
+            modifications: buffered,
+        }));
+    }
+
+    let mut receiver = {
+        let db = filters::timed_read(&db).await;
+        db.occupancies_modifications_subscribe()
+    };
+
+    let modifications = match tokio::time::timeout(LONG_POLL_TIMEOUT, receiver.recv()).await {
+        Ok(Ok(event)) => vec![event],
+        // Timed out, or the feed was closed/lagged behind: either way, the client just polls
+        // again with the same `since` it already has.
+        Ok(Err(_)) | Err(_) => Vec::new(),
+    };
+
+    Ok(warp::reply::json(&ChangesResponse {
+        status: "success",
+        modifications,
+    }))
+}
+
+// Server-Sent-Events variant of `changes`: pushes every `ModificationEvent` as it happens instead
+// of requiring the client to re-poll.
+async fn stream(
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let receiver = {
+        let db = filters::timed_read(&db).await;
+        db.occupancies_modifications_subscribe()
+    };
+
+    let events = unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let sse_event = warp::sse::Event::default()
+                        .json_data(&event)
+                        .unwrap_or_else(|_| warp::sse::Event::default());
+                    return Some((Ok::<_, Infallible>(sse_event), receiver));
+                }
+                // A lagging receiver just means it missed some events; skip ahead and keep
+                // streaming rather than ending the connection.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+#[derive(Deserialize, Debug)]
+struct PrintableTimetableRequest {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub teacher_id: Option<u32>,
+    pub class_id: Option<u32>,
+}
+
+async fn print(
+    _username: String,
+    db: Db,
+    request: PrintableTimetableRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db: LockedDb = filters::timed_read(&db).await;
+
+    let occupancies_list = db.occupancies_list(request.start, request.end);
+
+    let occupancies_list = occupancies_list
+        .into_iter()
+        .filter(|o| {
+            if let Some(teacher_id) = request.teacher_id {
+                if o.teacher_id != teacher_id {
+                    return false;
+                }
+            }
+
+            if let Some(class_id) = request.class_id {
+                let subject_class_id = o
+                    .subject_id
+                    .and_then(|subject_id| db.subject_get(subject_id))
+                    .map(|subject| subject.class_id);
+
+                if subject_class_id != Some(class_id) {
+                    return false;
+                }
+            }
+
+            true
+        })
+        .collect();
+
+    let title = match (request.teacher_id, request.class_id) {
+        (Some(teacher_id), _) => db
+            .user_get_teacher_by_id(teacher_id)
+            .map(|u| format!("Emploi du temps - {}", u.full_name()))
+            .unwrap_or_else(|| "Emploi du temps".to_string()),
+        (_, Some(class_id)) => db
+            .class_get(class_id)
+            .map(|c| format!("Emploi du temps - {}", c.name))
+            .unwrap_or_else(|| "Emploi du temps".to_string()),
+        _ => "Emploi du temps".to_string(),
+    };
+
+    let response = OccupanciesListResponse::from_list(&db, occupancies_list, None);
+
+    Ok(warp::reply::html(response.to_printable_html(&title)))
+}
+
+#[derive(Deserialize)]
+struct OccupancyCreationRequest {
+    pub classroom_id: Option<u32>,
+    pub group_number: Option<u32>,
+    pub subject_id: Option<u32>,
+    pub teacher_id: u32,
+    pub start: HumanDatetime,
+    pub end: HumanDatetime,
+    pub occupancy_type: OccupancyType,
+    pub name: String,
+    pub recurrence: Option<Recurrence>,
+}
+
+#[derive(Serialize)]
+struct OccupancyCreatedResponse {
+    status: &'static str,
+    id: u32,
+}
+
+/// Unlike `subject::occupancies_create`/`occupancies_groups_create`, this one isn't scoped to an
+/// already-known subject or group: `subject_id`/`group_number` are optional here (an
+/// `Administration`/`External` occupancy may carry neither), so it validates them itself with the
+/// same `db.subject_get(...).is_none()` → `ErrorCode::InvalidID` pattern those use, rather than
+/// going through `validate_new_occupancy_base`, which requires a subject.
+async fn create(
+    _username: String,
+    db: Db,
+    request: OccupancyCreationRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = filters::timed_write(&db).await;
+
+    if let Some(subject_id) = request.subject_id {
+        if db.subject_get(subject_id).is_none() {
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+
+        if let Some(group_number) = request.group_number {
+            let subject = db.subject_get(subject_id).expect("checked to exist above");
+
+            if group_number >= subject.group_count {
+                return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+            }
+        }
+    } else if request.group_number.is_some() {
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+    }
+
+    if db.user_get_teacher_by_id(request.teacher_id).is_none() {
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+    }
+
+    if let Some(classroom_id) = request.classroom_id {
+        if db.classroom_get(classroom_id).is_none() {
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+        }
+    }
+
+    let occupancy = NewOccupancy {
+        classroom_id: request.classroom_id,
+        group_number: request.group_number,
+        subject_id: request.subject_id,
+        teacher_id: request.teacher_id,
+        start_datetime: request.start.0,
+        end_datetime: request.end.0,
+        occupancy_type: request.occupancy_type,
+        name: request.name,
+        recurrence: request.recurrence,
+    };
+
+    let id = match db.occupancies_add_checked(occupancy) {
+        Ok(id) => id,
+        Err(conflicts) => return Ok(OccupancyConflictResponse::reply(conflicts)),
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&OccupancyCreatedResponse { status: "ok", id }),
+        StatusCode::CREATED,
+    ))
 }
 
 async fn delete(
@@ -57,7 +478,7 @@ async fn delete(
     db: Db,
     request: Vec<u32>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     if db.occupancies_remove(&request) {
         Ok(warp::reply::with_status(
@@ -65,24 +486,34 @@ async fn delete(
             StatusCode::OK,
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
 
 async fn update(
     id: u32,
-    _username: String,
+    username: String,
     db: Db,
+    force: ForceQuery,
     request: OccupancyUpdate,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
-    // TODO: VALIDATION
+    // `?force=true` is only honored for administrators; anyone else always goes through the
+    // conflict-checked path below, force flag or not.
+    let is_admin = db
+        .user_get(&username)
+        .map(|user| matches!(user.kind, UserKind::Administrator))
+        .unwrap_or(false);
 
-    let status = db.occupancies_update(id, request);
+    let status = if force.force && is_admin {
+        db.occupancies_update(id, request)
+    } else {
+        match db.occupancies_update_checked(id, request) {
+            Ok(status) => status,
+            Err(conflicts) => return Ok(OccupancyConflictResponse::reply(conflicts)),
+        }
+    };
 
     if status.found {
         Ok(warp::reply::with_status(
@@ -94,9 +525,81 @@ async fn update(
             },
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
+
+#[derive(Deserialize)]
+struct BatchOccupancyAdd {
+    pub classroom_id: Option<u32>,
+    pub group_number: Option<u32>,
+    pub subject_id: Option<u32>,
+    pub teacher_id: u32,
+    pub start: HumanDatetime,
+    pub end: HumanDatetime,
+    pub occupancy_type: OccupancyType,
+    pub name: String,
+    pub recurrence: Option<Recurrence>,
+}
+
+#[derive(Deserialize)]
+struct BatchOccupancyUpdate {
+    pub id: u32,
+    #[serde(flatten)]
+    pub update: OccupancyUpdate,
+}
+
+#[derive(Deserialize)]
+struct OccupanciesBatchRequest {
+    #[serde(default)]
+    pub add: Vec<BatchOccupancyAdd>,
+    #[serde(default)]
+    pub update: Vec<BatchOccupancyUpdate>,
+    #[serde(default)]
+    pub remove: Vec<u32>,
+}
+
+// All-or-nothing version of create/update/delete: either every item in `request` is valid and
+// free of conflicts and the whole batch lands, or nothing does. Unlike calling
+// `occupancies_add_checked`/`occupancies_update_checked` one at a time, there's no `?force=true`
+// escape hatch here — a batch is rejected wholesale rather than partially applied.
+async fn batch(
+    _username: String,
+    db: Db,
+    request: OccupanciesBatchRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = filters::timed_write(&db).await;
+
+    let add = request
+        .add
+        .into_iter()
+        .map(|item| NewOccupancy {
+            classroom_id: item.classroom_id,
+            group_number: item.group_number,
+            subject_id: item.subject_id,
+            teacher_id: item.teacher_id,
+            start_datetime: item.start.0,
+            end_datetime: item.end.0,
+            occupancy_type: item.occupancy_type,
+            name: item.name,
+            recurrence: item.recurrence,
+        })
+        .collect();
+
+    let update = request
+        .update
+        .into_iter()
+        .map(|item| (item.id, item.update))
+        .collect();
+
+    let report = db.occupancies_batch(add, update, request.remove);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&report),
+        if report.committed {
+            StatusCode::OK
+        } else {
+            StatusCode::CONFLICT
+        },
+    ))
+}
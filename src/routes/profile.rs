@@ -1,14 +1,35 @@
+use bytes::Buf;
+use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
 use super::globals::{ErrorCode, FailureResponse, SimpleSuccessResponse};
 use db::Database;
 use db::{
-    models::{ModificationType, OccupancyType},
+    models::{Avatar, ModificationType, OccupancyType},
     Db,
 };
 use filters::{authed, delayed, with_db, Malformed, Unauthorized};
 
+/// Upper bound on a multipart avatar upload's total size, enforced by
+/// `warp::multipart::form().max_length` before any of it is buffered in memory.
+const MAX_AVATAR_UPLOAD_BYTES: u64 = 5 * 1024 * 1024;
+
+/// A decoded upload wider or taller than this (regardless of its compressed file size) is
+/// rejected, to guard against decompression-bomb-style uploads that are small on the wire but
+/// balloon once decoded.
+const MAX_DECODED_AVATAR_DIMENSION: u32 = 8192;
+
+/// The longest side a stored `Avatar::full` is allowed to be; uploads bigger than this are
+/// downscaled (never upscaled) to it.
+const MAX_AVATAR_DIMENSION: u32 = 1024;
+
+/// Side length of the square `Avatar::thumbnail`.
+const AVATAR_THUMBNAIL_SIZE: u32 = 128;
+
+/// Both `Avatar` variants are always re-encoded to this format, regardless of what was uploaded.
+const AVATAR_CONTENT_TYPE: &str = "image/jpeg";
+
 pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let put_profile_route = warp::path!("api" / "profile")
         .and(warp::put())
@@ -28,23 +49,64 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
             .and(delayed(db))
             .boxed();
 
-    put_profile_route.or(last_occupancies_modifications_route)
+    let post_avatar_route = warp::path!("api" / "profile" / "avatar")
+        .and(warp::post())
+        .and(authed(db))
+        .and(warp::multipart::form().max_length(MAX_AVATAR_UPLOAD_BYTES))
+        .and(with_db(db.clone()))
+        .and_then(post_avatar)
+        .and(delayed(db))
+        .boxed();
+
+    let get_avatar_route = warp::path!("api" / "profile" / "avatar" / u32)
+        .and(warp::get())
+        .and(authed(db))
+        .and(with_db(db.clone()))
+        .and_then(get_avatar)
+        .boxed();
+
+    let get_avatar_thumbnail_route = warp::path!("api" / "profile" / "avatar" / u32 / "thumbnail")
+        .and(warp::get())
+        .and(authed(db))
+        .and(with_db(db.clone()))
+        .and_then(get_avatar_thumbnail)
+        .boxed();
+
+    put_profile_route
+        .or(last_occupancies_modifications_route)
+        .or(post_avatar_route)
+        .or(get_avatar_route)
+        .or(get_avatar_thumbnail_route)
 }
 
-#[derive(Deserialize)]
-struct UpdateRequest {
+#[derive(Deserialize, utoipa::ToSchema)]
+pub struct UpdateRequest {
     old_password: Option<String>,
     password: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
 }
 
-async fn put_profile(
+/// Updates the logged-in user's own profile: password (with the current one for confirmation),
+/// and for administrators, first and last name.
+#[utoipa::path(
+    put,
+    path = "/api/profile",
+    request_body = UpdateRequest,
+    responses(
+        (status = 200, description = "Profile updated", body = SimpleSuccessResponse),
+        (status = 204, description = "Nothing to update"),
+        (status = 403, description = "Current password is incorrect, or not allowed to set first/last name"),
+    ),
+    security(("bearerAuth" = [])),
+)]
+#[tracing::instrument(skip(username, request, db), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn put_profile(
     username: String,
     request: UpdateRequest,
     db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let mut user = db
         .user_get(&username)
@@ -55,6 +117,8 @@ async fn put_profile(
     if !user.kind.is_administrator()
         && (request.first_name.is_some() || request.last_name.is_some())
     {
+        tracing::warn!("refused to update first/last name: not an administrator");
+        tracing::Span::current().record("outcome", &"forbidden");
         return Err(warp::reject::custom(Unauthorized {}));
     }
 
@@ -62,18 +126,26 @@ async fn put_profile(
 
     match (request.old_password, request.password) {
         (Some(old_password), Some(password)) => {
-            if user.password != old_password {
-                return Ok(warp::reply::with_status(
-                    FailureResponse::new_reply(ErrorCode::InvalidOldPassword),
-                    StatusCode::FORBIDDEN,
-                ));
+            match db::auth::verify_password(&old_password, &user.password) {
+                db::auth::PasswordCheck::Invalid => {
+                    tracing::warn!("refused to update password: current password is incorrect");
+                    tracing::Span::current().record("outcome", &"invalid_old_password");
+                    return Ok(FailureResponse::reply(ErrorCode::InvalidOldPassword));
+                }
+                // Either way the new password is about to overwrite `user.password` below, so
+                // there's nothing further to do for the `ValidNeedsRehash` migration here.
+                db::auth::PasswordCheck::Valid | db::auth::PasswordCheck::ValidNeedsRehash(_) => {}
             }
 
-            user.password = password;
+            user.password = db::auth::hash_password(&password);
             modified = true;
         }
         // Check for provided password without old_password (or the inverse)
         (None, Some(_)) | (Some(_), None) => {
+            tracing::warn!(
+                "refused to update: password given without old_password (or the inverse)"
+            );
+            tracing::Span::current().record("outcome", &"malformed");
             return Err(warp::reject::custom(Malformed {}));
         }
         _ => {}
@@ -93,6 +165,8 @@ async fn put_profile(
         db.user_update(user);
     }
 
+    tracing::Span::current().record("outcome", &if modified { "success" } else { "noop" });
+
     // Return a 204 if the content didn't change
     let status_code = if modified {
         StatusCode::OK
@@ -106,20 +180,20 @@ async fn put_profile(
     ))
 }
 
-#[derive(Serialize)]
-struct LastOccupanciesModificationsResponse {
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct LastOccupanciesModificationsResponse {
     status: &'static str,
     modifications: Vec<ModificationResponse>,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, utoipa::ToSchema)]
 pub struct ModificationResponse {
     pub modification_type: ModificationType,
     pub modification_timestamp: u64,
     pub occupancy: ModificationOccupancyResponse,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, utoipa::ToSchema)]
 pub struct ModificationOccupancyResponse {
     pub subject_name: Option<String>,
     pub class_name: Option<String>,
@@ -130,11 +204,22 @@ pub struct ModificationOccupancyResponse {
     pub previous_occupancy_end: u64,
 }
 
-async fn last_occupancies_modifications(
+/// Lists the logged-in user's most recent timetable modifications (room changes, substitutions,
+/// cancellations, ...), newest first.
+#[utoipa::path(
+    get,
+    path = "/api/profile/last-occupancies-modifications",
+    responses(
+        (status = 200, description = "Recent modifications", body = LastOccupanciesModificationsResponse),
+    ),
+    security(("bearerAuth" = [])),
+)]
+#[tracing::instrument(skip(username, db))]
+pub(crate) async fn last_occupancies_modifications(
     username: String,
     db: Db,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
     let user = db.user_get(&username).expect("should be a valid reference");
 
     let modifications = db.last_occupancies_modifications(user.id);
@@ -171,3 +256,198 @@ async fn last_occupancies_modifications(
             .collect(),
     }))
 }
+
+/// Reads the first `avatar`-named part of a multipart upload into memory, decodes it, rejects it
+/// if it's not a valid image or decodes to something absurdly large, and stores both re-encoded
+/// variants for `username`.
+#[utoipa::path(
+    post,
+    path = "/api/profile/avatar",
+    request_body(content = Vec<u8>, description = "Multipart form with an `avatar` file part", content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Avatar stored", body = SimpleSuccessResponse),
+        (status = 400, description = "Not a valid image, or too large once decoded", body = FailureResponse),
+    ),
+    security(("bearerAuth" = [])),
+)]
+#[tracing::instrument(skip(username, form, db), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn post_avatar(
+    username: String,
+    mut form: warp::multipart::FormData,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut bytes = Vec::new();
+
+    while let Some(mut part) = form
+        .try_next()
+        .await
+        .map_err(|_| warp::reject::custom(Malformed {}))?
+    {
+        if part.name() != "avatar" {
+            continue;
+        }
+
+        while let Some(chunk) = part
+            .data()
+            .await
+            .transpose()
+            .map_err(|_| warp::reject::custom(Malformed {}))?
+        {
+            bytes.extend_from_slice(chunk.bytes());
+        }
+
+        break;
+    }
+
+    if bytes.is_empty() {
+        tracing::warn!("refused to set avatar: no `avatar` part in the upload");
+        tracing::Span::current().record("outcome", &"malformed");
+        return Err(warp::reject::custom(Malformed {}));
+    }
+
+    let image = match image::load_from_memory(&bytes) {
+        Ok(image) => image,
+        Err(_) => {
+            tracing::warn!("refused to set avatar: not a valid image");
+            tracing::Span::current().record("outcome", &"invalid_image");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidImage));
+        }
+    };
+
+    if image.width() > MAX_DECODED_AVATAR_DIMENSION || image.height() > MAX_DECODED_AVATAR_DIMENSION
+    {
+        tracing::warn!(
+            width = image.width(),
+            height = image.height(),
+            "refused to set avatar: decoded image is too large"
+        );
+        tracing::Span::current().record("outcome", &"image_too_large");
+        return Ok(FailureResponse::reply(ErrorCode::ImageTooLarge));
+    }
+
+    let mut db = filters::timed_write(&db).await;
+    let user = db
+        .user_get(&username)
+        .expect("checked username should be valid");
+    db.avatar_set(user.id, reencode(image));
+
+    tracing::Span::current().record("outcome", &"success");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&SimpleSuccessResponse::new()),
+        StatusCode::OK,
+    ))
+}
+
+/// Normalizes a decoded upload into the two variants `Avatar` stores: a full-size copy clamped to
+/// `MAX_AVATAR_DIMENSION` on its longest side, and an `AVATAR_THUMBNAIL_SIZE` square center-cropped
+/// out of that clamped copy. Re-encoding (rather than storing the upload as-is) strips whatever
+/// metadata it carried (EXIF, ICC profiles, ...) and bounds how much space one user's avatar can
+/// take up regardless of the format or resolution they uploaded.
+fn reencode(image: image::DynamicImage) -> Avatar {
+    let full = if image.width() > MAX_AVATAR_DIMENSION || image.height() > MAX_AVATAR_DIMENSION {
+        image.resize(
+            MAX_AVATAR_DIMENSION,
+            MAX_AVATAR_DIMENSION,
+            image::imageops::FilterType::Lanczos3,
+        )
+    } else {
+        image
+    };
+
+    let side = full.width().min(full.height());
+    let x = (full.width() - side) / 2;
+    let y = (full.height() - side) / 2;
+
+    let thumbnail = full.crop_imm(x, y, side, side).resize_exact(
+        AVATAR_THUMBNAIL_SIZE,
+        AVATAR_THUMBNAIL_SIZE,
+        image::imageops::FilterType::Lanczos3,
+    );
+
+    Avatar {
+        content_type: AVATAR_CONTENT_TYPE,
+        full: encode_jpeg(&full),
+        thumbnail: encode_jpeg(&thumbnail),
+    }
+}
+
+fn encode_jpeg(image: &image::DynamicImage) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image
+        .write_to(&mut bytes, image::ImageOutputFormat::Jpeg(85))
+        .expect("encoding a valid in-memory image to JPEG should not fail");
+    bytes
+}
+
+/// Serves a user's full-size avatar as a raw image, or a 404-shaped `FailureResponse` if they
+/// never uploaded one.
+#[utoipa::path(
+    get,
+    path = "/api/profile/avatar/{user_id}",
+    params(("user_id" = u32, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The avatar image", content_type = "image/jpeg"),
+        (status = 404, description = "This user has no avatar", body = FailureResponse),
+    ),
+    security(("bearerAuth" = [])),
+)]
+#[tracing::instrument(skip(_username, db), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn get_avatar(
+    user_id: u32,
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    match db.avatar_get(user_id) {
+        Some(avatar) => {
+            tracing::Span::current().record("outcome", &"success");
+            Ok(
+                warp::reply::with_header(avatar.full.clone(), "content-type", avatar.content_type)
+                    .into_response(),
+            )
+        }
+        None => {
+            tracing::Span::current().record("outcome", &"invalid_id");
+            Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response())
+        }
+    }
+}
+
+/// Serves a user's square thumbnail avatar, or a 404-shaped `FailureResponse` if they never
+/// uploaded one.
+#[utoipa::path(
+    get,
+    path = "/api/profile/avatar/{user_id}/thumbnail",
+    params(("user_id" = u32, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The avatar thumbnail", content_type = "image/jpeg"),
+        (status = 404, description = "This user has no avatar", body = FailureResponse),
+    ),
+    security(("bearerAuth" = [])),
+)]
+#[tracing::instrument(skip(_username, db), fields(outcome = tracing::field::Empty))]
+pub(crate) async fn get_avatar_thumbnail(
+    user_id: u32,
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    match db.avatar_get(user_id) {
+        Some(avatar) => {
+            tracing::Span::current().record("outcome", &"success");
+            Ok(warp::reply::with_header(
+                avatar.thumbnail.clone(),
+                "content-type",
+                avatar.content_type,
+            )
+            .into_response())
+        }
+        None => {
+            tracing::Span::current().record("outcome", &"invalid_id");
+            Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response())
+        }
+    }
+}
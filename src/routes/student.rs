@@ -5,16 +5,17 @@ use warp::{http::StatusCode, Filter, Rejection, Reply};
 use super::{
     globals::{
         AccountCreatedResponse, OccupanciesListResponse, OccupanciesRequest,
-        PaginatedQueryableListRequest, SimpleSuccessResponse,
+        PaginatedQueryableListRequest, PaginationMeta, SimpleSuccessResponse,
     },
-    ErrorCode, FailureResponse,
+    ErrorCode, FailureResponse, InternalError,
 };
 use db::{
+    auth::{Claims, Role},
     group_numbers,
     models::{OccupancyType, StudentInformations, UserKind},
-    Database, Db, NewUser,
+    Database, Db, LockedDb, NewUser, PAGE_SIZE,
 };
-use filters::{authed_is_of_kind, delayed, with_db, PossibleUserKind};
+use filters::{authed_claims, authed_is_of_kind, delayed, with_db, PossibleUserKind};
 
 pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let list_route = warp::path!("api" / "students")
@@ -49,10 +50,11 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
         .and(delayed(db))
         .boxed();
 
-    // TODO: missing auth??
-
+    // Admins, the student themselves, or a teacher who teaches them; `authed_claims` carries the
+    // id/role needed for that check directly, so the handlers don't need to re-look-up a username.
     let get_route = warp::path!("api" / "students" / u32)
         .and(warp::get())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and_then(get)
         .and(delayed(db))
@@ -60,6 +62,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let update_route = warp::path!("api" / "students" / u32)
         .and(warp::put())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(update)
@@ -68,6 +71,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let subjects_get_route = warp::path!("api" / "students" / u32 / "subjects")
         .and(warp::get())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and_then(subjects_get)
         .and(delayed(db))
@@ -75,6 +79,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let occupancies_get_route = warp::path!("api" / "students" / u32 / "occupancies")
         .and(warp::get())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and(warp::query::<OccupanciesRequest>())
         .and_then(occupancies_get)
@@ -95,6 +100,7 @@ struct ListResponse<'a> {
     status: &'static str,
     total: usize,
     students: Vec<Student<'a>>,
+    pagination: PaginationMeta,
 }
 
 #[derive(Serialize)]
@@ -105,15 +111,19 @@ struct Student<'a> {
     class_name: &'a str,
 }
 
+#[tracing::instrument(skip(db, request), fields(outcome = tracing::field::Empty))]
 async fn list(
     _username: String,
     db: Db,
     request: PaginatedQueryableListRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     let page = request.normalized_page_number();
-    let (total, users) = db.user_list(Some(page), request.query.as_deref(), |u| match u.kind {
+    let per_page = request.normalized_per_page();
+    let (total, users) = db.user_list(Some(page), per_page, request.query.as_deref(), |u| match u
+        .kind
+    {
         UserKind::Student(_) => true,
         UserKind::Administrator => false,
         UserKind::Teacher(_) => false,
@@ -123,25 +133,29 @@ async fn list(
         .into_iter()
         .map(|u| match &u.kind {
             UserKind::Student(informations) => {
-                let class = db
-                    .class_get(informations.class_id)
-                    .expect("invalid class_id in user informations");
+                let class = db.class_get(informations.class_id).ok_or_else(|| {
+                    InternalError("invalid class_id in user informations".to_string())
+                })?;
 
-                Student {
+                Ok(Student {
                     id: u.id,
                     first_name: &u.first_name,
                     last_name: &u.last_name,
                     class_name: &class.name,
-                }
+                })
             }
-            UserKind::Administrator => unreachable!(),
-            UserKind::Teacher(_) => unreachable!(),
+            UserKind::Administrator | UserKind::Teacher(_) => Err(InternalError(
+                "user_list(..., is Student) returned a non-student user".to_string(),
+            )),
         })
-        .collect();
+        .collect::<super::globals::Result<Vec<_>>>()?;
+
+    tracing::Span::current().record("outcome", &"success");
 
     Ok(warp::reply::json(&ListResponse {
         status: "success",
         total,
+        pagination: PaginationMeta::new(total, page, per_page.unwrap_or(PAGE_SIZE)),
         students,
     }))
 }
@@ -153,24 +167,28 @@ struct NewStudent {
     class_id: u32,
 }
 
+#[tracing::instrument(skip(db, request), fields(outcome = tracing::field::Empty))]
 async fn create(
     _username: String,
     db: Db,
     request: NewStudent,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let class = db.class_get(request.class_id);
 
     if class.is_none() {
-        return Ok(warp::reply::json(&FailureResponse::new(
-            ErrorCode::InvalidID,
-        )));
+        tracing::warn!(
+            class_id = request.class_id,
+            "refused to create student: no such class"
+        );
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let mut rng = rand::thread_rng();
 
-    let password = std::iter::repeat(())
+    let password: String = std::iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
         .take(10)
         .collect();
@@ -178,7 +196,7 @@ async fn create(
     let user = NewUser {
         first_name: request.first_name,
         last_name: request.last_name,
-        password,
+        password: password.clone(),
         kind: UserKind::Student(StudentInformations {
             class_id: request.class_id,
         }),
@@ -186,19 +204,26 @@ async fn create(
 
     let user = db.user_add(user);
 
-    Ok(warp::reply::json(&AccountCreatedResponse {
-        status: "success",
-        username: &user.username,
-        password: &user.password,
-    }))
+    tracing::info!(created_username = %user.username, "created student account");
+    tracing::Span::current().record("outcome", &"success");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&AccountCreatedResponse {
+            status: "success",
+            username: &user.username,
+            password: &password,
+        }),
+        StatusCode::OK,
+    ))
 }
 
+#[tracing::instrument(skip(db), fields(outcome = tracing::field::Empty))]
 async fn delete(
     _username: String,
     db: Db,
     request: Vec<u32>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let all_exist_and_student =
         request
@@ -210,19 +235,23 @@ async fn delete(
             });
 
     if !all_exist_and_student {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!(ids = ?request, "refused to delete: not all ids are existing students");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     if db.user_remove(&request) {
+        tracing::Span::current().record("outcome", &"success");
+
         Ok(warp::reply::with_status(
             warp::reply::json(&SimpleSuccessResponse::new()),
             StatusCode::OK,
         ))
     } else {
-        unreachable!("Since we checked that the users exist, they should be able to be removed")
+        Err(InternalError(
+            "user_remove failed for ids that were just confirmed to exist".to_string(),
+        )
+        .into())
     }
 }
 
@@ -240,8 +269,39 @@ struct GetResponseStudent<'a> {
     // TODO: total_hours + subjects
 }
 
-async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+/// Whether `claims` may view student `id`'s record: an administrator, the student themselves, or
+/// a teacher who teaches a subject the student is enrolled in.
+fn authorized_to_view(db: &LockedDb, claims: &Claims, id: u32) -> bool {
+    match claims.role {
+        Role::Administrator => true,
+        Role::Student => claims.sub == id,
+        Role::Teacher => db
+            .student_subjects(id)
+            .iter()
+            .any(|subject| db.teacher_teaches(claims.sub, subject.id)),
+    }
+}
+
+/// Whether `claims` may update student `id`'s record: an administrator, or the student themselves.
+/// Unlike [`authorized_to_view`], a teacher of the student's subjects does not qualify.
+fn authorized_to_update(claims: &Claims, id: u32) -> bool {
+    claims.role == Role::Administrator || claims.sub == id
+}
+
+#[tracing::instrument(skip(db, claims), fields(outcome = tracing::field::Empty))]
+async fn get(
+    id: u32,
+    claims: Claims,
+    db: Db,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let db: LockedDb = filters::timed_read(&db).await;
+
+    if !authorized_to_view(&db, &claims, id) {
+        tracing::warn!("not authorized to view this student");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
+
     let user = db.user_get_by_id(id);
 
     let res_student = match user {
@@ -257,17 +317,22 @@ async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallib
     };
 
     match res_student {
-        Some(res_student) => Ok(warp::reply::with_status(
-            warp::reply::json(&GetResponse {
-                status: "success",
-                student: res_student,
-            }),
-            StatusCode::OK,
-        )),
-        None => Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        )),
+        Some(res_student) => {
+            tracing::Span::current().record("outcome", &"success");
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&GetResponse {
+                    status: "success",
+                    student: res_student,
+                }),
+                StatusCode::OK,
+            ))
+        }
+        None => {
+            tracing::warn!("no such student");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            Ok(FailureResponse::reply(ErrorCode::InvalidID))
+        }
     }
 }
 
@@ -279,12 +344,28 @@ struct StudentUpdate {
     password: Option<String>,
 }
 
+#[tracing::instrument(skip(db, claims, request), fields(outcome = tracing::field::Empty))]
 async fn update(
     id: u32,
+    claims: Claims,
     db: Db,
     request: StudentUpdate,
-) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !authorized_to_update(&claims, id) {
+        tracing::warn!("not authorized to update this student");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
+
+    // Only an administrator may move a student to a different class; the student themselves is
+    // otherwise allowed to update their own record.
+    if request.class_id.is_some() && claims.role != Role::Administrator {
+        tracing::warn!("refused to let a non-administrator change their own class_id");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
+
+    let mut db = filters::timed_write(&db).await;
 
     let user = db.user_get_by_id(id).and_then(|user| match &user.kind {
         UserKind::Administrator => None,
@@ -295,10 +376,9 @@ async fn update(
     let mut user = match user {
         Some(user) => user,
         None => {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::NOT_FOUND,
-            ))
+            tracing::warn!("no such student");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
         }
     }
     .clone();
@@ -316,18 +396,21 @@ async fn update(
     }
 
     let mut informations = match &mut user.kind {
-        UserKind::Administrator => unreachable!(),
-        UserKind::Teacher(_) => unreachable!(),
+        UserKind::Administrator | UserKind::Teacher(_) => {
+            return Err(InternalError(
+                "user_get_by_id(..., is Student) returned a non-student user".to_string(),
+            )
+            .into())
+        }
         UserKind::Student(infos) => infos,
     };
 
     if let Some(class_id) = request.class_id {
         // Check that class exists
         if db.class_get(class_id).is_none() {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::NOT_FOUND,
-            ));
+            tracing::warn!(class_id, "refused to update: no such class");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
         }
 
         informations.class_id = class_id;
@@ -335,7 +418,7 @@ async fn update(
     }
 
     if let Some(value) = request.password {
-        user.password = value;
+        user.password = db::auth::hash_password(&value);
         updated = true;
     }
 
@@ -343,6 +426,8 @@ async fn update(
         db.user_update(user);
     }
 
+    tracing::Span::current().record("outcome", &if updated { "success" } else { "noop" });
+
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
         if updated {
@@ -384,14 +469,24 @@ struct GroupSubjectGetResponse {
     is_student_group: bool,
 }
 
-async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+#[tracing::instrument(skip(db, claims), fields(outcome = tracing::field::Empty))]
+async fn subjects_get(
+    id: u32,
+    claims: Claims,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db: LockedDb = filters::timed_read(&db).await;
+
+    if !authorized_to_view(&db, &claims, id) {
+        tracing::warn!("not authorized to view this student's subjects");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
 
     if db.user_get_student_by_id(id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!("no such student");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let student_subjects = db.student_subjects(id);
@@ -403,30 +498,31 @@ async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejecti
         // Eg: L3 Informatique
         let class_name = db
             .class_get(student_subject.class_id)
-            .expect("invalid class_id in user informations")
+            .ok_or_else(|| InternalError("invalid class_id in user informations".to_string()))?
             .name
             .to_string();
 
         // List of all teachers that teach this subject.
-        let subject_teachers: Vec<TeacherSubjectGetResponse> = db.user_list(None, None, |u| match u.kind {
+        let subject_teachers: Vec<TeacherSubjectGetResponse> = db.user_list(None, None, None, |u| match u.kind {
             UserKind::Student(_) => false,
             UserKind::Administrator => false,
             UserKind::Teacher(_) => true,
         } && db.teacher_teaches(u.id, student_subject.id)).1.iter().map(|u| {
             let informations = match &u.kind {
-                UserKind::Student(_) => unreachable!(),
-                UserKind::Administrator => unreachable!(),
-                UserKind::Teacher(informations) => informations,
-            };
+                UserKind::Student(_) | UserKind::Administrator => Err(InternalError(
+                    "user_list(..., is Teacher) returned a non-teacher user".to_string(),
+                )),
+                UserKind::Teacher(informations) => Ok(informations),
+            }?;
 
-            TeacherSubjectGetResponse {
+            Ok(TeacherSubjectGetResponse {
                 first_name: &u.first_name,
                 last_name: &u.last_name,
                 in_charge: db.teacher_in_charge(u.id, student_subject.id),
                 email: informations.email.as_deref(),
                 phone_number: informations.phone_number.as_deref(),
-            }
-        }).collect();
+            })
+        }).collect::<super::globals::Result<Vec<_>>>()?;
 
         let total_student_count: usize = db.subject_students(student_subject.id).len();
 
@@ -453,6 +549,8 @@ async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejecti
         });
     }
 
+    tracing::Span::current().record("outcome", &"success");
+
     return Ok(warp::reply::with_status(
         warp::reply::json(&SubjectGetResponseList {
             status: "success",
@@ -462,18 +560,25 @@ async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejecti
     ));
 }
 
+#[tracing::instrument(skip(db, claims, request), fields(outcome = tracing::field::Empty))]
 async fn occupancies_get(
     id: u32,
+    claims: Claims,
     db: Db,
     request: OccupanciesRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db: LockedDb = filters::timed_read(&db).await;
+
+    if !authorized_to_view(&db, &claims, id) {
+        tracing::warn!("not authorized to view this student's occupancies");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
 
     if db.user_get_student_by_id(id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!("no such student");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let student_subjects = db.student_subjects(id);
@@ -523,6 +628,8 @@ async fn occupancies_get(
     let response =
         OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
 
+    tracing::Span::current().record("outcome", &"success");
+
     Ok(warp::reply::with_status(
         warp::reply::json(&response),
         StatusCode::OK,
@@ -1,22 +1,29 @@
-use log;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
 
 use super::{
     globals::{
-        OccupanciesListResponse, OccupanciesRequest, PaginatedQueryableListRequest,
-        SimpleSuccessResponse,
+        ForceQuery, OccupanciesListResponse, OccupanciesRequest, OccupancyConflictResponse,
+        PaginatedQueryableListRequest, PaginationMeta, SimpleSuccessResponse,
     },
-    ErrorCode, FailureResponse,
+    ErrorCode, FailureResponse, InternalError,
 };
 use db::{
     group_numbers,
-    models::{OccupancyType, UserKind},
-    Database, Db, LockedDb, NewOccupancy, NewSubject, SubjectUpdate,
+    models::{OccupancyType, Recurrence, UserKind},
+    BatchItemResult, BatchItemStatus, ConcreteDb, Database, Db, HumanDatetime, NewOccupancy,
+    NewSubject, SubjectUpdate, PAGE_SIZE,
+};
+use filters::{
+    authed, authed_is_of_kind, correlation_id, delayed, with_db, with_metrics, Metrics,
+    PossibleUserKind,
 };
-use filters::{authed_is_of_kind, delayed, with_db, PossibleUserKind};
 
-pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn routes(
+    db: &Db,
+    metrics: &Metrics,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let list_route = warp::path!("api" / "subjects")
         .and(warp::get())
         .and(authed_is_of_kind(
@@ -58,6 +65,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let update_route = warp::path!("api" / "subjects" / u32)
         .and(warp::put())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(update)
@@ -66,6 +74,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let teacher_post_route = warp::path!("api" / "subjects" / u32 / "teachers")
         .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(teacher_post)
@@ -74,6 +83,8 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let teacher_delete_route = warp::path!("api" / "subjects" / u32 / "teachers")
         .and(warp::delete())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(correlation_id())
         .and(with_db(db.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(teacher_delete)
@@ -82,6 +93,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let group_post_route = warp::path!("api" / "subjects" / u32 / "groups")
         .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and_then(group_post)
         .and(delayed(db))
@@ -89,6 +101,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let group_delete_route = warp::path!("api" / "subjects" / u32 / "groups")
         .and(warp::delete())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
         .and(with_db(db.clone()))
         .and_then(group_delete)
         .and(delayed(db))
@@ -97,6 +110,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
     let occupancies_get_route = warp::path!("api" / "subjects" / u32 / "occupancies")
         .and(warp::get())
         .and(with_db(db.clone()))
+        .and(with_metrics(metrics.clone()))
         .and(warp::query::<OccupanciesRequest>())
         .and_then(occupancies_get)
         .and(delayed(db))
@@ -106,7 +120,9 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
     let occupancies_create_route = warp::path!("api" / "subjects" / u32 / "occupancies")
         .and(warp::post())
         .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(correlation_id())
         .and(with_db(db.clone()))
+        .and(warp::query::<ForceQuery>())
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(occupancies_create)
         .and(delayed(db))
@@ -117,7 +133,9 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
         warp::path!("api" / "subjects" / u32 / "groups" / u32 / "occupancies")
             .and(warp::post())
             .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+            .and(correlation_id())
             .and(with_db(db.clone()))
+            .and(warp::query::<ForceQuery>())
             .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
             .and_then(occupancies_groups_create)
             .and(delayed(db))
@@ -128,11 +146,33 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
             .and(warp::get())
             .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
             .and(with_db(db.clone()))
+            .and(with_metrics(metrics.clone()))
             .and(warp::query::<OccupanciesRequest>())
             .and_then(occupancies_group_get)
             .and(delayed(db))
             .boxed();
 
+    let occupancies_batch_get_route = warp::path!("api" / "subjects" / "occupancies" / "batch")
+        .and(warp::get())
+        .and(authed(db))
+        .and(with_db(db.clone()))
+        .and(with_metrics(metrics.clone()))
+        .and(warp::query::<BatchOccupanciesRequest>())
+        .and_then(occupancies_batch_get)
+        .and(delayed(db))
+        .boxed();
+
+    let occupancies_create_batch_route =
+        warp::path!("api" / "subjects" / u32 / "occupancies" / "batch")
+            .and(warp::post())
+            .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+            .and(with_db(db.clone()))
+            .and(warp::query::<ForceQuery>())
+            .and(warp::body::content_length_limit(1024 * 256).and(warp::body::json()))
+            .and_then(occupancies_create_batch)
+            .and(delayed(db))
+            .boxed();
+
     list_route
         .or(create_route)
         .or(delete_route)
@@ -146,6 +186,8 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
         .or(occupancies_create_route)
         .or(occupancies_groups_create_route)
         .or(occupancies_group_get_route)
+        .or(occupancies_create_batch_route)
+        .or(occupancies_batch_get_route)
 }
 
 #[derive(Serialize)]
@@ -153,6 +195,7 @@ struct ListResponse<'a> {
     status: &'static str,
     total: usize,
     subjects: Vec<ListResponseItem<'a>>, // TODO: remove group_count from here
+    pagination: PaginationMeta,
 }
 
 #[derive(Serialize)]
@@ -167,10 +210,11 @@ async fn list(
     db: Db,
     request: PaginatedQueryableListRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     let page = request.normalized_page_number();
-    let (total, subjects) = db.subject_list(page, request.query.as_deref(), |_| true);
+    let per_page = request.normalized_per_page();
+    let (total, subjects) = db.subject_list(page, per_page, request.query.as_deref(), |_| true);
 
     let subjects = subjects
         .iter()
@@ -187,6 +231,7 @@ async fn list(
     Ok(warp::reply::json(&ListResponse {
         status: "success",
         total,
+        pagination: PaginationMeta::new(total, page, per_page.unwrap_or(PAGE_SIZE)),
         subjects,
     }))
 }
@@ -196,13 +241,10 @@ async fn create(
     db: Db,
     request: NewSubject,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     if db.class_get(request.class_id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     db.subject_add(request);
@@ -218,7 +260,7 @@ async fn delete(
     db: Db,
     request: Vec<u32>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     if db.subject_remove(&request) {
         Ok(warp::reply::with_status(
@@ -226,10 +268,7 @@ async fn delete(
             StatusCode::OK,
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
 
@@ -263,28 +302,24 @@ struct GetResponseGroup {
     pub count: u32,
 }
 
-async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+async fn get(id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
 
     let total_student_count: usize = db.subject_students(id).len();
 
     let subject = match db.subject_get(id) {
         Some(u) => u,
         None => {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::NOT_FOUND,
-            ))
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID))
         }
     };
 
-    let class = match db.class_get(subject.class_id) {
-        Some(c) => c,
-        None => panic!("the class reference should be valid"),
-    };
+    let class = db
+        .class_get(subject.class_id)
+        .ok_or_else(|| InternalError("invalid class_id in subject informations".to_string()))?;
 
     let teachers: Vec<GetResponseTeacher> = db
-        .user_list(None, None, |u| match u.kind {
+        .user_list(None, None, None, |u| match u.kind {
             UserKind::Student(_) => false,
             UserKind::Administrator => false,
             UserKind::Teacher(_) => true,
@@ -326,28 +361,23 @@ async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallib
 
 async fn update(
     id: u32,
+    _username: String,
     db: Db,
     request: SubjectUpdate,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     // First: validate teacher already teaches that subject
     if let Some(teacher_id) = request.teacher_in_charge_id {
         if !db.teacher_teaches(teacher_id, id) {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            ));
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
         }
     }
 
     // Then: validate class id is valid
     if let Some(class_id) = request.class_id {
         if db.class_get(class_id).is_none() {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            ));
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
         }
     }
 
@@ -363,26 +393,21 @@ async fn update(
             },
         ))
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ))
+        Ok(FailureResponse::reply(ErrorCode::InvalidID))
     }
 }
 
 async fn teacher_post(
     subject_id: u32,
+    _username: String,
     db: Db,
     request: Vec<u32>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
     let subject = db.subject_get(subject_id);
 
     if subject.is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let all_teachers_exist = request
@@ -390,10 +415,7 @@ async fn teacher_post(
         .all(|id| db.user_get_teacher_by_id(*id).is_some());
 
     if !all_teachers_exist {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     for id in &request {
@@ -410,19 +432,22 @@ async fn teacher_post(
     ))
 }
 
+#[tracing::instrument(skip(_username, db, request), fields(outcome = tracing::field::Empty))]
 async fn teacher_delete(
     subject_id: u32,
+    _username: String,
+    correlation_id: String,
     db: Db,
     request: Vec<u32>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
     let subject = db.subject_get(subject_id);
 
     if subject.is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!("refused to remove teachers: no such subject");
+        tracing::Span::current().record("outcome", &"invalid_id");
+
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let all_teachers_exist_and_teaching_subject_but_not_in_charge = request.iter().all(|id| {
@@ -432,15 +457,18 @@ async fn teacher_delete(
     });
 
     if !all_teachers_exist_and_teaching_subject_but_not_in_charge {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!(
+            teacher_ids = ?request,
+            "refused to remove teachers: not all ids exist, teach this subject, and are not in charge of it"
+        );
+        tracing::Span::current().record("outcome", &"invalid_id");
+
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     // Should not be needed, because there will always be at least one teacher in charge (checked above)
     let count_after_deletion = db
-        .user_list(None, None, |u| match u.kind {
+        .user_list(None, None, None, |u| match u.kind {
             UserKind::Student(_) => false,
             UserKind::Administrator => false,
             UserKind::Teacher(_) => true,
@@ -452,16 +480,22 @@ async fn teacher_delete(
         .count();
 
     if count_after_deletion == 0 {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::IllegalRequest)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!(
+            %correlation_id,
+            teacher_ids = ?request,
+            "refused to remove teachers: no teacher would be left in charge of this subject"
+        );
+        tracing::Span::current().record("outcome", &"illegal_request");
+
+        return Ok(FailureResponse::reply(ErrorCode::IllegalRequest));
     }
 
     for id in &request {
         db.teacher_unset_teaches(*id, subject_id);
     }
 
+    tracing::Span::current().record("outcome", &"success");
+
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
         if request.len() > 0 {
@@ -472,21 +506,22 @@ async fn teacher_delete(
     ))
 }
 
-async fn group_post(subject_id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejection> {
+async fn group_post(
+    subject_id: u32,
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
     // Set group_count and group_number
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let subject = db.subject_get(subject_id);
 
     if subject.is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     db.subject_add_group(subject_id);
-    db.distribute_subject_groups(subject_id);
+    db.distribute_subject_groups(subject_id, false);
 
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
@@ -494,27 +529,25 @@ async fn group_post(subject_id: u32, db: Db) -> Result<impl warp::Reply, warp::R
     ))
 }
 
-async fn group_delete(subject_id: u32, db: Db) -> Result<impl warp::Reply, warp::Rejection> {
+async fn group_delete(
+    subject_id: u32,
+    _username: String,
+    db: Db,
+) -> Result<impl warp::Reply, warp::Rejection> {
     // Set group_count and group_number
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let subject = db.subject_get(subject_id);
 
     if subject.is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     if !db.subject_remove_group(subject_id) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::IllegalRequest)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::IllegalRequest));
     }
 
-    db.distribute_subject_groups(subject_id);
+    db.distribute_subject_groups(subject_id, false);
 
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
@@ -522,27 +555,33 @@ async fn group_delete(subject_id: u32, db: Db) -> Result<impl warp::Reply, warp:
     ))
 }
 
+#[tracing::instrument(
+    skip(db, metrics, request),
+    fields(subject_id, result_count = tracing::field::Empty)
+)]
 async fn occupancies_get(
     subject_id: u32,
     db: Db,
+    metrics: Metrics,
     request: OccupanciesRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     if db.subject_get(subject_id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        metrics.record_invalid_id();
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let occupancies_list = db.occupancies_list(request.start, request.end);
 
-    let occupancies_list = occupancies_list
+    let occupancies_list: Vec<_> = occupancies_list
         .into_iter()
         .filter(|o| o.subject_id == Some(subject_id))
         .collect();
 
+    tracing::Span::current().record("result_count", &occupancies_list.len());
+    metrics.record_occupancies_returned("subject_occupancies_get", occupancies_list.len());
+
     let response =
         OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
 
@@ -556,42 +595,54 @@ async fn occupancies_get(
 struct SubjectOccupancyCreationRequest {
     pub classroom_id: Option<u32>,
     pub teacher_id: u32,
-    pub start: u64,
-    pub end: u64,
+    pub start: HumanDatetime,
+    pub end: HumanDatetime,
     pub occupancy_type: OccupancyType,
     pub name: String,
+    pub recurrence: Option<Recurrence>,
 }
 
+#[tracing::instrument(
+    skip(db, force, request),
+    fields(
+        teacher_id = request.teacher_id,
+        occupancy_type = ?request.occupancy_type,
+        outcome = tracing::field::Empty,
+    )
+)]
 async fn occupancies_create(
     subject_id: u32,
     _username: String,
+    correlation_id: String,
     db: Db,
+    force: ForceQuery,
     request: SubjectOccupancyCreationRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     if let Some(err_response) = validate_new_occupancy_base(&db, subject_id, &request) {
+        tracing::Span::current().record("outcome", &"rejected");
         return Ok(err_response);
     }
 
     // Type constraints
     match request.occupancy_type {
         OccupancyType::TD | OccupancyType::TP => {
-            log::warn!("Trying to create an occupancy without a group, but the occupancy type is TD or TP. Specify a group to create those.");
+            tracing::warn!(
+                "refused to create occupancy: TD/TP occupancies need a group, use the /groups/:group_number/occupancies route instead"
+            );
+            tracing::Span::current().record("outcome", &"illegal_occupancy_type");
 
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::IllegalOccupancyType)),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            ));
+            return Ok(FailureResponse::reply(ErrorCode::IllegalOccupancyType));
         }
         OccupancyType::CM | OccupancyType::Projet => {
             if request.classroom_id.is_none() {
-                log::warn!("Trying to create an occupancy, and the type is CM or Projet, but the classroom id is not defined.");
+                tracing::warn!(
+                    "refused to create occupancy: CM/Projet occupancies need a classroom_id"
+                );
+                tracing::Span::current().record("outcome", &"invalid_id");
 
-                return Ok(warp::reply::with_status(
-                    warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                    StatusCode::NOT_FOUND,
-                ));
+                return Ok(FailureResponse::reply(ErrorCode::InvalidID));
             }
         }
         OccupancyType::Administration => {}
@@ -605,13 +656,26 @@ async fn occupancies_create(
         group_number: None,
         subject_id: Some(subject_id),
         teacher_id: request.teacher_id,
-        start_datetime: request.start,
-        end_datetime: request.end,
+        start_datetime: request.start.0,
+        end_datetime: request.end.0,
         occupancy_type: request.occupancy_type,
         name: request.name,
+        recurrence: request.recurrence.clone(),
     };
 
-    db.occupancies_add(occupancy);
+    if force.force {
+        db.occupancies_add(occupancy);
+    } else if let Err(conflicts) = db.occupancies_add_checked(occupancy) {
+        tracing::warn!(
+            ?conflicts,
+            "refused to create occupancy: conflicts with existing occupancies"
+        );
+        tracing::Span::current().record("outcome", &"conflict");
+
+        return Ok(OccupancyConflictResponse::reply(conflicts));
+    }
+
+    tracing::Span::current().record("outcome", &"success");
 
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
@@ -619,16 +683,117 @@ async fn occupancies_create(
     ))
 }
 
+#[derive(Serialize)]
+struct BatchCreateResponse {
+    status: &'static str,
+    created: usize,
+    results: Vec<BatchItemResult>,
+}
+
+async fn occupancies_create_batch(
+    subject_id: u32,
+    _username: String,
+    db: Db,
+    force: ForceQuery,
+    requests: Vec<SubjectOccupancyCreationRequest>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = filters::timed_write(&db).await;
+
+    let mut created = 0;
+
+    let results = requests
+        .into_iter()
+        .enumerate()
+        .map(|(index, request)| {
+            let status = create_one_occupancy_checked(&mut db, subject_id, force.force, request);
+
+            if let BatchItemStatus::Ok = status {
+                created += 1;
+            }
+
+            BatchItemResult { index, status }
+        })
+        .collect();
+
+    Ok(warp::reply::json(&BatchCreateResponse {
+        status: "success",
+        created,
+        results,
+    }))
+}
+
+/// Validates and, if valid, commits one item of an `occupancies_create_batch` request, in the
+/// same style as `occupancies_create` (structural checks via `validate_new_occupancy_base`, then
+/// the CM/Projet/Administration/External type constraints — TD/TP need a group, so they're always
+/// `Invalid` here the same way they are on the non-batch route). Committing the occupancy
+/// immediately, instead of collecting every item and committing them all at the end, is what
+/// makes two entries in the same batch that clash with each other — not just with already-stored
+/// data — show up as a `Conflict` on the later one: by the time it's checked, the earlier item is
+/// already "stored" as far as `occupancies_add_checked` is concerned.
+fn create_one_occupancy_checked(
+    db: &mut ConcreteDb,
+    subject_id: u32,
+    force: bool,
+    request: SubjectOccupancyCreationRequest,
+) -> BatchItemStatus {
+    if validate_new_occupancy_base(db, subject_id, &request).is_some() {
+        return BatchItemStatus::Invalid;
+    }
+
+    match request.occupancy_type {
+        OccupancyType::TD | OccupancyType::TP => return BatchItemStatus::Invalid,
+        OccupancyType::CM | OccupancyType::Projet => {
+            if request.classroom_id.is_none() {
+                return BatchItemStatus::Invalid;
+            }
+        }
+        OccupancyType::Administration | OccupancyType::External => {}
+    }
+
+    let occupancy = NewOccupancy {
+        classroom_id: request.classroom_id,
+        group_number: None,
+        subject_id: Some(subject_id),
+        teacher_id: request.teacher_id,
+        start_datetime: request.start.0,
+        end_datetime: request.end.0,
+        occupancy_type: request.occupancy_type,
+        name: request.name,
+        recurrence: request.recurrence,
+    };
+
+    if force {
+        db.occupancies_add(occupancy);
+        return BatchItemStatus::Ok;
+    }
+
+    match db.occupancies_add_checked(occupancy) {
+        Ok(_id) => BatchItemStatus::Ok,
+        Err(conflicts) => BatchItemStatus::Conflict(conflicts),
+    }
+}
+
+#[tracing::instrument(
+    skip(db, force, request),
+    fields(
+        teacher_id = request.teacher_id,
+        occupancy_type = ?request.occupancy_type,
+        outcome = tracing::field::Empty,
+    )
+)]
 async fn occupancies_groups_create(
     subject_id: u32,
     group_number: u32,
     _username: String,
+    correlation_id: String,
     db: Db,
+    force: ForceQuery,
     request: SubjectOccupancyCreationRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     if let Some(err_response) = validate_new_occupancy_base(&db, subject_id, &request) {
+        tracing::Span::current().record("outcome", &"rejected");
         return Ok(err_response);
     }
 
@@ -636,24 +801,20 @@ async fn occupancies_groups_create(
     match request.occupancy_type {
         OccupancyType::TD | OccupancyType::TP => {}
         _ => {
-            log::warn!(
-                "Trying to create an occupancy with a group, but the type is neither TD nor TP."
+            tracing::warn!(
+                "refused to create occupancy: a group was given, but the type is neither TD nor TP"
             );
+            tracing::Span::current().record("outcome", &"illegal_occupancy_type");
 
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::IllegalOccupancyType)),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            ));
+            return Ok(FailureResponse::reply(ErrorCode::IllegalOccupancyType));
         }
     }
 
     if request.classroom_id.is_none() {
-        log::warn!("Trying to create an occupancy, and the type is TD or TP, but the classroom id is not defined.");
+        tracing::warn!("refused to create occupancy: TD/TP occupancies need a classroom_id");
+        tracing::Span::current().record("outcome", &"invalid_id");
 
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let subject = db
@@ -661,12 +822,14 @@ async fn occupancies_groups_create(
         .expect("should be a valid id, since its already been validated");
 
     if group_number >= subject.group_count {
-        log::warn!("Trying to create an occupancy, but the provided group number is invalid.");
+        tracing::warn!(
+            group_number,
+            group_count = subject.group_count,
+            "refused to create occupancy: no such group"
+        );
+        tracing::Span::current().record("outcome", &"invalid_id");
 
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     // TODO: check that group number is valid
@@ -676,13 +839,26 @@ async fn occupancies_groups_create(
         group_number: Some(group_number),
         subject_id: Some(subject_id),
         teacher_id: request.teacher_id,
-        start_datetime: request.start,
-        end_datetime: request.end,
+        start_datetime: request.start.0,
+        end_datetime: request.end.0,
         occupancy_type: request.occupancy_type,
         name: request.name,
+        recurrence: request.recurrence.clone(),
     };
 
-    db.occupancies_add(occupancy);
+    if force.force {
+        db.occupancies_add(occupancy);
+    } else if let Err(conflicts) = db.occupancies_add_checked(occupancy) {
+        tracing::warn!(
+            ?conflicts,
+            "refused to create occupancy: conflicts with existing occupancies"
+        );
+        tracing::Span::current().record("outcome", &"conflict");
+
+        return Ok(OccupancyConflictResponse::reply(conflicts));
+    }
+
+    tracing::Span::current().record("outcome", &"success");
 
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
@@ -690,115 +866,101 @@ async fn occupancies_groups_create(
     ))
 }
 
+/// Structural checks shared by `occupancies_create`/`occupancies_groups_create`: that the
+/// referenced subject/teacher/classroom exist and the teacher teaches the subject, and
+/// that the time range is well-formed. Whether the slot is actually free (room, teacher, class,
+/// group) is not this function's job any more — the caller runs the request through
+/// `occupancies_add_checked` for that, which reports every clashing occupancy instead of bailing
+/// out on the first one.
 fn validate_new_occupancy_base(
-    db: &LockedDb,
+    db: &ConcreteDb,
     subject_id: u32,
     request: &SubjectOccupancyCreationRequest,
 ) -> Option<warp::reply::WithStatus<warp::reply::Json>> {
     // Check subject exists
     if db.subject_get(subject_id).is_none() {
-        log::warn!("Trying to create an occupancy but the subject does not exist.");
+        tracing::warn!(subject_id, "refused to create occupancy: no such subject");
 
-        return Some(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        return Some(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     // Check teacher exists and teaches that subject
     match db.user_get_teacher_by_id(request.teacher_id) {
         Some(_) => {
             if !db.teacher_teaches(request.teacher_id, subject_id) {
-                log::warn!(
-                    "Trying to create an occupancy but the teacher does not teach that subject."
+                tracing::warn!(
+                    teacher_id = request.teacher_id,
+                    subject_id,
+                    "refused to create occupancy: teacher does not teach this subject"
                 );
-                return Some(warp::reply::with_status(
-                    warp::reply::json(&FailureResponse::new(ErrorCode::TeacherDoesNotTeach)),
-                    StatusCode::UNPROCESSABLE_ENTITY,
-                ));
+                return Some(FailureResponse::reply(ErrorCode::TeacherDoesNotTeach));
             }
         }
         None => {
-            log::warn!("Trying to create an occupancy but the teacher does not exist.");
+            tracing::warn!(
+                teacher_id = request.teacher_id,
+                "refused to create occupancy: no such teacher"
+            );
 
-            return Some(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::NOT_FOUND,
-            ));
+            return Some(FailureResponse::reply(ErrorCode::InvalidID));
         }
     }
 
-    // Check that classroom exists, and that is is free
+    // Check that classroom exists
     if let Some(classroom_id) = request.classroom_id {
         if db.classroom_get(classroom_id).is_none() {
-            log::warn!("Trying to create an occupancy but the classroom does not exist.");
-
-            return Some(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::NOT_FOUND,
-            ));
-        }
-
-        if !db.classroom_free(classroom_id, request.start, request.end) {
-            log::warn!("Trying to create an occupancy but the classroom is not free.");
+            tracing::warn!(
+                classroom_id,
+                "refused to create occupancy: no such classroom"
+            );
 
-            return Some(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::ClassroomAlreadyOccupied)),
-                StatusCode::UNPROCESSABLE_ENTITY,
-            ));
+            return Some(FailureResponse::reply(ErrorCode::InvalidID));
         }
     }
 
     // Check end_datetime >= start_datetime
-    if request.end < request.start {
-        log::warn!(
-            "Trying to create an occupancy but the end_datetime is before the start_datetime."
+    if request.end.0 < request.start.0 {
+        tracing::warn!(
+            start = request.start.0,
+            end = request.end.0,
+            "refused to create occupancy: end_datetime is before start_datetime"
         );
 
-        return Some(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::EndBeforeStart)),
-            StatusCode::UNPROCESSABLE_ENTITY,
-        ));
+        return Some(FailureResponse::reply(ErrorCode::EndBeforeStart));
     }
 
-    // Check that the teacher is free
-    if !db.teacher_free(request.teacher_id, request.start, request.end) {
-        log::warn!("Trying to create an occupancy, but the teacher is not free.");
-
-        return Some(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::Unknown)),
-            StatusCode::UNPROCESSABLE_ENTITY,
-        ));
-    }
-
-    // TODO: Check that the class is free
-
     None
 }
 
+#[tracing::instrument(
+    skip(db, metrics, request),
+    fields(subject_id, group_number, result_count = tracing::field::Empty)
+)]
 async fn occupancies_group_get(
     subject_id: u32,
     group_number: u32,
     _username: String,
     db: Db,
+    metrics: Metrics,
     request: OccupanciesRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     if db.subject_get(subject_id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        metrics.record_invalid_id();
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let occupancies_list = db.occupancies_list(request.start, request.end);
 
-    let occupancies_list = occupancies_list
+    let occupancies_list: Vec<_> = occupancies_list
         .into_iter()
         .filter(|o| o.subject_id == Some(subject_id) && o.group_number == Some(group_number))
         .collect();
 
+    tracing::Span::current().record("result_count", &occupancies_list.len());
+    metrics.record_occupancies_returned("subject_occupancies_group_get", occupancies_list.len());
+
     let response =
         OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
 
@@ -807,3 +969,109 @@ async fn occupancies_group_get(
         StatusCode::OK,
     ))
 }
+
+/// Parses the `pairs` query param, a comma-separated list of `subject_id:group_number` pairs
+/// (e.g. `pairs=1:0,2:1`), following the same comma-split-list-in-one-query-param shape
+/// `occupancies_create_batch`'s JSON body covers for POST, but for a GET request where a body
+/// isn't idiomatic.
+fn deserialize_pairs<'de, D>(deserializer: D) -> Result<Vec<(u32, u32)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+
+    raw.split(',')
+        .map(|pair| {
+            let (subject_id, group_number) = pair
+                .split_once(':')
+                .ok_or_else(|| serde::de::Error::custom("expected \"subject_id:group_number\""))?;
+
+            Ok((
+                subject_id.parse().map_err(serde::de::Error::custom)?,
+                group_number.parse().map_err(serde::de::Error::custom)?,
+            ))
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct BatchOccupanciesRequest {
+    #[serde(deserialize_with = "deserialize_pairs")]
+    pairs: Vec<(u32, u32)>,
+    start: Option<u64>,
+    end: Option<u64>,
+    occupancies_per_day: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct BatchOccupanciesResponse<'a> {
+    status: &'static str,
+    results: HashMap<String, OccupanciesListResponse<'a>>,
+}
+
+/// Like `occupancies_group_get`, but for several `(subject_id, group_number)` pairs at once: runs
+/// `occupancies_list` a single time and partitions the result in memory, rather than the caller
+/// making one round trip per pair (each of which would otherwise re-run the same full-range
+/// query).
+#[tracing::instrument(
+    skip(db, metrics, request),
+    fields(pairs = ?request.pairs, result_count = tracing::field::Empty)
+)]
+async fn occupancies_batch_get(
+    _username: String,
+    db: Db,
+    metrics: Metrics,
+    request: BatchOccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    for &(subject_id, group_number) in &request.pairs {
+        match db.subject_get(subject_id) {
+            Some(subject) if group_number < subject.group_count => {}
+            _ => {
+                metrics.record_invalid_id();
+                return Ok(FailureResponse::reply(ErrorCode::InvalidID));
+            }
+        }
+    }
+
+    let occupancies_list = db.occupancies_list(request.start, request.end);
+
+    let mut buckets: HashMap<(u32, u32), Vec<_>> = request
+        .pairs
+        .iter()
+        .map(|&pair| (pair, Vec::new()))
+        .collect();
+
+    for occupancy in occupancies_list {
+        if let (Some(subject_id), Some(group_number)) =
+            (occupancy.subject_id, occupancy.group_number)
+        {
+            if let Some(bucket) = buckets.get_mut(&(subject_id, group_number)) {
+                bucket.push(occupancy);
+            }
+        }
+    }
+
+    let result_count: usize = buckets.values().map(|bucket| bucket.len()).sum();
+    tracing::Span::current().record("result_count", &result_count);
+    metrics.record_occupancies_returned("subject_occupancies_batch_get", result_count);
+
+    let results = buckets
+        .into_iter()
+        .map(|((subject_id, group_number), bucket)| {
+            let response =
+                OccupanciesListResponse::from_list(&db, bucket, request.occupancies_per_day);
+
+            (format!("{}:{}", subject_id, group_number), response)
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&BatchOccupanciesResponse {
+            status: "ok",
+            results,
+        }),
+        StatusCode::OK,
+    ))
+}
@@ -4,17 +4,20 @@ use warp::{http::StatusCode, Filter, Rejection, Reply};
 
 use super::{
     globals::{
-        deserialize_some, AccountCreatedResponse, PaginatedQueryableListRequest,
-        SimpleSuccessResponse,
+        deserialize_some, AccountCreatedResponse, OccupanciesListResponse, OccupanciesRequest,
+        PaginatedQueryableListRequest, PaginationMeta, SimpleSuccessResponse,
     },
     ErrorCode, FailureResponse,
 };
 use db::{
+    auth::{Claims, Role},
     group_numbers,
     models::{Rank, TeacherInformations, UserKind},
-    Database, Db, NewUser,
+    Database, Db, NewUser, PAGE_SIZE,
 };
-use filters::{authed_is_of_kind, delayed, with_db, PossibleUserKind};
+use filters::{authed_claims, authed_is_of_kind, delayed, with_db, PossibleUserKind};
+
+use crate::service;
 
 pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let list_route = warp::path!("api" / "teachers")
@@ -49,10 +52,11 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
         .and(delayed(db))
         .boxed();
 
-    // TODO: missing auth??
-
+    // Admins and the teacher themselves for their own record; `authed_claims` carries the id/role
+    // needed for that check directly, so the handlers don't need to re-look-up a username.
     let get_route = warp::path!("api" / "teachers" / u32)
         .and(warp::get())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and_then(get)
         .and(delayed(db))
@@ -60,6 +64,7 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let update_route = warp::path!("api" / "teachers" / u32)
         .and(warp::put())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
         .and_then(update)
@@ -68,17 +73,27 @@ pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection
 
     let subjects_get_route = warp::path!("api" / "teachers" / u32 / "subjects")
         .and(warp::get())
+        .and(authed_claims(db))
         .and(with_db(db.clone()))
         .and_then(subjects_get)
         .and(delayed(db))
         .boxed();
 
+    let occupancies_ics_route = warp::path!("api" / "teachers" / u32 / "occupancies.ics")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(warp::query::<OccupanciesRequest>())
+        .and_then(occupancies_ics)
+        .and(delayed(db))
+        .boxed();
+
     list_route
         .or(create_route)
         .or(delete_route)
         .or(get_route)
         .or(update_route)
         .or(subjects_get_route)
+        .or(occupancies_ics_route)
 }
 
 #[derive(Serialize)]
@@ -86,6 +101,7 @@ struct ListResponse<'a> {
     status: &'static str,
     total: usize,
     teachers: Vec<Teacher<'a>>,
+    pagination: PaginationMeta,
 }
 
 #[derive(Serialize)]
@@ -97,19 +113,26 @@ struct Teacher<'a> {
     phone_number: Option<&'a str>,
 }
 
+#[tracing::instrument(skip(db, request), fields(outcome = tracing::field::Empty))]
 async fn list(
-    _username: String,
+    username: String,
     db: Db,
     request: PaginatedQueryableListRequest,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = db.lock().await;
+    let db = filters::timed_read(&db).await;
 
     let page = request.normalized_page_number();
-    let (total, users) = db.user_list(Some(page), request.query.as_deref(), |u| match u.kind {
-        UserKind::Student(_) => false,
-        UserKind::Administrator => false,
-        UserKind::Teacher(_) => true,
-    });
+    let per_page = request.normalized_per_page();
+    let (total, users) = db.user_list(
+        Some(page),
+        per_page,
+        request.query.as_deref(),
+        |u| match u.kind {
+            UserKind::Student(_) => false,
+            UserKind::Administrator => false,
+            UserKind::Teacher(_) => true,
+        },
+    );
 
     let teachers = users
         .into_iter()
@@ -126,9 +149,12 @@ async fn list(
         })
         .collect();
 
+    tracing::Span::current().record("outcome", &"success");
+
     Ok(warp::reply::json(&ListResponse {
         status: "success",
         total,
+        pagination: PaginationMeta::new(total, page, per_page.unwrap_or(PAGE_SIZE)),
         teachers,
     }))
 }
@@ -142,16 +168,17 @@ struct NewTeacher {
     rank: Rank,
 }
 
+#[tracing::instrument(skip(db, request), fields(outcome = tracing::field::Empty))]
 async fn create(
-    _username: String,
+    username: String,
     db: Db,
     request: NewTeacher,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let mut rng = rand::thread_rng();
 
-    let password = std::iter::repeat(())
+    let password: String = std::iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
         .take(10)
         .collect();
@@ -159,7 +186,7 @@ async fn create(
     let user = NewUser {
         first_name: request.first_name,
         last_name: request.last_name,
-        password,
+        password: password.clone(),
         kind: UserKind::Teacher(TeacherInformations {
             phone_number: request.phone_number,
             email: request.email,
@@ -169,19 +196,23 @@ async fn create(
 
     let user = db.user_add(user);
 
+    tracing::info!(created_username = %user.username, "created teacher account");
+    tracing::Span::current().record("outcome", &"success");
+
     Ok(warp::reply::json(&AccountCreatedResponse {
         status: "success",
         username: &user.username,
-        password: &user.password,
+        password: &password,
     }))
 }
 
+#[tracing::instrument(skip(db), fields(outcome = tracing::field::Empty))]
 async fn delete(
-    _username: String,
+    username: String,
     db: Db,
     request: Vec<u32>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut db = db.lock().await;
+    let mut db = filters::timed_write(&db).await;
 
     let all_exist_and_teacher =
         request
@@ -193,13 +224,14 @@ async fn delete(
             });
 
     if !all_exist_and_teacher {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!(ids = ?request, "refused to delete: not all ids are existing teachers");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     if db.user_remove(&request) {
+        tracing::Span::current().record("outcome", &"success");
+
         Ok(warp::reply::with_status(
             warp::reply::json(&SimpleSuccessResponse::new()),
             StatusCode::OK,
@@ -223,42 +255,71 @@ struct GetResponseTeacher<'a> {
     email: Option<&'a str>,
     phone_number: Option<&'a str>,
     rank: &'a Rank,
-    //total_service: u32, // TODO: total_service
-    // TODO: services
+    total_service: f64,
 }
 
-async fn get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+/// An admin can look up any teacher; a teacher can only look up their own record.
+fn authorized_for(claims: &Claims, id: u32) -> bool {
+    claims.role == Role::Administrator || claims.sub == id
+}
+
+#[tracing::instrument(skip(db, claims), fields(outcome = tracing::field::Empty))]
+async fn get(
+    id: u32,
+    claims: Claims,
+    db: Db,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if !authorized_for(&claims, id) {
+        tracing::warn!("not authorized to view this teacher");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
+
+    let db = filters::timed_read(&db).await;
     let user = db.user_get_by_id(id);
 
     let res_teacher = match user {
         Some(user) => match &user.kind {
             UserKind::Administrator => None,
-            UserKind::Teacher(informations) => Some(GetResponseTeacher {
-                first_name: &user.first_name,
-                last_name: &user.last_name,
-                username: &user.username,
-                email: informations.email.as_deref(),
-                phone_number: informations.phone_number.as_deref(),
-                rank: &informations.rank,
-            }),
+            UserKind::Teacher(informations) => {
+                let occupancies: Vec<_> = db
+                    .occupancies_list(None, None)
+                    .into_iter()
+                    .filter(|o| o.teacher_id == id)
+                    .collect();
+
+                Some(GetResponseTeacher {
+                    first_name: &user.first_name,
+                    last_name: &user.last_name,
+                    username: &user.username,
+                    email: informations.email.as_deref(),
+                    phone_number: informations.phone_number.as_deref(),
+                    rank: &informations.rank,
+                    total_service: service::teacher_service(&occupancies).total,
+                })
+            }
             UserKind::Student(_) => None,
         },
         None => None,
     };
 
     match res_teacher {
-        Some(res_teacher) => Ok(warp::reply::with_status(
-            warp::reply::json(&GetResponse {
-                status: "success",
-                teacher: res_teacher,
-            }),
-            StatusCode::OK,
-        )),
-        None => Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        )),
+        Some(res_teacher) => {
+            tracing::Span::current().record("outcome", &"success");
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&GetResponse {
+                    status: "success",
+                    teacher: res_teacher,
+                }),
+                StatusCode::OK,
+            ))
+        }
+        None => {
+            tracing::warn!("no such teacher");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            Ok(FailureResponse::reply(ErrorCode::InvalidID))
+        }
     }
 }
 
@@ -274,20 +335,27 @@ struct TeacherUpdate {
     password: Option<String>,
 }
 
+#[tracing::instrument(skip(db, claims, request), fields(outcome = tracing::field::Empty))]
 async fn update(
     id: u32,
+    claims: Claims,
     db: Db,
     request: TeacherUpdate,
 ) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let mut db = db.lock().await;
+    if !authorized_for(&claims, id) {
+        tracing::warn!("not authorized to update this teacher");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
+
+    let mut db = filters::timed_write(&db).await;
 
     let mut user = match db.user_get_teacher_by_id(id) {
         Some(user) => user,
         None => {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-                StatusCode::NOT_FOUND,
-            ))
+            tracing::warn!("no such teacher");
+            tracing::Span::current().record("outcome", &"invalid_id");
+            return Ok(FailureResponse::reply(ErrorCode::InvalidID));
         }
     }
     .clone();
@@ -305,7 +373,11 @@ async fn update(
 
     update!(user, first_name);
     update!(user, last_name);
-    update!(user, password);
+
+    if let Some(value) = request.password {
+        user.password = db::auth::hash_password(&value);
+        updated = true;
+    }
 
     let mut informations = match &mut user.kind {
         UserKind::Administrator => unreachable!(),
@@ -321,6 +393,8 @@ async fn update(
         db.user_update(user);
     }
 
+    tracing::Span::current().record("outcome", &if updated { "success" } else { "noop" });
+
     Ok(warp::reply::with_status(
         warp::reply::json(&SimpleSuccessResponse::new()),
         if updated {
@@ -335,6 +409,14 @@ async fn update(
 struct SubjectGetResponseList<'a> {
     status: &'static str,
     subjects: Vec<SubjectGetResponse<'a>>,
+    services: Vec<ServiceGetResponse<'a>>,
+}
+
+#[derive(Serialize)]
+struct ServiceGetResponse<'a> {
+    subject_id: u32,
+    subject_name: &'a str,
+    hours: f64,
 }
 
 #[derive(Serialize)]
@@ -362,8 +444,19 @@ struct GroupSubjectGetResponse {
     count: u32,
 }
 
-async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let db = db.lock().await;
+#[tracing::instrument(skip(db, claims), fields(outcome = tracing::field::Empty))]
+async fn subjects_get(
+    id: u32,
+    claims: Claims,
+    db: Db,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if !authorized_for(&claims, id) {
+        tracing::warn!("not authorized to view this teacher's subjects");
+        tracing::Span::current().record("outcome", &"forbidden");
+        return Ok(FailureResponse::reply(ErrorCode::InsufficientAuthorization));
+    }
+
+    let db = filters::timed_read(&db).await;
 
     // in: $teacher_id
     // list of all subjects $teacher_id participates in : db.teacher_subjects
@@ -371,45 +464,75 @@ async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert:
     //    -> for each subject, list of all groups : just use subject.group_count + db::group_numbers as in subject.rs
 
     if db.user_get_teacher_by_id(id).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&FailureResponse::new(ErrorCode::InvalidID)),
-            StatusCode::NOT_FOUND,
-        ));
+        tracing::warn!("no such teacher");
+        tracing::Span::current().record("outcome", &"invalid_id");
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID));
     }
 
     let teacher_subjects = db.teacher_subjects(id);
 
+    // One pass over the teacher/subject links for every subject the teacher is in, instead of a
+    // full `user_list` scan plus a `teacher_teaches`/`teacher_in_charge` pair per teacher per
+    // subject.
+    let subject_ids: Vec<u32> = teacher_subjects.iter().map(|s| s.id).collect();
+    let mut subjects_teachers = db.subjects_teachers(&subject_ids);
+
+    let occupancies: Vec<_> = db
+        .occupancies_list(None, None)
+        .into_iter()
+        .filter(|o| o.teacher_id == id)
+        .collect();
+    let service = service::teacher_service(&occupancies);
+
     let mut subjects: Vec<SubjectGetResponse> = Vec::new();
+    let mut services: Vec<ServiceGetResponse> = Vec::new();
 
     // For each subject that the teacher is in.
     for teacher_subject in teacher_subjects {
+        services.push(ServiceGetResponse {
+            subject_id: teacher_subject.id,
+            subject_name: &teacher_subject.name,
+            hours: service
+                .by_subject
+                .get(&teacher_subject.id)
+                .copied()
+                .unwrap_or(0.0),
+        });
+
         // Eg: L3 Informatique
-        let class_name = db
-            .class_get(teacher_subject.class_id)
-            .expect("invalid class_id in user informations")
-            .name
-            .to_string();
+        let class_name = match db.class_get(teacher_subject.class_id) {
+            Some(class) => class.name.to_string(),
+            None => {
+                tracing::error!(
+                    subject_id = teacher_subject.id,
+                    class_id = teacher_subject.class_id,
+                    "subject references a class_id that no longer exists"
+                );
+                "Unknown".to_string()
+            }
+        };
 
         // List of all teachers that teach this subject.
-        let subject_teachers: Vec<TeacherSubjectGetResponse> = db.user_list(None, None, |u| match u.kind {
-            UserKind::Student(_) => false,
-            UserKind::Administrator => false,
-            UserKind::Teacher(_) => true,
-        } && db.teacher_teaches(u.id, teacher_subject.id)).1.iter().map(|u| {
-            let informations = match &u.kind {
-                UserKind::Student(_) => unreachable!(),
-                UserKind::Administrator => unreachable!(),
-                UserKind::Teacher(informations) => informations,
-            };
-
-            TeacherSubjectGetResponse {
-                first_name: &u.first_name,
-                last_name: &u.last_name,
-                in_charge: db.teacher_in_charge(u.id, teacher_subject.id),
-                email: informations.email.as_deref(),
-                phone_number: informations.phone_number.as_deref(),
-            }
-        }).collect();
+        let subject_teachers: Vec<TeacherSubjectGetResponse> = subjects_teachers
+            .remove(&teacher_subject.id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(u, in_charge)| {
+                let informations = match &u.kind {
+                    UserKind::Student(_) => unreachable!(),
+                    UserKind::Administrator => unreachable!(),
+                    UserKind::Teacher(informations) => informations,
+                };
+
+                TeacherSubjectGetResponse {
+                    first_name: &u.first_name,
+                    last_name: &u.last_name,
+                    in_charge,
+                    email: informations.email.as_deref(),
+                    phone_number: informations.phone_number.as_deref(),
+                }
+            })
+            .collect();
 
         let total_student_count: usize = db.subject_students(teacher_subject.id).len();
 
@@ -434,11 +557,42 @@ async fn subjects_get(id: u32, db: Db) -> Result<impl warp::Reply, std::convert:
         });
     }
 
+    tracing::Span::current().record("outcome", &"success");
+
     return Ok(warp::reply::with_status(
         warp::reply::json(&SubjectGetResponseList {
             status: "success",
             subjects,
+            services,
         }),
         StatusCode::OK,
     ));
 }
+
+async fn occupancies_ics(
+    id: u32,
+    db: Db,
+    request: OccupanciesRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = filters::timed_read(&db).await;
+
+    if db.user_get_teacher_by_id(id).is_none() {
+        return Ok(FailureResponse::reply(ErrorCode::InvalidID).into_response());
+    }
+
+    let occupancies_list = db
+        .occupancies_list(request.start, request.end)
+        .into_iter()
+        .filter(|o| o.teacher_id == id)
+        .collect();
+
+    let response =
+        OccupanciesListResponse::from_list(&db, occupancies_list, request.occupancies_per_day);
+
+    Ok(warp::reply::with_header(
+        response.to_ics(),
+        "content-type",
+        "text/calendar; charset=utf-8",
+    )
+    .into_response())
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+use db::{
+    webuntis::{WebUntisClient, WebUntisConfig},
+    Database, Db, ImportReport,
+};
+use filters::{authed_is_of_kind, delayed, with_db, PossibleUserKind};
+use warp::{Filter, Rejection, Reply};
+
+pub fn routes(db: &Db) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let import_route = warp::path!("api" / "import" / "webuntis")
+        .and(warp::post())
+        .and(authed_is_of_kind(db, &[PossibleUserKind::Administrator]))
+        .and(with_db(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
+        .and_then(import)
+        .and(delayed(db))
+        .boxed();
+
+    import_route
+}
+
+#[derive(Deserialize)]
+struct ImportRequest {
+    #[serde(flatten)]
+    config: WebUntisConfig,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Serialize)]
+struct ImportResponse {
+    status: &'static str,
+    report: ImportReport,
+}
+
+#[tracing::instrument(skip(_username, db, request), fields(outcome = tracing::field::Empty))]
+async fn import(
+    _username: String,
+    db: Db,
+    request: ImportRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut client = WebUntisClient::new(request.config);
+
+    client.login().await.map_err(|_| {
+        tracing::warn!("refused to import: could not log in to WebUntis");
+        tracing::Span::current().record("outcome", &"login_failed");
+        warp::reject::custom(filters::Malformed {})
+    })?;
+
+    let periods = client
+        .fetch_periods(request.start, request.end)
+        .await
+        .map_err(|_| {
+            tracing::warn!("refused to import: could not fetch periods from WebUntis");
+            tracing::Span::current().record("outcome", &"fetch_failed");
+            warp::reject::custom(filters::Malformed {})
+        })?;
+
+    let mut db = filters::timed_write(&db).await;
+    let report = db.import_webuntis_periods(periods.into_iter());
+
+    tracing::info!(
+        created = report.created,
+        skipped = report.skipped,
+        unresolved = report.unresolved.len(),
+        "imported a WebUntis dump"
+    );
+    tracing::Span::current().record("outcome", &"success");
+
+    Ok(warp::reply::json(&ImportResponse {
+        status: "success",
+        report,
+    }))
+}
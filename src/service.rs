@@ -1,4 +1,5 @@
-use db::models::{Occupancy, OccupancyType};
+use db::models::{Occupancy, OccupancyOccurrence, OccupancyType};
+use std::collections::HashMap;
 
 const CM_COEFF: f64 = 1.5;
 const TD_COEFF: f64 = 1.0;
@@ -7,7 +8,7 @@ const PROJET_COEFF: f64 = 1.0;
 const ADMINISTRATION_COEFF: f64 = 1.0;
 const EXTERNAL_COEFF: f64 = 0.0;
 
-pub fn service_value(occupancies: &[&Occupancy]) -> f64 {
+pub fn service_value(occupancies: &[OccupancyOccurrence]) -> f64 {
     let mut total = 0.0;
 
     for occupancy in occupancies {
@@ -61,6 +62,40 @@ impl Default for Service {
     }
 }
 
+/// A teacher's teaching load in équivalent-TD hours (via `coeff`), overall and broken down by
+/// subject, computed straight from their scheduled occupancies rather than a stored total so it
+/// always reflects the current calendar.
+pub struct TeacherService {
+    pub total: f64,
+    pub by_subject: HashMap<u32, f64>,
+}
+
+/// Sums `occupancies` (already filtered to one teacher) into a `TeacherService`. Occurrences with
+/// no `subject_id`, or with `end_datetime <= start_datetime` (a malformed or still-being-edited
+/// occupancy), are skipped for the per-subject breakdown; the former are still counted in `total`,
+/// the latter never are.
+pub fn teacher_service(occupancies: &[OccupancyOccurrence]) -> TeacherService {
+    let mut total = 0.0;
+    let mut by_subject: HashMap<u32, f64> = HashMap::new();
+
+    for occupancy in occupancies {
+        if occupancy.end_datetime <= occupancy.start_datetime {
+            continue;
+        }
+
+        let elapsed_hours = (occupancy.end_datetime - occupancy.start_datetime) as f64 / 3600.0;
+        let hours = elapsed_hours * coeff(occupancy);
+
+        total += hours;
+
+        if let Some(subject_id) = occupancy.subject_id {
+            *by_subject.entry(subject_id).or_insert(0.0) += hours;
+        }
+    }
+
+    TeacherService { total, by_subject }
+}
+
 pub fn count_hours(occupancies: &[&Occupancy]) -> Service {
     use OccupancyType::*;
 